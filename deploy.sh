@@ -13,6 +13,7 @@ SYSTEMD_DEST="/etc/systemd/system/$APP_NAME.service"
 CONFIG_FILE_SOURCE="$REPO_DIR/config.toml"
 CONFIG_FILE_DEST="$HOME/.config/mail-forge/config.toml"
 EMAIL_LOG_FOLDER="/var/log/$APP_NAME/emails"
+SPOOL_FOLDER="/var/spool/$APP_NAME"
 
 confirm() {
 	# Ask user for confirmation
@@ -75,6 +76,11 @@ sudo mkdir -p "$EMAIL_LOG_FOLDER"
 sudo chown ubuntu:ubuntu "$EMAIL_LOG_FOLDER"
 chmod 750 "$EMAIL_LOG_FOLDER"
 
+echo "Ensuring $SPOOL_FOLDER exists and has correct permissions..."
+sudo mkdir -p "$SPOOL_FOLDER"
+sudo chown ubuntu:ubuntu "$SPOOL_FOLDER"
+chmod 750 "$SPOOL_FOLDER"
+
 echo "Stopping the service..."
 sudo systemctl stop "$APP_NAME.service" || echo "Service not running. Continuing..."
 
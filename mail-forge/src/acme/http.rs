@@ -0,0 +1,97 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Answers HTTP-01 validation requests for the tokens of a pending order.
+/// It stops listening when dropped.
+pub struct ChallengeResponder {
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl ChallengeResponder {
+    pub async fn start(bind_address: &str) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(bind_address).await?;
+        let local_addr = listener.local_addr()?;
+        info!("Answering ACME HTTP-01 challenges on {}", local_addr);
+
+        let tokens = Arc::new(Mutex::new(HashMap::new()));
+        let task = {
+            let tokens = tokens.clone();
+            tokio::spawn(async move {
+                while let Ok((socket, addr)) = listener.accept().await {
+                    let tokens = tokens.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(socket, &tokens).await {
+                            warn!("ACME challenge request from {} failed: {}", addr, e);
+                        }
+                    });
+                }
+            })
+        };
+
+        Ok(Self {
+            tokens,
+            local_addr,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Serves `key_authorization` at `/.well-known/acme-challenge/<token>`.
+    pub fn add(&self, token: String, key_authorization: String) {
+        self.tokens.lock().unwrap().insert(token, key_authorization);
+    }
+}
+
+impl Drop for ChallengeResponder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn respond(
+    socket: TcpStream,
+    tokens: &Mutex<HashMap<String, String>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(socket);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let key_authorization = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => path
+            .strip_prefix(CHALLENGE_PREFIX)
+            .and_then(|token| tokens.lock().unwrap().get(token).cloned()),
+        _ => None,
+    };
+
+    let response = match key_authorization {
+        Some(body) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ),
+        None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string(),
+    };
+    reader.get_mut().write_all(response.as_bytes()).await?;
+    reader.get_mut().shutdown().await
+}
@@ -0,0 +1,305 @@
+pub mod http;
+
+use crate::config::{self, AcmeChallenge, AcmeConfig};
+use crate::tls;
+use chrono::Utc;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, OrderStatus, RetryPolicy,
+};
+use log::{error, info, warn};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use x509_parser::extensions::GeneralName;
+
+type AcmeError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How often the renewal task looks at the certificate's expiry.
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Makes sure a usable certificate is on disk before the listeners start,
+/// obtaining one if there is none yet or it is due for renewal.
+///
+/// Only fails when there is nothing to serve: a certificate that is merely
+/// due for renewal keeps being served, and the renewal task tries again.
+pub async fn ensure_certificate(acme: &AcmeConfig) -> Result<(), AcmeError> {
+    if !needs_renewal(acme) {
+        return Ok(());
+    }
+    match obtain_certificate(acme).await {
+        Ok(()) => Ok(()),
+        Err(e) if has_usable_certificate(acme) => {
+            warn!(
+                "Failed to renew certificate via ACME, serving the current one for now: {}",
+                e
+            );
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Periodically renews the certificate and swaps it into `shared`.
+pub fn spawn_renewal(config: Arc<config::Config>, shared: tls::SharedServerConfig) {
+    tokio::spawn(async move {
+        let Some(acme) = &config.acme else {
+            return;
+        };
+        loop {
+            tokio::time::sleep(RENEWAL_CHECK_INTERVAL).await;
+            if !needs_renewal(acme) {
+                continue;
+            }
+            match obtain_certificate(acme).await {
+                Ok(()) => {
+                    if let Err(e) = tls::reload(&config, &shared) {
+                        error!("Failed to load the renewed certificate: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to renew certificate via ACME: {}", e),
+            }
+        }
+    });
+}
+
+/// Whether the stored certificate is missing, unreadable, expires within
+/// `renew_before_days` or was issued for other names than `acme.domains`.
+pub fn needs_renewal(acme: &AcmeConfig) -> bool {
+    let Some(stored) = stored_certificate(acme) else {
+        return true;
+    };
+    let renew_at = stored.not_after - (acme.renew_before_days * 24 * 60 * 60) as i64;
+    if Utc::now().timestamp() >= renew_at {
+        return true;
+    }
+
+    let mut wanted: Vec<String> = acme
+        .domains
+        .iter()
+        .map(|domain| domain.to_ascii_lowercase())
+        .collect();
+    wanted.sort();
+    wanted.dedup();
+    stored.names != wanted
+}
+
+/// Whether there is a certificate and key on disk that can still be served.
+fn has_usable_certificate(acme: &AcmeConfig) -> bool {
+    acme.key_path().exists()
+        && stored_certificate(acme)
+            .is_some_and(|stored| Utc::now().timestamp() < stored.not_after)
+}
+
+struct StoredCertificate {
+    not_after: i64,
+    /// The DNS names among its subject alternative names, lowercased and sorted.
+    names: Vec<String>,
+}
+
+fn stored_certificate(acme: &AcmeConfig) -> Option<StoredCertificate> {
+    let cert = CertificateDer::pem_file_iter(acme.cert_path())
+        .ok()?
+        .next()?
+        .ok()?;
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert).ok()?;
+
+    let mut names: Vec<String> = parsed
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|extension| {
+            extension
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names.dedup();
+    Some(StoredCertificate {
+        not_after: parsed.validity().not_after.timestamp(),
+        names,
+    })
+}
+
+/// Runs a complete order for `acme.domains` and stores the certificate and
+/// its private key in the state directory.
+pub async fn obtain_certificate(acme: &AcmeConfig) -> Result<(), AcmeError> {
+    fs::create_dir_all(&acme.state_dir)?;
+    let account = load_or_create_account(acme).await?;
+
+    let identifiers: Vec<Identifier> = acme
+        .domains
+        .iter()
+        .map(|domain| Identifier::Dns(domain.clone()))
+        .collect();
+    let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+    info!("Requesting certificate for {}", acme.domains.join(", "));
+
+    // Kept alive until validation is over
+    let responder = match acme.challenge {
+        AcmeChallenge::Http01 => {
+            Some(http::ChallengeResponder::start(&acme.http_bind_address).await?)
+        }
+        AcmeChallenge::Dns01 => None,
+    };
+    let mut dns_records = Vec::new();
+
+    let validated = async {
+        let mut authorizations = order.authorizations();
+        while let Some(authorization) = authorizations.next().await {
+            let mut authorization = authorization?;
+            match authorization.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => return Err(format!("Authorization is {:?}", status).into()),
+            }
+
+            let challenge_type = match acme.challenge {
+                AcmeChallenge::Http01 => ChallengeType::Http01,
+                AcmeChallenge::Dns01 => ChallengeType::Dns01,
+            };
+            let mut challenge = authorization
+                .challenge(challenge_type.clone())
+                .ok_or_else(|| format!("ACME server offered no {:?} challenge", challenge_type))?;
+            let key_authorization = challenge.key_authorization();
+
+            match (&responder, &acme.dns_hook) {
+                (Some(responder), _) => responder.add(
+                    challenge.token.clone(),
+                    key_authorization.as_str().to_string(),
+                ),
+                (None, Some(hook)) => {
+                    // Wildcards are validated on the base domain's record
+                    let Identifier::Dns(domain) = challenge.identifier().identifier else {
+                        return Err("DNS-01 only applies to DNS identifiers".into());
+                    };
+                    let value = key_authorization.dns_value();
+                    run_dns_hook(hook, "present", domain, &value).await?;
+                    dns_records.push((domain.clone(), value));
+                }
+                (None, None) => return Err("ACME DNS-01 challenges need acme.dns_hook".into()),
+            }
+
+            challenge.set_ready().await?;
+        }
+
+        let status = order.poll_ready(&RetryPolicy::default()).await?;
+        if status != OrderStatus::Ready {
+            return Err(format!("ACME order ended up {:?}", status).into());
+        }
+        Ok::<(), AcmeError>(())
+    }
+    .await;
+
+    drop(responder);
+    for (domain, value) in &dns_records {
+        if let Some(hook) = &acme.dns_hook {
+            if let Err(e) = run_dns_hook(hook, "cleanup", domain, value).await {
+                warn!("Failed to clean up DNS-01 record for {}: {}", domain, e);
+            }
+        }
+    }
+    validated?;
+
+    let key_pem = order.finalize().await?;
+    let cert_pem = order.poll_certificate(&RetryPolicy::default()).await?;
+
+    // The key goes first so the certificate never points at a missing key
+    write_atomically(&acme.key_path(), key_pem.as_bytes())?;
+    write_atomically(&acme.cert_path(), cert_pem.as_bytes())?;
+    info!(
+        "Stored certificate for {} in {}",
+        acme.domains.join(", "),
+        acme.state_dir.display()
+    );
+    Ok(())
+}
+
+async fn load_or_create_account(acme: &AcmeConfig) -> Result<Account, AcmeError> {
+    let builder = match &acme.root_ca_path {
+        Some(root_ca_path) => Account::builder_with_root(root_ca_path)?,
+        None => Account::builder()?,
+    };
+
+    let account_path = acme.account_path();
+    if account_path.exists() {
+        let credentials: AccountCredentials =
+            serde_json::from_str(&fs::read_to_string(&account_path)?)?;
+        return Ok(builder.from_credentials(credentials).await?);
+    }
+
+    let contact: Vec<&str> = acme.contact.iter().map(String::as_str).collect();
+    let (account, credentials) = builder
+        .create(
+            &NewAccount {
+                contact: &contact,
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            acme.directory_url.clone(),
+            None,
+        )
+        .await?;
+    write_atomically(
+        &account_path,
+        serde_json::to_string(&credentials)?.as_bytes(),
+    )?;
+    info!("Registered ACME account with {}", acme.directory_url);
+    Ok(account)
+}
+
+async fn run_dns_hook(
+    hook: &Path,
+    action: &str,
+    domain: &str,
+    value: &str,
+) -> Result<(), AcmeError> {
+    let status = Command::new(hook)
+        .args([action, domain, value])
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(format!(
+            "DNS hook {} {} {} exited with {}",
+            hook.display(),
+            action,
+            domain,
+            status
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Writes through a temporary file so a reload never sees half a PEM file.
+/// The file is readable by its owner only, since it may hold a private key
+/// or the account credentials.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    // A leftover from an interrupted write would keep its old mode
+    match fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
@@ -0,0 +1,30 @@
+//! Helpers for internationalised (RFC 6531) mailbox addresses.
+
+/// Splits a mailbox at its last `@` into local part and domain.
+pub fn split(address: &str) -> Option<(&str, &str)> {
+    address.rsplit_once('@')
+}
+
+/// The address with its domain in lower-case ASCII (punycode) form, which is
+/// what routing compares. Local parts are left alone. Returns `None` if the
+/// domain is not a valid IDN.
+pub fn to_ascii(address: &str) -> Option<String> {
+    match split(address) {
+        Some((local, domain)) => {
+            let domain = idna::domain_to_ascii(domain).ok()?;
+            Some(format!("{}@{}", local, domain))
+        }
+        None => Some(address.to_string()),
+    }
+}
+
+/// The address with any punycode labels in its domain decoded to Unicode.
+pub fn to_unicode(address: &str) -> String {
+    match split(address) {
+        Some((local, domain)) => {
+            let (domain, _) = idna::domain_to_unicode(domain);
+            format!("{}@{}", local, domain)
+        }
+        None => address.to_string(),
+    }
+}
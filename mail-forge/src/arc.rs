@@ -0,0 +1,271 @@
+//! ARC (RFC 8617) validation of the chain of `ARC-Seal`,
+//! `ARC-Message-Signature` and `ARC-Authentication-Results` headers that
+//! forwarders such as mailing lists add, vouching for what they saw before
+//! they changed the message.
+
+use crate::dkim::{self, permerror, Algorithm, Canonicalization, Failure, Header, Signature};
+use crate::dns::Resolver;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Highest instance number a chain may reach (section 4.2.1).
+const MAX_INSTANCES: usize = 50;
+
+/// The chain validation status, named as in `Authentication-Results`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArcResult {
+    /// The message carries no ARC headers.
+    None,
+    Pass,
+    Fail,
+}
+
+impl fmt::Display for ArcResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ArcResult::None => "none",
+            ArcResult::Pass => "pass",
+            ArcResult::Fail => "fail",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One forwarder in the chain and what it recorded on receiving the message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArcHop {
+    pub instance: usize,
+    /// The domain that sealed this hop (the seal's `d=`).
+    pub sealer: String,
+    /// Who did the checking, as named in `ARC-Authentication-Results`.
+    pub authserv_id: String,
+    /// The results it recorded, such as `spf=pass smtp.mailfrom=example.org`.
+    pub results: String,
+}
+
+/// What the ARC chain of one message says.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArcVerdict {
+    pub result: ArcResult,
+    /// Why the chain failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The hops oldest first, so the first holds the results from before
+    /// the message was forwarded. Only to be trusted if the chain passed.
+    pub hops: Vec<ArcHop>,
+}
+
+/// Indexes into the message's headers of the three headers of one instance.
+struct ArcSet {
+    results: usize,
+    signature: usize,
+    seal: usize,
+}
+
+/// Validates the ARC chain of `message` (section 5.2).
+pub async fn verify(resolver: &dyn Resolver, message: &[u8]) -> ArcVerdict {
+    let message = dkim::crlf_line_endings(message);
+    let (headers, body) = dkim::split_message(&message);
+    let fail = |reason: &str, hops: Vec<ArcHop>| ArcVerdict {
+        result: ArcResult::Fail,
+        reason: Some(reason.to_string()),
+        hops,
+    };
+
+    let sets = match collect_sets(&headers) {
+        Ok(sets) => sets,
+        Err(reason) => return fail(reason, Vec::new()),
+    };
+    if sets.is_empty() {
+        return ArcVerdict {
+            result: ArcResult::None,
+            reason: None,
+            hops: Vec::new(),
+        };
+    }
+    let hops = sets
+        .iter()
+        .enumerate()
+        .map(|(index, set)| hop(&headers, index + 1, set))
+        .collect();
+
+    // The first seal starts the chain and every later one vouches for it
+    for (index, set) in sets.iter().enumerate() {
+        let tags = dkim::parse_tags(&headers[set.seal].value()).unwrap_or_default();
+        let expected = if index == 0 { "none" } else { "pass" };
+        match dkim::tag_value(&tags, "cv") {
+            Some(cv) if cv.eq_ignore_ascii_case(expected) => {}
+            Some(cv) if cv.eq_ignore_ascii_case("fail") => {
+                return fail("chain already failed", hops)
+            }
+            _ => return fail("invalid chain validation status", hops),
+        }
+    }
+
+    let newest = sets.last().expect("chain has at least one set");
+    if let Err((_, reason)) =
+        verify_message_signature(resolver, &headers, newest.signature, body).await
+    {
+        return fail(&format!("message signature: {}", reason), hops);
+    }
+    for instance in (1..=sets.len()).rev() {
+        if let Err((_, reason)) = verify_seal(resolver, &headers, &sets[..instance]).await {
+            return fail(&format!("seal {}: {}", instance, reason), hops);
+        }
+    }
+    ArcVerdict {
+        result: ArcResult::Pass,
+        reason: None,
+        hops,
+    }
+}
+
+/// Groups the ARC headers into complete sets, ordered by instance.
+fn collect_sets(headers: &[Header<'_>]) -> Result<Vec<ArcSet>, &'static str> {
+    let mut found: Vec<[Option<usize>; 3]> = Vec::new();
+    for (index, header) in headers.iter().enumerate() {
+        let name = header.name.trim();
+        let (kind, instance) = if name.eq_ignore_ascii_case("ARC-Authentication-Results") {
+            // Not a tag list: the instance is followed by the results
+            let value = header.value();
+            let first = value.split(';').next().unwrap_or("");
+            (0, instance_number(first.split_once('=')))
+        } else if name.eq_ignore_ascii_case("ARC-Message-Signature") {
+            (1, tag_instance(header))
+        } else if name.eq_ignore_ascii_case("ARC-Seal") {
+            (2, tag_instance(header))
+        } else {
+            continue;
+        };
+
+        let instance = instance.ok_or("invalid instance")?;
+        if instance > found.len() {
+            found.resize(instance, [None; 3]);
+        }
+        let slot = &mut found[instance - 1][kind];
+        if slot.is_some() {
+            return Err("duplicate ARC header");
+        }
+        *slot = Some(index);
+    }
+
+    found
+        .into_iter()
+        .map(|set| match set {
+            [Some(results), Some(signature), Some(seal)] => Ok(ArcSet {
+                results,
+                signature,
+                seal,
+            }),
+            _ => Err("incomplete ARC set"),
+        })
+        .collect()
+}
+
+fn tag_instance(header: &Header<'_>) -> Option<usize> {
+    let tags = dkim::parse_tags(&header.value()).ok()?;
+    instance_number(dkim::tag_value(&tags, "i").map(|i| ("i", i)))
+}
+
+fn instance_number(tag: Option<(&str, &str)>) -> Option<usize> {
+    let (name, value) = tag?;
+    if name.trim() != "i" {
+        return None;
+    }
+    let instance: usize = value.trim().parse().ok()?;
+    (1..=MAX_INSTANCES).contains(&instance).then_some(instance)
+}
+
+fn hop(headers: &[Header<'_>], instance: usize, set: &ArcSet) -> ArcHop {
+    let tags = dkim::parse_tags(&headers[set.seal].value()).unwrap_or_default();
+    let value = headers[set.results].value();
+    let mut fields = value.splitn(3, ';').skip(1);
+    let authserv_id = fields.next().unwrap_or("").trim().to_string();
+    let results = fields
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    ArcHop {
+        instance,
+        sealer: dkim::tag_value(&tags, "d")
+            .unwrap_or_default()
+            .to_ascii_lowercase(),
+        authserv_id,
+        results,
+    }
+}
+
+/// Checks an `ARC-Message-Signature`, which works like a DKIM signature
+/// with `i=` numbering the hop instead of naming an identity.
+async fn verify_message_signature(
+    resolver: &dyn Resolver,
+    headers: &[Header<'_>],
+    index: usize,
+    body: &[u8],
+) -> Result<(), Failure> {
+    let signature = &headers[index];
+    let tags = dkim::parse_tags(&signature.value()).map_err(|e| permerror(&e))?;
+    let params = Signature::parse(&tags)?;
+
+    let body_hash = dkim::body_hash(params.body_canonicalization, body, params.length)?;
+    if body_hash != params.body_hash {
+        return Err(permerror("body hash mismatch"));
+    }
+    let key = dkim::fetch_key(resolver, &params.selector, &params.domain).await?;
+    if key.algorithm != params.algorithm {
+        return Err(permerror("key type mismatch"));
+    }
+    let data = dkim::signed_data(
+        headers,
+        &params.headers,
+        signature,
+        params.header_canonicalization,
+    );
+    if !params.algorithm.verify(&key.key, &data, &params.signature) {
+        return Err(permerror("signature did not verify"));
+    }
+    Ok(())
+}
+
+/// Checks the newest seal in `sets`, which covers every ARC header up to
+/// and including its own, oldest first, in relaxed form (section 5.1.1).
+async fn verify_seal(
+    resolver: &dyn Resolver,
+    headers: &[Header<'_>],
+    sets: &[ArcSet],
+) -> Result<(), Failure> {
+    let (newest, older) = sets.split_last().expect("seal to check");
+    let seal = &headers[newest.seal];
+    let tags = dkim::parse_tags(&seal.value()).map_err(|e| permerror(&e))?;
+    if dkim::tag_value(&tags, "h").is_some() {
+        return Err(permerror("seal must not have h= tag"));
+    }
+    let required = |name: &str| {
+        dkim::tag_value(&tags, name).ok_or_else(|| permerror(&format!("missing {}= tag", name)))
+    };
+    let algorithm = Algorithm::parse(required("a")?)?;
+    let signature = dkim::decode_base64(required("b")?)?;
+    let domain = required("d")?.to_ascii_lowercase();
+    let key = dkim::fetch_key(resolver, required("s")?, &domain).await?;
+    if key.algorithm != algorithm {
+        return Err(permerror("key type mismatch"));
+    }
+
+    let relaxed = Canonicalization::Relaxed;
+    let mut data = Vec::new();
+    for set in older {
+        data.extend(relaxed.header(&headers[set.results]));
+        data.extend(relaxed.header(&headers[set.signature]));
+        data.extend(relaxed.header(&headers[set.seal]));
+    }
+    data.extend(relaxed.header(&headers[newest.results]));
+    data.extend(relaxed.header(&headers[newest.signature]));
+    data.extend(dkim::unsigned_header(seal, relaxed));
+    if !algorithm.verify(&key.key, &data, &signature) {
+        return Err(permerror("signature did not verify"));
+    }
+    Ok(())
+}
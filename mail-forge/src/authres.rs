@@ -0,0 +1,115 @@
+//! The `Authentication-Results` header (RFC 8601) recording what SPF, DKIM,
+//! DMARC and ARC said about a message, for the webhook's benefit.
+
+use crate::arc::ArcResult;
+use crate::dkim;
+use crate::spool::Envelope;
+
+/// The header for `envelope`, with `authserv_id` naming us as the one who
+/// did the checking.
+pub fn header(authserv_id: &str, envelope: &Envelope) -> String {
+    let mut results = Vec::new();
+    if let Some(user) = &envelope.auth_user {
+        results.push(format!("auth=pass smtp.auth={}", value(user)));
+    }
+    if let Some(spf) = &envelope.spf {
+        let property = if envelope.mail_from.is_empty() {
+            "smtp.helo"
+        } else {
+            "smtp.mailfrom"
+        };
+        let mut result = format!("spf={}", spf.result);
+        if !spf.domain.is_empty() {
+            result.push_str(&format!(" {}={}", property, value(&spf.domain)));
+        }
+        results.push(result);
+    }
+    for signature in envelope.dkim.iter().flatten() {
+        let mut result = format!("dkim={}", signature.result);
+        if let Some(reason) = &signature.reason {
+            result.push_str(&format!(" reason={}", quoted(reason)));
+        }
+        if !signature.domain.is_empty() {
+            result.push_str(&format!(" header.d={}", value(&signature.domain)));
+        }
+        if !signature.selector.is_empty() {
+            result.push_str(&format!(" header.s={}", value(&signature.selector)));
+        }
+        results.push(result);
+    }
+    if let Some(dmarc) = &envelope.dmarc {
+        let mut result = format!("dmarc={}", dmarc.result);
+        if let (Some(record), Some(policy)) = (&dmarc.record, dmarc.policy) {
+            result.push_str(&format!(" (p={} dis={})", record.policy, policy));
+        }
+        if !dmarc.domain.is_empty() {
+            result.push_str(&format!(" header.from={}", value(&dmarc.domain)));
+        }
+        results.push(result);
+    }
+
+    // Only forwarded mail has a chain worth mentioning
+    let arc = envelope.arc.as_ref().filter(|arc| arc.result != ArcResult::None);
+    if let Some(arc) = arc {
+        let mut result = format!("arc={}", arc.result);
+        if let Some(reason) = &arc.reason {
+            result.push_str(&format!(" reason={}", quoted(reason)));
+        }
+        results.push(result);
+    }
+
+    if results.is_empty() {
+        return format!("Authentication-Results: {}; none\r\n", authserv_id);
+    }
+    format!(
+        "Authentication-Results: {};\r\n\t{}\r\n",
+        authserv_id,
+        results.join(";\r\n\t")
+    )
+}
+
+/// Drops `Authentication-Results` headers claiming to come from
+/// `authserv_id`, which can only be forgeries (RFC 8601 section 5).
+pub fn remove_forged(message: Vec<u8>, authserv_id: &str) -> Vec<u8> {
+    let normalized = dkim::crlf_line_endings(&message);
+    let (headers, body) = dkim::split_message(&normalized);
+    let forged = |header: &dkim::Header<'_>| {
+        header
+            .name
+            .trim()
+            .eq_ignore_ascii_case("Authentication-Results")
+            && header
+                .value()
+                .split(';')
+                .next()
+                .is_some_and(|id| id.trim().eq_ignore_ascii_case(authserv_id))
+    };
+    if !headers.iter().any(forged) {
+        return message;
+    }
+
+    let mut cleaned = Vec::with_capacity(normalized.len());
+    for header in headers.iter().filter(|header| !forged(header)) {
+        cleaned.extend_from_slice(header.raw);
+    }
+    cleaned.extend_from_slice(b"\r\n");
+    cleaned.extend_from_slice(body);
+    cleaned
+}
+
+/// A property value, quoted unless it is a plain token or address.
+fn value(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-./=?@^_`{|}~".contains(c));
+    if plain {
+        value.to_string()
+    } else {
+        quoted(value)
+    }
+}
+
+fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
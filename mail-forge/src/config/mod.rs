@@ -1,49 +1,578 @@
+use crate::tls;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::fs;
 use std::path::{Path, PathBuf};
-use tokio_rustls::rustls::pki_types::pem::PemObject;
-use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::sync::Arc;
 
 impl Config {
     pub fn load<P: AsRef<Path>>(file_path: P) -> Result<Self, Box<dyn std::error::Error>> {
         let config_contents = fs::read_to_string(file_path)?;
         let config: Config = toml::from_str(&config_contents)?;
+        if config.effective_listeners().is_empty() {
+            return Err(
+                "No listeners configured: set [[listeners]] or server.smtp_bind_address".into(),
+            );
+        }
+        if let Some(acme) = &config.acme {
+            if acme.challenge == AcmeChallenge::Dns01 && acme.dns_hook.is_none() {
+                return Err("ACME DNS-01 challenges need acme.dns_hook".into());
+            }
+        }
+        if config.listeners.iter().any(|listener| listener.auth) && config.credentials.is_empty()
+        {
+            return Err("Listeners with auth enabled need [[credentials]]".into());
+        }
+        if config.tls.client_auth != ClientAuth::None && config.tls.client_ca_path.is_none() {
+            return Err("tls.client_auth needs tls.client_ca_path".into());
+        }
         Ok(config)
     }
+
+    /// The certificate and key served when no `[[tls.certificates]]` entry
+    /// matches: the ACME-managed pair if `[acme]` is set, else the `[server]` one.
+    pub fn default_certificate(&self) -> (PathBuf, PathBuf) {
+        match &self.acme {
+            Some(acme) => (acme.cert_path(), acme.key_path()),
+            None => (
+                PathBuf::from(&self.server.cert_path),
+                PathBuf::from(&self.server.key_path),
+            ),
+        }
+    }
+
+    /// The `[[listeners]]` entries, or when there are none, the listeners
+    /// described by the `[server]` bind addresses.
+    pub fn effective_listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        let mut listeners = Vec::new();
+        if let Some(address) = &self.server.smtp_bind_address {
+            listeners.push(ListenerConfig::new(address.clone(), ListenerMode::Smtp));
+        }
+        if let Some(address) = &self.server.lmtp_bind_address {
+            listeners.push(ListenerConfig::new(address.clone(), ListenerMode::Lmtp));
+        }
+        for listener in &mut listeners {
+            listener.require_tls = self.server.require_tls;
+        }
+        listeners
+    }
 }
 
-pub fn load_certs(
-    cert_path: PathBuf,
-    key_path: PathBuf,
-) -> Result<rustls::ServerConfig, Box<dyn std::error::Error>> {
-    let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
-    let key = PrivateKeyDer::from_pem_file(key_path)?;
+/// Builds the TLS configuration shared by all listeners. The `[server]`
+/// certificate is the default; `[[tls.certificates]]` entries are picked by SNI.
+pub fn load_certs(config: &Config) -> Result<rustls::ServerConfig, Box<dyn std::error::Error>> {
+    let provider = Arc::new(tls::crypto_provider(&config.tls)?);
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(config.tls.min_version.protocol_versions())?;
+    let resolver = tls::CertResolver::load(config, &provider)?;
 
-    let config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)?;
+    let mut server_config = builder
+        .with_client_cert_verifier(tls::client_cert_verifier(&config.tls, provider)?)
+        .with_cert_resolver(Arc::new(resolver));
+    server_config.alpn_protocols = config
+        .tls
+        .alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
 
-    Ok(config)
+    Ok(server_config)
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub webhooks: HashMap<String, WebhookConfig>,
+    #[serde(default)]
+    pub spool: SpoolConfig,
+    #[serde(default)]
+    pub dsn: DsnConfig,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    pub acme: Option<AcmeConfig>,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Logins accepted by listeners with `auth` enabled.
+    #[serde(default)]
+    pub credentials: Vec<CredentialConfig>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ServerConfig {
-    pub smtp_bind_address: String,
+    /// Plain SMTP listener, used when no `[[listeners]]` are configured.
+    pub smtp_bind_address: Option<String>,
+    /// Optional second listener speaking LMTP, e.g. for delivery from Postfix.
+    pub lmtp_bind_address: Option<String>,
+    /// Refuse MAIL with 530 until STARTTLS on the listeners above;
+    /// `[[listeners]]` entries set this individually.
+    #[serde(default)]
+    pub require_tls: bool,
     pub hostname: String,
     pub max_size: usize,
+    /// Default certificate and key; not needed when `[acme]` manages them.
+    #[serde(default)]
     pub cert_path: String,
+    #[serde(default)]
     pub key_path: String,
+    /// Seconds allowed for the PROXY header and implicit TLS handshake that
+    /// come before the greeting.
+    #[serde(default = "default_greeting_timeout_secs")]
+    pub greeting_timeout_secs: u64,
+    /// Seconds to wait for the next command (RFC 5321 section 4.5.3.2.7).
+    #[serde(default = "default_command_timeout_secs")]
+    pub command_timeout_secs: u64,
+    /// Seconds to wait for each further piece of message content (section 4.5.3.2.5).
+    #[serde(default = "default_data_block_timeout_secs")]
+    pub data_block_timeout_secs: u64,
+    /// Seconds we may spend on a complete message before replying to it
+    /// (section 4.5.3.2.6).
+    #[serde(default = "default_data_termination_timeout_secs")]
+    pub data_termination_timeout_secs: u64,
+    /// Longest a session may last, however busy the client keeps it.
+    #[serde(default = "default_max_session_secs")]
+    pub max_session_secs: u64,
+}
+
+fn default_greeting_timeout_secs() -> u64 {
+    60
+}
+
+fn default_command_timeout_secs() -> u64 {
+    5 * 60
+}
+
+fn default_data_block_timeout_secs() -> u64 {
+    3 * 60
+}
+
+fn default_data_termination_timeout_secs() -> u64 {
+    10 * 60
+}
+
+fn default_max_session_secs() -> u64 {
+    60 * 60
+}
+
+/// Caps that keep a single client from using up the server.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LimitsConfig {
+    /// Concurrent sessions across all listeners; 0 lifts the cap.
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+    /// Concurrent sessions from one client network, see the prefixes below;
+    /// 0 lifts the cap.
+    #[serde(default = "default_max_sessions_per_ip")]
+    pub max_sessions_per_ip: usize,
+    /// Prefix lengths that group client addresses into one network for
+    /// `max_sessions_per_ip`; IPv6 clients usually get a whole /64.
+    #[serde(default = "default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+    /// Messages (MAIL commands) one address may start per minute, unlimited if unset.
+    pub messages_per_minute: Option<u32>,
+    /// Recipients one address may give per minute, unlimited if unset.
+    pub recipients_per_minute: Option<u32>,
+    /// Recipients accepted for a single message; RFC 5321 asks for at least 100.
+    /// 0 lifts the cap.
+    #[serde(default = "default_max_recipients")]
+    pub max_recipients: usize,
+    /// Messages one session may send before it has to reconnect; 0 lifts the cap.
+    #[serde(default = "default_max_messages_per_session")]
+    pub max_messages_per_session: usize,
+}
+
+impl LimitsConfig {
+    /// The network `ip` counts towards for `max_sessions_per_ip`.
+    pub fn network(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - self.ipv4_prefix.min(32) as u32);
+                IpAddr::V4((u32::from(ip) & mask.unwrap_or(0)).into())
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - self.ipv6_prefix.min(128) as u32);
+                IpAddr::V6((u128::from(ip) & mask.unwrap_or(0)).into())
+            }
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_sessions: default_max_sessions(),
+            max_sessions_per_ip: default_max_sessions_per_ip(),
+            ipv4_prefix: default_ipv4_prefix(),
+            ipv6_prefix: default_ipv6_prefix(),
+            messages_per_minute: None,
+            recipients_per_minute: None,
+            max_recipients: default_max_recipients(),
+            max_messages_per_session: default_max_messages_per_session(),
+        }
+    }
+}
+
+fn default_max_sessions() -> usize {
+    1000
+}
+
+fn default_max_sessions_per_ip() -> usize {
+    20
+}
+
+fn default_max_recipients() -> usize {
+    100
+}
+
+fn default_max_messages_per_session() -> usize {
+    100
+}
+
+fn default_ipv4_prefix() -> u8 {
+    32
+}
+
+fn default_ipv6_prefix() -> u8 {
+    64
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TlsConfig {
+    /// Additional certificates, chosen by the server name the client asks for.
+    #[serde(default)]
+    pub certificates: Vec<CertificateConfig>,
+    /// How often to check the certificate files for renewals; 0 leaves
+    /// reloading to SIGHUP alone.
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// Cipher suites to offer, by their IANA names such as
+    /// `TLS13_AES_256_GCM_SHA384`; empty means the rustls defaults.
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// ALPN protocol names to accept, in order of preference.
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+    /// PEM bundle of the CAs client certificates must chain to.
+    pub client_ca_path: Option<PathBuf>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            certificates: Vec::new(),
+            reload_interval_secs: default_reload_interval_secs(),
+            min_version: TlsVersion::default(),
+            cipher_suites: Vec::new(),
+            alpn: Vec::new(),
+            client_auth: ClientAuth::default(),
+            client_ca_path: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    /// The protocol versions allowed with this as the minimum.
+    pub fn protocol_versions(self) -> &'static [&'static rustls::SupportedProtocolVersion] {
+        const TLS12_AND_LATER: &[&rustls::SupportedProtocolVersion] =
+            &[&rustls::version::TLS13, &rustls::version::TLS12];
+        const TLS13_ONLY: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS13];
+        match self {
+            TlsVersion::Tls12 => TLS12_AND_LATER,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        }
+    }
+}
+
+/// Whether clients are asked for a certificate during the handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    #[default]
+    None,
+    /// Verify a certificate if the client sends one, but don't insist.
+    Optional,
+    /// Refuse handshakes without a valid client certificate.
+    Required,
+}
+
+fn default_reload_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CertificateConfig {
+    /// Host name this certificate is for; `*.example.com` matches one label.
+    pub server_name: String,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
 }
 
+/// Automatic certificate management (RFC 8555). The certificate covers all
+/// `domains` and replaces the `[server]` certificate as the default.
 #[derive(Debug, Deserialize)]
+pub struct AcmeConfig {
+    #[serde(default = "default_acme_directory_url")]
+    pub directory_url: String,
+    pub domains: Vec<String>,
+    /// Contact URIs for the account, like `mailto:postmaster@example.com`.
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Holds the account credentials and the issued certificate and key.
+    #[serde(default = "default_acme_state_dir")]
+    pub state_dir: PathBuf,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    /// Where the HTTP-01 responder listens while an order is pending.
+    #[serde(default = "default_acme_http_bind_address")]
+    pub http_bind_address: String,
+    /// Command run as `<hook> present|cleanup <domain> <txt-value>` to set
+    /// and remove the `_acme-challenge` TXT record for DNS-01.
+    pub dns_hook: Option<PathBuf>,
+    /// Renew once the certificate has fewer days than this left.
+    #[serde(default = "default_acme_renew_before_days")]
+    pub renew_before_days: u64,
+    /// Extra CA to trust for the directory's HTTPS, e.g. Pebble's test CA.
+    pub root_ca_path: Option<PathBuf>,
+}
+
+impl AcmeConfig {
+    pub fn cert_path(&self) -> PathBuf {
+        self.state_dir.join("cert.pem")
+    }
+
+    pub fn key_path(&self) -> PathBuf {
+        self.state_dir.join("key.pem")
+    }
+
+    pub fn account_path(&self) -> PathBuf {
+        self.state_dir.join("account.json")
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum AcmeChallenge {
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "dns-01")]
+    Dns01,
+}
+
+fn default_acme_directory_url() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_string()
+}
+
+fn default_acme_state_dir() -> PathBuf {
+    PathBuf::from("/var/lib/mail-forge/acme")
+}
+
+fn default_acme_http_bind_address() -> String {
+    "0.0.0.0:80".to_string()
+}
+
+fn default_acme_renew_before_days() -> u64 {
+    30
+}
+
+/// One socket to accept connections on. All listeners share the spool and
+/// the webhook routing table.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerConfig {
+    pub address: String,
+    #[serde(default)]
+    pub mode: ListenerMode,
+    /// Refuse mail transactions until the connection is encrypted.
+    #[serde(default)]
+    pub require_tls: bool,
+    /// Text after the hostname in the 220 greeting.
+    pub banner: Option<String>,
+    /// Expect a PROXY protocol v1/v2 header from a load balancer before the
+    /// greeting, and treat the client it names as the peer.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Offer SMTP AUTH once the connection is encrypted and require it for
+    /// MAIL, as on a submission port.
+    #[serde(default)]
+    pub auth: bool,
+}
+
+impl ListenerConfig {
+    pub fn new(address: String, mode: ListenerMode) -> Self {
+        Self {
+            address,
+            mode,
+            require_tls: false,
+            banner: None,
+            proxy_protocol: false,
+            auth: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerMode {
+    /// SMTP with STARTTLS offered, typically port 25 or 587.
+    #[default]
+    Smtp,
+    /// SMTP inside TLS from the first byte (RFC 8314), typically port 465.
+    Smtps,
+    /// LMTP (RFC 2033) with STARTTLS offered.
+    Lmtp,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct WebhookConfig {
     pub url: String,
     pub api_key: String,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// What to do with mail whose sender fails its SPF check.
+    #[serde(default)]
+    pub on_spf_fail: SpfFailAction,
+    /// Whether to refuse mail once DATA is complete when it fails DMARC and
+    /// its From domain asks for that with `p=reject`. Mail arriving over LMTP
+    /// isn't checked against DMARC.
+    #[serde(default)]
+    pub honor_dmarc_reject: bool,
+    /// Seconds allowed for connecting to the webhook.
+    #[serde(default = "default_webhook_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Seconds allowed for a whole request, from connecting to reading the
+    /// response. Running out counts as a network error and is retried.
+    #[serde(default = "default_webhook_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_webhook_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_webhook_timeout_secs() -> u64 {
+    60
+}
+
+/// When, if at all, mail failing SPF is refused for a webhook.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpfFailAction {
+    /// Deliver it anyway; the webhook still sees the `spf` result.
+    #[default]
+    Accept,
+    /// Refuse the webhook's recipients at RCPT.
+    RejectRcpt,
+    /// Take the message but refuse it once DATA is complete.
+    RejectData,
+}
+
+/// A login for SMTP AUTH. Mail submitted with it goes to its own webhook,
+/// whatever the recipients.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CredentialConfig {
+    pub username: String,
+    /// Argon2 PHC string (`$argon2id$...`) or bcrypt hash (`$2b$...`).
+    pub password_hash: String,
+    pub webhook: WebhookConfig,
+}
+
+/// How the spool retries deliveries that failed with a 5xx or a network error.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetryPolicy {
+    /// Attempts allowed before a recipient is dead-lettered. Network errors are
+    /// retried regardless, until the message reaches `max_age_secs`.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_initial_delay_secs")]
+    pub initial_delay_secs: u64,
+    /// Each subsequent delay is the previous one multiplied by this factor.
+    #[serde(default = "default_backoff_factor")]
+    pub backoff_factor: f64,
+    /// Messages older than this are dead-lettered instead of retried.
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            initial_delay_secs: default_initial_delay_secs(),
+            backoff_factor: default_backoff_factor(),
+            max_age_secs: default_max_age_secs(),
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    10
+}
+
+fn default_initial_delay_secs() -> u64 {
+    60
+}
+
+fn default_backoff_factor() -> f64 {
+    2.0
+}
+
+fn default_max_age_secs() -> u64 {
+    2 * 24 * 60 * 60
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpoolConfig {
+    /// Directory holding accepted messages until every recipient has been delivered.
+    #[serde(default = "default_spool_dir")]
+    pub dir: PathBuf,
+    /// Number of background tasks delivering spooled messages to webhooks.
+    #[serde(default = "default_spool_workers")]
+    pub workers: usize,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_spool_dir(),
+            workers: default_spool_workers(),
+        }
+    }
+}
+
+fn default_spool_dir() -> PathBuf {
+    PathBuf::from("/var/spool/mail-forge")
+}
+
+fn default_spool_workers() -> usize {
+    4
+}
+
+/// Where delivery status notifications for failed recipients are sent. With
+/// neither option set, failures are only logged and dead-lettered.
+#[derive(Debug, Default, Deserialize)]
+pub struct DsnConfig {
+    /// SMTP relay (`host:port`) that accepts bounces for the original senders.
+    pub smarthost: Option<String>,
+    /// Directory to drop bounces into when there is no smarthost or it fails.
+    pub outbox_dir: Option<PathBuf>,
 }
@@ -0,0 +1,641 @@
+//! DKIM (RFC 6376) verification of the signatures on a received message,
+//! with the rsa-sha256 and ed25519-sha256 (RFC 8463) algorithms.
+
+use crate::dns::Resolver;
+use aws_lc_rs::signature::{
+    UnparsedPublicKey, VerificationAlgorithm, ED25519,
+    RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt;
+
+/// Signatures looked at per message; any beyond this are ignored, so a
+/// message can't make us do an unbounded number of key lookups.
+const MAX_SIGNATURES: usize = 10;
+
+/// The result for one signature, named as in `Authentication-Results`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimResult {
+    Pass,
+    /// The signature or body hash doesn't match the message.
+    Fail,
+    /// The key couldn't be fetched, but might be later.
+    TempError,
+    /// The signature or its key is malformed or unacceptable.
+    PermError,
+}
+
+impl fmt::Display for DkimResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DkimResult::Pass => "pass",
+            DkimResult::Fail => "fail",
+            DkimResult::TempError => "temperror",
+            DkimResult::PermError => "permerror",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What became of one `DKIM-Signature` header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DkimSignature {
+    /// The signing domain (`d=`), empty if the signature didn't name one.
+    pub domain: String,
+    pub selector: String,
+    pub result: DkimResult,
+    /// Why the signature didn't pass.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Verifies every DKIM signature on `message`, topmost first.
+pub async fn verify(resolver: &dyn Resolver, message: &[u8]) -> Vec<DkimSignature> {
+    let message = crlf_line_endings(message);
+    let (headers, body) = split_message(&message);
+
+    let mut results = Vec::new();
+    let signatures = headers
+        .iter()
+        .enumerate()
+        .filter(|(_, header)| header.name.eq_ignore_ascii_case("DKIM-Signature"))
+        .take(MAX_SIGNATURES);
+    for (index, header) in signatures {
+        let tags = parse_tags(&header.value()).unwrap_or_default();
+        let (result, reason) = match verify_signature(resolver, &headers, index, body).await {
+            Ok(()) => (DkimResult::Pass, None),
+            Err((result, reason)) => (result, Some(reason)),
+        };
+        results.push(DkimSignature {
+            domain: tag_value(&tags, "d")
+                .map(|d| d.to_ascii_lowercase())
+                .unwrap_or_default(),
+            selector: tag_value(&tags, "s").unwrap_or_default().to_string(),
+            result,
+            reason,
+        });
+    }
+    results
+}
+
+/// Why a signature didn't pass.
+pub(crate) type Failure = (DkimResult, String);
+
+pub(crate) fn permerror(reason: &str) -> Failure {
+    (DkimResult::PermError, reason.to_string())
+}
+
+async fn verify_signature(
+    resolver: &dyn Resolver,
+    headers: &[Header<'_>],
+    index: usize,
+    body: &[u8],
+) -> Result<(), Failure> {
+    let signature = &headers[index];
+    let tags = parse_tags(&signature.value()).map_err(|e| permerror(&e))?;
+    let params = Signature::parse(&tags)?;
+
+    if tag_value(&tags, "v") != Some("1") {
+        return Err(permerror("unsupported version"));
+    }
+    if !params
+        .headers
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case("From"))
+    {
+        return Err(permerror("From header not signed"));
+    }
+    let identity = tag_value(&tags, "i").map(|i| i.to_ascii_lowercase());
+    let identity_domain = identity
+        .as_deref()
+        .map(|i| i.rsplit_once('@').map_or(i, |(_, domain)| domain));
+    if let Some(identity_domain) = identity_domain {
+        if !is_subdomain(identity_domain, &params.domain) {
+            return Err(permerror("identity outside signing domain"));
+        }
+    }
+    if let Some(methods) = tag_value(&tags, "q") {
+        if !methods.split(':').any(|method| method.trim() == "dns/txt") {
+            return Err(permerror("unsupported query method"));
+        }
+    }
+    if let Some(expires) = tag_value(&tags, "x") {
+        let expires: i64 = expires.parse().map_err(|_| permerror("invalid expiry"))?;
+        if expires < Utc::now().timestamp() {
+            return Err(permerror("signature expired"));
+        }
+    }
+
+    let body_hash = body_hash(params.body_canonicalization, body, params.length)?;
+    if body_hash != params.body_hash {
+        return Err((DkimResult::Fail, "body hash mismatch".to_string()));
+    }
+
+    let key = fetch_key(resolver, &params.selector, &params.domain).await?;
+    if key.algorithm != params.algorithm {
+        return Err(permerror("key type mismatch"));
+    }
+    if key.strict && identity_domain.is_some_and(|domain| domain != params.domain) {
+        return Err(permerror("identity must match signing domain"));
+    }
+
+    let data = signed_data(
+        headers,
+        &params.headers,
+        signature,
+        params.header_canonicalization,
+    );
+    if !params.algorithm.verify(&key.key, &data, &params.signature) {
+        return Err((DkimResult::Fail, "signature did not verify".to_string()));
+    }
+    Ok(())
+}
+
+/// The parameters of a signature that matter for verifying it.
+pub(crate) struct Signature {
+    pub algorithm: Algorithm,
+    pub header_canonicalization: Canonicalization,
+    pub body_canonicalization: Canonicalization,
+    pub domain: String,
+    pub selector: String,
+    /// Names from `h=`, in the order they were signed.
+    pub headers: Vec<String>,
+    pub body_hash: Vec<u8>,
+    pub signature: Vec<u8>,
+    /// Octets of canonicalised body covered (`l=`), all if unset.
+    pub length: Option<usize>,
+}
+
+impl Signature {
+    /// Reads the tags shared by `DKIM-Signature` and `ARC-Message-Signature`.
+    pub(crate) fn parse(tags: &[(String, String)]) -> Result<Self, Failure> {
+        let required = |name: &str| {
+            tag_value(tags, name).ok_or_else(|| permerror(&format!("missing {}= tag", name)))
+        };
+
+        let algorithm = Algorithm::parse(required("a")?)?;
+        let (header_canonicalization, body_canonicalization) = match tag_value(tags, "c") {
+            None => (Canonicalization::Simple, Canonicalization::Simple),
+            Some(c) => {
+                let (header, body) = c.split_once('/').unwrap_or((c, "simple"));
+                (
+                    Canonicalization::parse(header)?,
+                    Canonicalization::parse(body)?,
+                )
+            }
+        };
+        let length = match tag_value(tags, "l") {
+            Some(l) => Some(l.parse().map_err(|_| permerror("invalid body length"))?),
+            None => None,
+        };
+        Ok(Self {
+            algorithm,
+            header_canonicalization,
+            body_canonicalization,
+            domain: required("d")?.to_ascii_lowercase(),
+            selector: required("s")?.to_string(),
+            headers: required("h")?
+                .split(':')
+                .map(|name| name.trim_matches(is_whitespace).to_string())
+                .collect(),
+            body_hash: decode_base64(required("bh")?)?,
+            signature: decode_base64(required("b")?)?,
+            length,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Algorithm {
+    RsaSha256,
+    Ed25519Sha256,
+}
+
+impl Algorithm {
+    pub(crate) fn parse(name: &str) -> Result<Self, Failure> {
+        match name.to_ascii_lowercase().as_str() {
+            "rsa-sha256" => Ok(Algorithm::RsaSha256),
+            "ed25519-sha256" => Ok(Algorithm::Ed25519Sha256),
+            _ => Err(permerror("unsupported algorithm")),
+        }
+    }
+
+    /// Checks `signature` over `data`. Ed25519 signs the SHA-256 digest of
+    /// the data rather than the data itself (RFC 8463 section 3).
+    pub(crate) fn verify(self, key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+        match self {
+            Algorithm::RsaSha256 => verify_with(
+                &RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                key,
+                data,
+                signature,
+            ),
+            Algorithm::Ed25519Sha256 => {
+                verify_with(&ED25519, key, &Sha256::digest(data), signature)
+            }
+        }
+    }
+}
+
+fn verify_with(
+    algorithm: &'static dyn VerificationAlgorithm,
+    key: &[u8],
+    data: &[u8],
+    signature: &[u8],
+) -> bool {
+    UnparsedPublicKey::new(algorithm, key)
+        .verify(data, signature)
+        .is_ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+impl Canonicalization {
+    fn parse(name: &str) -> Result<Self, Failure> {
+        match name.to_ascii_lowercase().as_str() {
+            "simple" => Ok(Canonicalization::Simple),
+            "relaxed" => Ok(Canonicalization::Relaxed),
+            _ => Err(permerror("unsupported canonicalization")),
+        }
+    }
+
+    /// One header field as it enters the signature (section 3.4.1 and 3.4.2).
+    pub(crate) fn header(self, header: &Header<'_>) -> Vec<u8> {
+        match self {
+            Canonicalization::Simple => header.raw.to_vec(),
+            Canonicalization::Relaxed => {
+                let mut canonical = header.name.trim_matches(is_whitespace).to_ascii_lowercase();
+                canonical.push(':');
+                canonical.push_str(&collapse_whitespace(&header.value()));
+                canonical.push_str("\r\n");
+                canonical.into_bytes()
+            }
+        }
+    }
+
+    /// The body as it enters the body hash (sections 3.4.3 and 3.4.4).
+    fn body(self, body: &[u8]) -> Vec<u8> {
+        let mut canonical = Vec::new();
+        // Empty lines are only written once something follows them
+        let mut empty_lines = 0;
+        let mut lines: Vec<&[u8]> = split_lines(body).collect();
+        if body.ends_with(b"\r\n") {
+            lines.pop();
+        }
+        for line in lines {
+            let line: Cow<[u8]> = match self {
+                Canonicalization::Simple => Cow::Borrowed(line),
+                Canonicalization::Relaxed => Cow::Owned(relax_body_line(line)),
+            };
+            if line.is_empty() {
+                empty_lines += 1;
+                continue;
+            }
+            for _ in 0..empty_lines {
+                canonical.extend_from_slice(b"\r\n");
+            }
+            empty_lines = 0;
+            canonical.extend_from_slice(&line);
+            canonical.extend_from_slice(b"\r\n");
+        }
+        if canonical.is_empty() && self == Canonicalization::Simple {
+            canonical.extend_from_slice(b"\r\n");
+        }
+        canonical
+    }
+}
+
+/// Collapses runs of whitespace in a body line and drops them at its end,
+/// working on bytes so that 8-bit content passes through untouched.
+fn relax_body_line(line: &[u8]) -> Vec<u8> {
+    let mut relaxed = Vec::with_capacity(line.len());
+    let mut in_whitespace = false;
+    for &byte in line {
+        if byte == b' ' || byte == b'\t' {
+            in_whitespace = true;
+            continue;
+        }
+        if in_whitespace {
+            relaxed.push(b' ');
+            in_whitespace = false;
+        }
+        relaxed.push(byte);
+    }
+    relaxed
+}
+
+/// Hashes the body as a signature covers it.
+pub(crate) fn body_hash(
+    canonicalization: Canonicalization,
+    body: &[u8],
+    length: Option<usize>,
+) -> Result<Vec<u8>, Failure> {
+    let canonical = canonicalization.body(body);
+    let covered = match length {
+        Some(length) if length > canonical.len() => {
+            return Err((
+                DkimResult::Fail,
+                "body shorter than signed length".to_string(),
+            ))
+        }
+        Some(length) => &canonical[..length],
+        None => &canonical[..],
+    };
+    Ok(Sha256::digest(covered).to_vec())
+}
+
+/// The data a signature is computed over: the signed headers, picked from
+/// the bottom up as section 5.4.2 describes, then the signature header
+/// itself with an empty `b=` and no final CRLF.
+pub(crate) fn signed_data(
+    headers: &[Header<'_>],
+    names: &[String],
+    signature: &Header<'_>,
+    canonicalization: Canonicalization,
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut used = vec![false; headers.len()];
+    for name in names {
+        let instance = (0..headers.len()).rev().find(|&i| {
+            !used[i]
+                && headers[i]
+                    .name
+                    .trim_matches(is_whitespace)
+                    .eq_ignore_ascii_case(name)
+        });
+        // Naming a header that isn't there signs its absence
+        if let Some(i) = instance {
+            used[i] = true;
+            data.extend(canonicalization.header(&headers[i]));
+        }
+    }
+
+    data.extend(unsigned_header(signature, canonicalization));
+    data
+}
+
+/// A signature header as it enters its own signature: with an empty `b=`
+/// and no final CRLF.
+pub(crate) fn unsigned_header(
+    signature: &Header<'_>,
+    canonicalization: Canonicalization,
+) -> Vec<u8> {
+    let unsigned = Header {
+        name: signature.name,
+        raw: &without_signature(signature.raw),
+    };
+    let mut canonical = canonicalization.header(&unsigned);
+    canonical.truncate(canonical.len().saturating_sub(2));
+    canonical
+}
+
+/// The header with the value of its `b=` tag removed, everything else
+/// left exactly as it was.
+fn without_signature(raw: &[u8]) -> Vec<u8> {
+    let raw = String::from_utf8_lossy(raw);
+    let (name, value) = raw.split_once(':').unwrap_or((&raw, ""));
+    let tags: Vec<String> = value
+        .split(';')
+        .map(|tag| match tag.split_once('=') {
+            Some((tag_name, _)) if tag_name.trim_matches(is_whitespace) == "b" => {
+                format!("{}=", tag_name)
+            }
+            _ => tag.to_string(),
+        })
+        .collect();
+    let mut stripped = format!("{}:{}", name, tags.join(";"));
+    // The value may have ended with b=, taking the line break with it
+    if !stripped.ends_with("\r\n") {
+        stripped.push_str("\r\n");
+    }
+    stripped.into_bytes()
+}
+
+/// A public key from `<selector>._domainkey.<domain>`.
+pub(crate) struct Key {
+    pub algorithm: Algorithm,
+    pub key: Vec<u8>,
+    /// `t=s`: the `i=` domain has to be the signing domain itself.
+    pub strict: bool,
+}
+
+pub(crate) async fn fetch_key(
+    resolver: &dyn Resolver,
+    selector: &str,
+    domain: &str,
+) -> Result<Key, Failure> {
+    let name = format!("{}._domainkey.{}", selector, domain);
+    let records = resolver
+        .txt(&name)
+        .await
+        .map_err(|e| (DkimResult::TempError, format!("key lookup failed: {}", e)))?;
+    let record = records
+        .first()
+        .ok_or_else(|| permerror("no key for signature"))?;
+    let tags = parse_tags(record).map_err(|e| permerror(&e))?;
+
+    if tag_value(&tags, "v").is_some_and(|v| v != "DKIM1") {
+        return Err(permerror("unsupported key version"));
+    }
+    let algorithm = match tag_value(&tags, "k").unwrap_or("rsa") {
+        "rsa" => Algorithm::RsaSha256,
+        "ed25519" => Algorithm::Ed25519Sha256,
+        _ => return Err(permerror("unsupported key type")),
+    };
+    if let Some(hashes) = tag_value(&tags, "h") {
+        if !hashes
+            .split(':')
+            .any(|hash| hash.trim_matches(is_whitespace) == "sha256")
+        {
+            return Err(permerror("key does not allow sha256"));
+        }
+    }
+    if let Some(services) = tag_value(&tags, "s") {
+        if !services
+            .split(':')
+            .any(|service| matches!(service.trim_matches(is_whitespace), "*" | "email"))
+        {
+            return Err(permerror("key is not for email"));
+        }
+    }
+    let strict = tag_value(&tags, "t").is_some_and(|flags| {
+        flags
+            .split(':')
+            .any(|flag| flag.trim_matches(is_whitespace) == "s")
+    });
+
+    let key = tag_value(&tags, "p").ok_or_else(|| permerror("key has no p= tag"))?;
+    if key.is_empty() {
+        return Err(permerror("key revoked"));
+    }
+    let key = STANDARD
+        .decode(strip_whitespace(key))
+        .map_err(|_| permerror("invalid key"))?;
+    Ok(Key {
+        algorithm,
+        key,
+        strict,
+    })
+}
+
+/// One header field of a message, `raw` being all of it up to and
+/// including the CRLF that ends its last line.
+pub(crate) struct Header<'a> {
+    pub name: &'a str,
+    pub raw: &'a [u8],
+}
+
+impl Header<'_> {
+    /// Everything after the colon, unfolded.
+    pub(crate) fn value(&self) -> String {
+        let raw = String::from_utf8_lossy(self.raw);
+        let value = raw.split_once(':').map_or("", |(_, value)| value);
+        value.replace("\r\n", "")
+    }
+}
+
+/// Splits a message into its header fields and its body.
+pub(crate) fn split_message(message: &[u8]) -> (Vec<Header<'_>>, &[u8]) {
+    let mut headers: Vec<Header> = Vec::new();
+    let mut start = 0;
+    let mut position = 0;
+    while position < message.len() {
+        let end = message[position..]
+            .windows(2)
+            .position(|pair| pair == b"\r\n")
+            .map_or(message.len(), |offset| position + offset + 2);
+        let line = &message[position..end];
+
+        if line == b"\r\n" {
+            // The empty line between headers and body
+            if start < position {
+                headers.push(header(&message[start..position]));
+            }
+            return (headers, &message[end..]);
+        }
+        let continuation = line.starts_with(b" ") || line.starts_with(b"\t");
+        if !continuation && start < position {
+            headers.push(header(&message[start..position]));
+            start = position;
+        }
+        position = end;
+    }
+    if start < position {
+        headers.push(header(&message[start..position]));
+    }
+    (headers, &[])
+}
+
+fn header(raw: &[u8]) -> Header<'_> {
+    let colon = raw
+        .iter()
+        .position(|&byte| byte == b':')
+        .unwrap_or(raw.len());
+    Header {
+        name: std::str::from_utf8(&raw[..colon]).unwrap_or(""),
+        raw,
+    }
+}
+
+/// The message with any bare LF line endings turned into CRLF, which is
+/// what signers hash.
+pub(crate) fn crlf_line_endings(message: &[u8]) -> Cow<'_, [u8]> {
+    let bare_lf = message
+        .iter()
+        .enumerate()
+        .any(|(i, &byte)| byte == b'\n' && (i == 0 || message[i - 1] != b'\r'));
+    if !bare_lf {
+        return Cow::Borrowed(message);
+    }
+    let mut converted = Vec::with_capacity(message.len() + message.len() / 32);
+    for (i, &byte) in message.iter().enumerate() {
+        if byte == b'\n' && (i == 0 || message[i - 1] != b'\r') {
+            converted.push(b'\r');
+        }
+        converted.push(byte);
+    }
+    Cow::Owned(converted)
+}
+
+/// Parses a `tag=value; ...` list (section 3.2). Duplicate tags make the
+/// whole list invalid.
+pub(crate) fn parse_tags(list: &str) -> Result<Vec<(String, String)>, String> {
+    let mut tags: Vec<(String, String)> = Vec::new();
+    for spec in list.split(';') {
+        if spec.trim_matches(is_whitespace).is_empty() {
+            continue;
+        }
+        let (name, value) = spec
+            .split_once('=')
+            .ok_or_else(|| "malformed tag list".to_string())?;
+        let name = name.trim_matches(is_whitespace);
+        if name.is_empty() || tags.iter().any(|(tag, _)| tag == name) {
+            return Err("malformed tag list".to_string());
+        }
+        tags.push((
+            name.to_string(),
+            value.trim_matches(is_whitespace).to_string(),
+        ));
+    }
+    Ok(tags)
+}
+
+pub(crate) fn decode_base64(value: &str) -> Result<Vec<u8>, Failure> {
+    STANDARD
+        .decode(strip_whitespace(value))
+        .map_err(|_| permerror("invalid base64"))
+}
+
+pub(crate) fn tag_value<'a>(tags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|(tag, _)| tag == name)
+        .map(|(_, value)| value.as_str())
+}
+
+fn split_lines(body: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = Some(body);
+    std::iter::from_fn(move || {
+        let current = rest?;
+        match current.windows(2).position(|pair| pair == b"\r\n") {
+            Some(end) => {
+                rest = Some(&current[end + 2..]);
+                Some(&current[..end])
+            }
+            None => {
+                rest = None;
+                Some(current)
+            }
+        }
+    })
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\r' | '\n')
+}
+
+fn strip_whitespace(value: &str) -> String {
+    value.chars().filter(|&c| !is_whitespace(c)).collect()
+}
+
+/// Unfolded header value with whitespace runs collapsed and trimmed.
+fn collapse_whitespace(value: &str) -> String {
+    value
+        .split(is_whitespace)
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn is_subdomain(name: &str, domain: &str) -> bool {
+    name == domain || name.ends_with(&format!(".{}", domain))
+}
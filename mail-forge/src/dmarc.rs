@@ -0,0 +1,294 @@
+//! DMARC (RFC 7489): whether a message's From domain is vouched for by an
+//! aligned SPF or DKIM pass, and what the domain asks receivers to do when
+//! it isn't.
+
+use crate::dkim::{self, DkimResult, DkimSignature};
+use crate::dns::Resolver;
+use crate::spf::{SpfResult, SpfVerdict};
+use mailparse::MailAddr;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The result of the check, named as in `Authentication-Results`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmarcResult {
+    /// The From domain publishes no DMARC record.
+    None,
+    Pass,
+    Fail,
+    /// The record couldn't be fetched, but might be later.
+    TempError,
+    /// The From header or the domain's record is unusable.
+    PermError,
+}
+
+impl fmt::Display for DmarcResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DmarcResult::None => "none",
+            DmarcResult::Pass => "pass",
+            DmarcResult::Fail => "fail",
+            DmarcResult::TempError => "temperror",
+            DmarcResult::PermError => "permerror",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What a domain asks receivers to do with mail that fails (section 6.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmarcPolicy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl DmarcPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Some(DmarcPolicy::None),
+            "quarantine" => Some(DmarcPolicy::Quarantine),
+            "reject" => Some(DmarcPolicy::Reject),
+            _ => None,
+        }
+    }
+
+    /// The next less severe policy, for messages left out by `pct`
+    /// (section 6.6.4).
+    fn relaxed(self) -> Self {
+        match self {
+            DmarcPolicy::Reject => DmarcPolicy::Quarantine,
+            _ => DmarcPolicy::None,
+        }
+    }
+}
+
+impl fmt::Display for DmarcPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DmarcPolicy::None => "none",
+            DmarcPolicy::Quarantine => "quarantine",
+            DmarcPolicy::Reject => "reject",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How closely an authenticated domain has to match the From domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Alignment {
+    /// The same organizational domain will do.
+    Relaxed,
+    /// Only the very same domain will do.
+    Strict,
+}
+
+/// A DMARC record as its domain published it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DmarcRecord {
+    /// Where the record was found.
+    pub domain: String,
+    /// `p=`
+    pub policy: DmarcPolicy,
+    /// `sp=`, which defaults to `p=`.
+    pub subdomain_policy: DmarcPolicy,
+    /// `pct=`, the share of failing mail the policy is meant for.
+    pub percent: u8,
+    /// `adkim=`
+    pub dkim_alignment: Alignment,
+    /// `aspf=`
+    pub spf_alignment: Alignment,
+}
+
+/// What DMARC says about one message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DmarcVerdict {
+    pub result: DmarcResult,
+    /// The domain of the From header, empty if there wasn't exactly one.
+    pub domain: String,
+    /// The policy for this message if it failed: `p=` or `sp=` after `pct=`
+    /// sampling.
+    pub policy: Option<DmarcPolicy>,
+    pub spf_aligned: bool,
+    pub dkim_aligned: bool,
+    /// The published record, if one was found.
+    pub record: Option<DmarcRecord>,
+}
+
+impl DmarcVerdict {
+    fn new(result: DmarcResult, domain: String) -> Self {
+        DmarcVerdict {
+            result,
+            domain,
+            policy: None,
+            spf_aligned: false,
+            dkim_aligned: false,
+            record: None,
+        }
+    }
+}
+
+/// Checks `message` against the DMARC policy of its From domain, given what
+/// SPF said about the envelope sender and the message's DKIM signatures.
+pub async fn verify(
+    resolver: &dyn Resolver,
+    message: &[u8],
+    spf: Option<&SpfVerdict>,
+    dkim: &[DkimSignature],
+) -> DmarcVerdict {
+    let Some(domain) = from_domain(message) else {
+        return DmarcVerdict::new(DmarcResult::PermError, String::new());
+    };
+    let record = match discover(resolver, &domain).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return DmarcVerdict::new(DmarcResult::None, domain),
+        Err(result) => return DmarcVerdict::new(result, domain),
+    };
+
+    let spf_aligned = spf.is_some_and(|spf| {
+        spf.result == SpfResult::Pass && aligned(&spf.domain, &domain, record.spf_alignment)
+    });
+    let dkim_aligned = dkim.iter().any(|signature| {
+        signature.result == DkimResult::Pass
+            && aligned(&signature.domain, &domain, record.dkim_alignment)
+    });
+
+    let mut policy = if record.domain == domain {
+        record.policy
+    } else {
+        record.subdomain_policy
+    };
+    if rand::thread_rng().gen_range(0..100) >= record.percent {
+        policy = policy.relaxed();
+    }
+    let result = if spf_aligned || dkim_aligned {
+        DmarcResult::Pass
+    } else {
+        DmarcResult::Fail
+    };
+    DmarcVerdict {
+        result,
+        domain,
+        policy: Some(policy),
+        spf_aligned,
+        dkim_aligned,
+        record: Some(record),
+    }
+}
+
+/// The one domain named in the From header (section 6.6.1). Messages
+/// without a From header, with several, or with authors in different
+/// domains give nothing to check.
+fn from_domain(message: &[u8]) -> Option<String> {
+    let message = dkim::crlf_line_endings(message);
+    let (headers, _) = dkim::split_message(&message);
+    let mut from = headers
+        .iter()
+        .filter(|header| header.name.trim().eq_ignore_ascii_case("From"));
+    let header = from.next()?;
+    if from.next().is_some() {
+        return None;
+    }
+
+    let mut domains: Vec<String> = Vec::new();
+    for address in mailparse::addrparse(&header.value()).ok()?.iter() {
+        let mailboxes = match address {
+            MailAddr::Single(mailbox) => vec![mailbox],
+            MailAddr::Group(group) => group.addrs.iter().collect(),
+        };
+        for mailbox in mailboxes {
+            let (_, domain) = mailbox.addr.rsplit_once('@')?;
+            let domain = idna::domain_to_ascii(domain.trim_end_matches('.')).ok()?;
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+    }
+    match domains.as_slice() {
+        [domain] if !domain.is_empty() => Some(domain.clone()),
+        _ => None,
+    }
+}
+
+/// Looks for a record at the From domain, then at its organizational
+/// domain (section 6.6.3).
+async fn discover(
+    resolver: &dyn Resolver,
+    domain: &str,
+) -> Result<Option<DmarcRecord>, DmarcResult> {
+    if let Some(record) = lookup(resolver, domain).await? {
+        return Ok(Some(record));
+    }
+    let organization = organizational_domain(domain);
+    if organization == domain {
+        return Ok(None);
+    }
+    lookup(resolver, organization).await
+}
+
+async fn lookup(resolver: &dyn Resolver, domain: &str) -> Result<Option<DmarcRecord>, DmarcResult> {
+    let records = resolver
+        .txt(&format!("_dmarc.{}", domain))
+        .await
+        .map_err(|_| DmarcResult::TempError)?;
+    let records: Vec<&String> = records
+        .iter()
+        .filter(|record| is_dmarc_record(record))
+        .collect();
+    // Several records are as good as none
+    match records.as_slice() {
+        [record] => parse_record(domain, record).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn is_dmarc_record(record: &str) -> bool {
+    record
+        .split(';')
+        .next()
+        .and_then(|tag| tag.split_once('='))
+        .is_some_and(|(name, value)| name.trim() == "v" && value.trim() == "DMARC1")
+}
+
+fn parse_record(domain: &str, record: &str) -> Result<DmarcRecord, DmarcResult> {
+    let tags = dkim::parse_tags(record).map_err(|_| DmarcResult::PermError)?;
+    let tag = |name: &str| dkim::tag_value(&tags, name);
+    let policy = tag("p")
+        .and_then(DmarcPolicy::parse)
+        .ok_or(DmarcResult::PermError)?;
+    // Unusable optional tags fall back to their defaults (section 6.3)
+    let alignment = |name: &str| match tag(name) {
+        Some(value) if value.eq_ignore_ascii_case("s") => Alignment::Strict,
+        _ => Alignment::Relaxed,
+    };
+    Ok(DmarcRecord {
+        domain: domain.to_string(),
+        policy,
+        subdomain_policy: tag("sp").and_then(DmarcPolicy::parse).unwrap_or(policy),
+        percent: tag("pct")
+            .and_then(|pct| pct.parse().ok())
+            .filter(|pct| *pct <= 100)
+            .unwrap_or(100),
+        dkim_alignment: alignment("adkim"),
+        spf_alignment: alignment("aspf"),
+    })
+}
+
+fn aligned(authenticated: &str, from: &str, alignment: Alignment) -> bool {
+    let authenticated = authenticated.trim_end_matches('.').to_ascii_lowercase();
+    match alignment {
+        Alignment::Strict => authenticated == from,
+        Alignment::Relaxed => organizational_domain(&authenticated) == organizational_domain(from),
+    }
+}
+
+/// The registered part of `domain` (section 3.2): the public suffix it is
+/// under, including private ones such as `github.io`, plus one label. A
+/// public suffix is its own organizational domain.
+pub(crate) fn organizational_domain(domain: &str) -> &str {
+    psl::domain_str(domain).unwrap_or(domain)
+}
@@ -0,0 +1,114 @@
+use async_trait::async_trait;
+use hickory_resolver::config::ResolverConfig;
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::{ResolveError, TokioResolver};
+use log::warn;
+use std::io::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+/// The DNS lookups sender verification needs. A name that doesn't exist or
+/// has no records of the type asked for gives an empty list; only failures to
+/// get an answer at all are errors.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// TXT records, each with its strings joined together.
+    async fn txt(&self, name: &str) -> std::io::Result<Vec<String>>;
+    async fn a(&self, name: &str) -> std::io::Result<Vec<Ipv4Addr>>;
+    async fn aaaa(&self, name: &str) -> std::io::Result<Vec<Ipv6Addr>>;
+    /// Mail exchanger host names, most preferred first.
+    async fn mx(&self, name: &str) -> std::io::Result<Vec<String>>;
+    /// Host names the address maps back to.
+    async fn ptr(&self, ip: IpAddr) -> std::io::Result<Vec<String>>;
+}
+
+pub type SharedResolver = Arc<dyn Resolver>;
+
+/// Resolves through the name servers in the system configuration, or public
+/// ones if there is none.
+pub fn system_resolver() -> SharedResolver {
+    let builder = TokioResolver::builder_tokio().unwrap_or_else(|e| {
+        warn!(
+            "No usable system DNS configuration ({}), using public resolvers",
+            e
+        );
+        TokioResolver::builder_with_config(
+            ResolverConfig::default(),
+            TokioConnectionProvider::default(),
+        )
+    });
+    Arc::new(HickoryResolver(builder.build()))
+}
+
+struct HickoryResolver(TokioResolver);
+
+#[async_trait]
+impl Resolver for HickoryResolver {
+    async fn txt(&self, name: &str) -> std::io::Result<Vec<String>> {
+        let records = match self.0.txt_lookup(fqdn(name)).await {
+            Ok(lookup) => lookup,
+            Err(e) => return no_records(e),
+        };
+        Ok(records
+            .iter()
+            .map(|txt| {
+                let data: Vec<u8> = txt
+                    .txt_data()
+                    .iter()
+                    .flat_map(|s| s.iter().copied())
+                    .collect();
+                String::from_utf8_lossy(&data).into_owned()
+            })
+            .collect())
+    }
+
+    async fn a(&self, name: &str) -> std::io::Result<Vec<Ipv4Addr>> {
+        match self.0.ipv4_lookup(fqdn(name)).await {
+            Ok(lookup) => Ok(lookup.iter().map(|a| a.0).collect()),
+            Err(e) => no_records(e),
+        }
+    }
+
+    async fn aaaa(&self, name: &str) -> std::io::Result<Vec<Ipv6Addr>> {
+        match self.0.ipv6_lookup(fqdn(name)).await {
+            Ok(lookup) => Ok(lookup.iter().map(|aaaa| aaaa.0).collect()),
+            Err(e) => no_records(e),
+        }
+    }
+
+    async fn mx(&self, name: &str) -> std::io::Result<Vec<String>> {
+        let lookup = match self.0.mx_lookup(fqdn(name)).await {
+            Ok(lookup) => lookup,
+            Err(e) => return no_records(e),
+        };
+        let mut exchanges: Vec<_> = lookup.iter().collect();
+        exchanges.sort_by_key(|mx| mx.preference());
+        Ok(exchanges
+            .iter()
+            .map(|mx| mx.exchange().to_ascii().trim_end_matches('.').to_string())
+            .collect())
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> std::io::Result<Vec<String>> {
+        match self.0.reverse_lookup(ip).await {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|ptr| ptr.0.to_ascii().trim_end_matches('.').to_string())
+                .collect()),
+            Err(e) => no_records(e),
+        }
+    }
+}
+
+/// Names are always absolute, so the search domains never get appended.
+fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+fn no_records<T>(e: ResolveError) -> std::io::Result<Vec<T>> {
+    if e.is_no_records_found() {
+        Ok(Vec::new())
+    } else {
+        Err(Error::other(e.to_string()))
+    }
+}
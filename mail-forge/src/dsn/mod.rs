@@ -0,0 +1,256 @@
+pub mod relay;
+
+use crate::config;
+use crate::smtp::params::{BodyType, Notify, Ret};
+use crate::spool::{Envelope, Recipient};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::fmt::Write;
+use std::fs;
+
+/// Builds an RFC 3464 failure report for the recipients of `envelope`, which
+/// are all assumed to have failed permanently.
+///
+/// Returns `None` when nobody should be told: the message had a null sender
+/// (it was a bounce itself), or none of the recipients asked for failure
+/// notifications.
+pub fn build_failure_report(
+    hostname: &str,
+    envelope: &Envelope,
+    message: &[u8],
+    now: DateTime<Utc>,
+) -> Option<Vec<u8>> {
+    if envelope.mail_from.is_empty() {
+        return None;
+    }
+
+    let recipients: Vec<&Recipient> = envelope
+        .recipients
+        .iter()
+        .filter(|recipient| wants_failure_notice(recipient))
+        .collect();
+    if recipients.is_empty() {
+        return None;
+    }
+
+    let boundary = generate_boundary();
+    let date = now.to_rfc2822();
+
+    // RFC 6533 types keep internationalised addresses and headers intact
+    let (status_type, message_type) = if envelope.smtputf8 {
+        ("message/global-delivery-status", "message/global")
+    } else {
+        ("message/delivery-status", "message/rfc822")
+    };
+
+    let mut report = String::new();
+    let _ = write!(
+        report,
+        "From: Mail Delivery System <MAILER-DAEMON@{hostname}>\r\n\
+         To: <{to}>\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{id}.dsn@{hostname}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n\
+         \tboundary=\"{boundary}\"\r\n\
+         \r\n\
+         This is a MIME-encapsulated message.\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\
+         \r\n\
+         This is the mail system at host {hostname}.\r\n\
+         \r\n\
+         Your message could not be delivered to one or more recipients.\r\n\
+         \r\n",
+        to = envelope.mail_from,
+        id = envelope.id,
+    );
+    for recipient in &recipients {
+        let _ = write!(
+            report,
+            "<{}>: {}\r\n",
+            recipient.address,
+            recipient.last_error.as_deref().unwrap_or("delivery failed")
+        );
+    }
+
+    let _ = write!(
+        report,
+        "\r\n--{boundary}\r\n\
+         Content-Type: {status_type}\r\n\
+         \r\n\
+         Reporting-MTA: dns; {hostname}\r\n"
+    );
+    if let Some(envid) = &envelope.envid {
+        let _ = write!(report, "Original-Envelope-Id: {}\r\n", envid);
+    }
+    let _ = write!(
+        report,
+        "Arrival-Date: {}\r\n",
+        envelope.received_at.to_rfc2822()
+    );
+
+    for recipient in &recipients {
+        let _ = write!(
+            report,
+            "\r\nFinal-Recipient: rfc822; {}\r\n",
+            recipient.address
+        );
+        if let Some(orcpt) = &recipient.orcpt {
+            let _ = write!(
+                report,
+                "Original-Recipient: {}\r\n",
+                orcpt.replacen(';', "; ", 1)
+            );
+        }
+        let _ = write!(
+            report,
+            "Action: failed\r\n\
+             Status: {}\r\n\
+             Diagnostic-Code: X-Webhook; {}\r\n\
+             Last-Attempt-Date: {}\r\n",
+            recipient.status.as_deref().unwrap_or("5.0.0"),
+            recipient.last_error.as_deref().unwrap_or("delivery failed"),
+            date
+        );
+    }
+
+    let mut report = report.into_bytes();
+    // Binary content can't travel in a report sent with DATA, so only its
+    // headers go back, whatever RET says
+    let binary = envelope.body == Some(BodyType::BinaryMime);
+    if envelope.ret == Some(Ret::Full) && !binary {
+        write_part_header(&mut report, &boundary, message_type);
+        report.extend_from_slice(message);
+        if !message.ends_with(b"\r\n") {
+            report.extend_from_slice(b"\r\n");
+        }
+    } else {
+        // Without RET=FULL only the headers go back, which keeps bounces small
+        let header_type = if envelope.smtputf8 {
+            "message/global-headers"
+        } else {
+            "text/rfc822-headers"
+        };
+        write_part_header(&mut report, &boundary, header_type);
+        report.extend_from_slice(message_headers(message));
+    }
+    report.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    Some(report)
+}
+
+/// Builds and sends a failure report, if one is due, through the smarthost
+/// or into the outbox directory.
+pub async fn notify_failure(config: &config::Config, envelope: &Envelope, message: &[u8]) {
+    let now = Utc::now();
+    let Some(report) = build_failure_report(&config.server.hostname, envelope, message, now) else {
+        return;
+    };
+
+    if let Some(smarthost) = &config.dsn.smarthost {
+        let sent = relay::send(
+            smarthost,
+            &config.server,
+            &envelope.mail_from,
+            &report,
+            envelope.smtputf8,
+        )
+        .await;
+        match sent {
+            Ok(()) => {
+                info!(
+                    "Sent delivery status notification for message {} to {} via {}",
+                    envelope.id, envelope.mail_from, smarthost
+                );
+                return;
+            }
+            Err(e) => error!(
+                "Failed to send delivery status notification for message {} via {}: {}",
+                envelope.id, smarthost, e
+            ),
+        }
+    }
+
+    if let Some(outbox_dir) = &config.dsn.outbox_dir {
+        // Recipients of one message can fail at different times, so each
+        // report gets a name of its own
+        let name = format!(
+            "{}-{}-{}",
+            envelope.id,
+            now.format("%Y%m%d%H%M%S%3f"),
+            random_suffix(8)
+        );
+        let path = outbox_dir.join(format!("{}.eml", name));
+        let temp_path = outbox_dir.join(format!("{}.eml.tmp", name));
+        // Write under a temporary name so whatever picks up the outbox never
+        // sees half a message
+        let written = fs::create_dir_all(outbox_dir)
+            .and_then(|_| fs::write(&temp_path, &report))
+            .and_then(|_| fs::rename(&temp_path, &path));
+        match written {
+            Ok(()) => info!(
+                "Wrote delivery status notification for message {} to {}",
+                envelope.id,
+                path.display()
+            ),
+            Err(e) => error!(
+                "Failed to write delivery status notification to {}: {}",
+                path.display(),
+                e
+            ),
+        }
+        return;
+    }
+
+    if config.dsn.smarthost.is_none() {
+        info!(
+            "No DSN smarthost or outbox configured; {} is not notified about message {}",
+            envelope.mail_from, envelope.id
+        );
+    }
+}
+
+/// Without a NOTIFY parameter, failures are reported (RFC 3461 section 4.1).
+fn wants_failure_notice(recipient: &Recipient) -> bool {
+    recipient
+        .notify
+        .as_ref()
+        .is_none_or(|notify| notify.contains(&Notify::Failure))
+}
+
+fn write_part_header(report: &mut Vec<u8>, boundary: &str, content_type: &str) {
+    report.extend_from_slice(
+        format!(
+            "\r\n--{}\r\nContent-Type: {}\r\n\r\n",
+            boundary, content_type
+        )
+        .as_bytes(),
+    );
+}
+
+/// The header section of a message, including the blank line that ends it.
+fn message_headers(message: &[u8]) -> &[u8] {
+    match mailparse::parse_headers(message) {
+        Ok((_, body_start)) => &message[..body_start],
+        Err(_) => message,
+    }
+}
+
+fn generate_boundary() -> String {
+    format!("=_dsn_{}", random_suffix(24))
+}
+
+fn random_suffix(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
@@ -0,0 +1,129 @@
+use crate::config::ServerConfig;
+use crate::smtp::timeout::within;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+type RelayError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Hands a bounce to `smarthost` over plain SMTP with a null reverse path,
+/// so it can never bounce back to us. Each step is bounded by the session
+/// timeouts in `server`, so a smarthost that stops answering can't hold up
+/// the caller for good.
+///
+/// Bounces of internationalized mail (`smtputf8`) go out with `SMTPUTF8`,
+/// and 8-bit content with `BODY=8BITMIME`; a smarthost that supports
+/// neither can't take them.
+pub async fn send(
+    smarthost: &str,
+    server: &ServerConfig,
+    recipient: &str,
+    message: &[u8],
+    smtputf8: bool,
+) -> Result<(), RelayError> {
+    let limit = Duration::from_secs(server.command_timeout_secs);
+    let stream = within(limit, TcpStream::connect(smarthost)).await?;
+    let mut stream = BufReader::new(stream);
+
+    within(limit, expect_reply(&mut stream, 220)).await?;
+    let ehlo = format!("EHLO {}\r\n", server.hostname);
+    let extensions = within(limit, command(&mut stream, &ehlo, 250)).await?;
+
+    let mut mail = String::from("MAIL FROM:<>");
+    if !message.is_ascii() {
+        require_extension(&extensions, "8BITMIME")?;
+        mail.push_str(" BODY=8BITMIME");
+    }
+    if smtputf8 || !recipient.is_ascii() {
+        require_extension(&extensions, "SMTPUTF8")?;
+        mail.push_str(" SMTPUTF8");
+    }
+    mail.push_str("\r\n");
+    within(limit, command(&mut stream, &mail, 250)).await?;
+    let rcpt = format!("RCPT TO:<{}>\r\n", recipient);
+    within(limit, command(&mut stream, &rcpt, 250)).await?;
+    within(limit, command(&mut stream, "DATA\r\n", 354)).await?;
+
+    let transfer = async {
+        stream.get_mut().write_all(&dot_stuff(message)).await?;
+        stream.get_mut().write_all(b".\r\n").await?;
+        expect_reply(&mut stream, 250).await.map(drop)
+    };
+    let data_limit = Duration::from_secs(server.data_termination_timeout_secs);
+    within(data_limit, transfer).await?;
+
+    // The bounce is accepted; a failed QUIT doesn't change that
+    let _ = within(limit, command(&mut stream, "QUIT\r\n", 221)).await;
+    Ok(())
+}
+
+async fn command(
+    stream: &mut BufReader<TcpStream>,
+    line: &str,
+    expected: u16,
+) -> Result<Vec<String>, RelayError> {
+    stream.get_mut().write_all(line.as_bytes()).await?;
+    expect_reply(stream, expected).await
+}
+
+/// Reads a possibly multi-line reply, checks its code and returns the text
+/// of each line.
+async fn expect_reply(
+    stream: &mut BufReader<TcpStream>,
+    expected: u16,
+) -> Result<Vec<String>, RelayError> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err("Smarthost closed the connection".into());
+        }
+        lines.push(line.get(4..).unwrap_or("").trim_end().to_string());
+        // "250-" continues the reply, "250 " ends it
+        if line.as_bytes().get(3) != Some(&b'-') {
+            break;
+        }
+    }
+
+    let code: u16 = line
+        .get(..3)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("Malformed reply from smarthost: {}", line.trim_end()))?;
+    if code != expected {
+        return Err(format!("Smarthost replied: {}", line.trim_end()).into());
+    }
+    Ok(lines)
+}
+
+/// Checks the EHLO reply for `name`; the first line is the greeting.
+fn require_extension(extensions: &[String], name: &str) -> Result<(), RelayError> {
+    let advertised = extensions.iter().skip(1).any(|extension| {
+        extension
+            .split_whitespace()
+            .next()
+            .is_some_and(|keyword| keyword.eq_ignore_ascii_case(name))
+    });
+    if !advertised {
+        return Err(format!("Smarthost does not support {}", name).into());
+    }
+    Ok(())
+}
+
+/// Applies RFC 5321 section 4.5.2 transparency and makes sure the message
+/// ends with CRLF before the terminating dot.
+fn dot_stuff(message: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(message.len() + 2);
+    let mut at_line_start = true;
+    for &byte in message {
+        if at_line_start && byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(byte);
+        at_line_start = byte == b'\n';
+    }
+    if !stuffed.ends_with(b"\r\n") {
+        stuffed.extend_from_slice(b"\r\n");
+    }
+    stuffed
+}
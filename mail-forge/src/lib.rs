@@ -1,3 +1,15 @@
-pub mod webhook;
-pub mod config;
-pub mod smtp;
\ No newline at end of file
+pub mod acme;
+pub mod address;
+pub mod arc;
+pub mod authres;
+pub mod webhook;
+pub mod config;
+pub mod dkim;
+pub mod dmarc;
+pub mod dns;
+pub mod dsn;
+pub mod replay;
+pub mod smtp;
+pub mod spf;
+pub mod spool;
+pub mod tls;
\ No newline at end of file
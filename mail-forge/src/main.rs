@@ -1,5 +1,26 @@
 use std::env;
-use mail_forge::{config, smtp};
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use mail_forge::{config, replay, smtp};
+
+#[derive(Parser)]
+#[command(version, about = "SMTP server that forwards inbound mail to webhooks")]
+struct Cli {
+    /// Path to the configuration file
+    #[arg(long, global = true, default_value = "config.toml")]
+    config: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the SMTP server (the default)
+    Serve,
+    /// Push stored messages back through the webhook pipeline
+    Replay(replay::ReplayArgs),
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -9,9 +30,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     env_logger::init();
 
-    let config = config::Config::load("config.toml")?;
+    let cli = Cli::parse();
+    let config = config::Config::load(&cli.config)?;
 
-    smtp::server::start(config).await?;
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => smtp::server::start(config).await?,
+        Command::Replay(args) => replay::run(&config, args).await?,
+    }
 
     Ok(())
 }
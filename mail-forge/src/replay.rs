@@ -0,0 +1,170 @@
+use crate::config;
+use crate::spool::{Envelope, Spool};
+use crate::webhook::client::{forward_to_webhook, preview_payload};
+use crate::webhook::mapping::get_webhook_for_envelope;
+use clap::Args;
+use std::fs;
+use std::path::PathBuf;
+
+/// Options for `mail-forge replay`.
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// Message files (.eml) to push through the webhook pipeline
+    #[arg(required_unless_present = "dead_letter")]
+    pub files: Vec<PathBuf>,
+
+    /// ID of a dead-lettered message to redeliver instead of files
+    #[arg(long, conflicts_with = "files")]
+    pub dead_letter: Option<String>,
+
+    /// Envelope recipient to deliver to; may be repeated. Defaults to the
+    /// failed recipients of a dead-lettered message.
+    #[arg(
+        long = "rcpt",
+        value_name = "ADDRESS",
+        required_unless_present = "dead_letter"
+    )]
+    pub recipients: Vec<String>,
+
+    /// Print the payload that would be sent instead of sending it
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+struct ReplayMessage {
+    source: String,
+    raw_email: Vec<u8>,
+    recipients: Vec<String>,
+    /// Session details for the payload; files come without any.
+    envelope: Envelope,
+    from_dead_letter: bool,
+}
+
+/// Redelivers stored messages, failing if any recipient could not be delivered.
+pub async fn run(
+    config: &config::Config,
+    args: ReplayArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut messages = Vec::new();
+    let mut dead_letter = None;
+
+    if let Some(id) = &args.dead_letter {
+        let (spool, _receiver) = Spool::open(&config.spool.dir)?;
+        let (envelope, raw_email) = spool
+            .load_dead_letter(id)
+            .map_err(|e| format!("Failed to load dead-lettered message {}: {}", id, e))?;
+
+        let recipients = if args.recipients.is_empty() {
+            envelope
+                .recipients
+                .iter()
+                .map(|recipient| recipient.address.clone())
+                .collect()
+        } else {
+            args.recipients.clone()
+        };
+
+        messages.push(ReplayMessage {
+            source: format!("dead letter {}", id),
+            raw_email,
+            recipients,
+            envelope,
+            from_dead_letter: true,
+        });
+        dead_letter = Some(spool);
+    }
+
+    for path in &args.files {
+        let raw_email = fs::read(path)
+            .map_err(|e| format!("Failed to read message {}: {}", path.display(), e))?;
+        messages.push(ReplayMessage {
+            source: path.display().to_string(),
+            raw_email,
+            recipients: args.recipients.clone(),
+            envelope: Envelope::new(String::new(), args.recipients.clone()),
+            from_dead_letter: false,
+        });
+    }
+
+    let mut failures = 0;
+    // What became of the dead-lettered recipients this time round
+    let mut delivered = Vec::new();
+    let mut failed_again = Vec::new();
+    for message in &messages {
+        let mut fail = |recipient: &str, error: String, status: &'static str| {
+            failures += 1;
+            if message.from_dead_letter {
+                failed_again.push((recipient.to_string(), error, status));
+            }
+        };
+        for recipient in &message.recipients {
+            let Some(webhook) = get_webhook_for_envelope(recipient, &message.envelope, config)
+            else {
+                eprintln!("{}: no webhook mapping for {}", message.source, recipient);
+                let error = "No webhook mapping found for recipient".to_string();
+                fail(recipient, error, "5.1.1");
+                continue;
+            };
+
+            if args.dry_run {
+                let payload = preview_payload(recipient, &message.raw_email, &message.envelope)?;
+                println!("{} -> {} ({})", message.source, webhook.url, recipient);
+                println!("{}", serde_json::to_string_pretty(&payload)?);
+                continue;
+            }
+
+            match forward_to_webhook(recipient, webhook, &message.raw_email, &message.envelope)
+                .await
+            {
+                Ok(_) => {
+                    println!(
+                        "{}: delivered to {} for {}",
+                        message.source, webhook.url, recipient
+                    );
+                    if message.from_dead_letter {
+                        delivered.push(recipient.clone());
+                    }
+                }
+                Err(e) => {
+                    eprintln!(
+                        "{}: failed to deliver for {}: {}",
+                        message.source, recipient, e
+                    );
+                    fail(recipient, e.to_string(), "5.0.0");
+                }
+            }
+        }
+    }
+
+    // Delivered recipients leave the dead letter, which has served its
+    // purpose once there are none left; the others stay, whether or not
+    // they were replayed
+    if let (Some(spool), Some(id)) = (dead_letter, &args.dead_letter) {
+        if !args.dry_run {
+            let mut envelope = messages[0].envelope.clone();
+            envelope
+                .recipients
+                .retain(|recipient| !delivered.contains(&recipient.address));
+            for recipient in &mut envelope.recipients {
+                let failure = failed_again
+                    .iter()
+                    .find(|(address, _, _)| *address == recipient.address);
+                if let Some((_, error, status)) = failure {
+                    recipient.last_error = Some(error.clone());
+                    recipient.status = Some(status.to_string());
+                }
+            }
+            if envelope.recipients.is_empty() {
+                spool.remove_dead_letter(id)?;
+            } else {
+                spool.update_dead_letter(&envelope)?;
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(format!("{} delivery(ies) failed", failures).into());
+    }
+    Ok(())
+}
+
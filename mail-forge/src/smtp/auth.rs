@@ -0,0 +1,89 @@
+use crate::config::CredentialConfig;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fmt;
+
+/// Why an AUTH exchange failed before the credentials could be checked.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// The client answered a challenge with `*` (RFC 4954 section 4).
+    Cancelled,
+    /// A response was not valid base64, or not in the mechanism's format.
+    Malformed,
+}
+
+impl AuthError {
+    pub fn reply(&self) -> &'static [u8] {
+        match self {
+            AuthError::Cancelled => b"501 5.7.0 Authentication cancelled\r\n",
+            AuthError::Malformed => b"501 5.5.2 Cannot decode response\r\n",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Cancelled => write!(f, "Authentication cancelled"),
+            AuthError::Malformed => write!(f, "Cannot decode response"),
+        }
+    }
+}
+
+/// Decodes one base64 line of an AUTH exchange.
+pub fn decode_response(response: &str) -> Result<String, AuthError> {
+    let response = response.trim();
+    match response {
+        "*" => return Err(AuthError::Cancelled),
+        // An empty initial response (RFC 4954 section 4)
+        "=" => return Ok(String::new()),
+        _ => {}
+    }
+    let decoded = STANDARD
+        .decode(response)
+        .map_err(|_| AuthError::Malformed)?;
+    String::from_utf8(decoded).map_err(|_| AuthError::Malformed)
+}
+
+/// Splits a SASL PLAIN message (RFC 4616) into username and password. An
+/// authorization identity is only accepted if it names the same user.
+pub fn parse_plain(message: &str) -> Result<(String, String), AuthError> {
+    let mut parts = message.split('\0');
+    let (Some(authzid), Some(authcid), Some(password), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(AuthError::Malformed);
+    };
+    if authcid.is_empty() || (!authzid.is_empty() && authzid != authcid) {
+        return Err(AuthError::Malformed);
+    }
+    Ok((authcid.to_string(), password.to_string()))
+}
+
+/// Finds the credential `username` logs in with, if `password` is right.
+pub fn verify<'a>(
+    credentials: &'a [CredentialConfig],
+    username: &str,
+    password: &str,
+) -> Option<&'a CredentialConfig> {
+    let credential = credentials
+        .iter()
+        .find(|credential| credential.username == username)?;
+    verify_password(&credential.password_hash, password).then_some(credential)
+}
+
+/// Checks `password` against an Argon2 PHC string or a bcrypt hash.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    } else if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        false
+    }
+}
@@ -0,0 +1,108 @@
+use crate::smtp::timeout::within;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// Result of reading a DATA block up to the terminating `<CRLF>.<CRLF>`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DataOutcome {
+    /// The message as the client meant it, with dot-stuffing removed.
+    Complete(Vec<u8>),
+    /// The message exceeded the size limit. The rest of it was read and discarded.
+    TooLarge,
+}
+
+/// Reads a DATA block byte for byte, undoing the RFC 5321 section 4.5.2
+/// transparency procedure and enforcing `max_size` on the unstuffed message.
+///
+/// The content is never decoded, so 8-bit bodies in any charset pass through
+/// unchanged. Each line has to arrive within `block_timeout`.
+pub async fn read_data<R>(
+    reader: &mut R,
+    max_size: usize,
+    block_timeout: Duration,
+) -> std::io::Result<DataOutcome>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut message = Vec::new();
+    let mut line = Vec::new();
+    let mut too_large = false;
+    let mut at_line_start = true;
+
+    // Bound each read so a client can't make us buffer an endless line
+    let read_limit = max_size as u64 + 3;
+
+    loop {
+        line.clear();
+        let mut limited = (&mut *reader).take(read_limit);
+        if within(block_timeout, limited.read_until(b'\n', &mut line)).await? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Client disconnected during DATA phase",
+            ));
+        }
+
+        let line_start = at_line_start;
+        at_line_start = line.ends_with(b"\n");
+
+        if line_start && line == b".\r\n" {
+            break;
+        }
+
+        if too_large {
+            // Keep consuming until the terminator so the session stays in sync
+            continue;
+        }
+
+        // A leading dot was doubled by the client; drop the one it added
+        let content = match line.strip_prefix(b".") {
+            Some(unstuffed) if line_start => unstuffed,
+            _ => &line,
+        };
+
+        if message.len() + content.len() > max_size {
+            too_large = true;
+            message = Vec::new();
+            continue;
+        }
+
+        message.extend_from_slice(content);
+    }
+
+    if too_large {
+        Ok(DataOutcome::TooLarge)
+    } else {
+        Ok(DataOutcome::Complete(message))
+    }
+}
+
+/// Reads a BDAT chunk of exactly `size` bytes, appending it to `message` or
+/// discarding it if there is none. Each read has to finish within `block_timeout`.
+pub async fn read_chunk<R>(
+    reader: &mut R,
+    size: u64,
+    mut message: Option<&mut Vec<u8>>,
+    block_timeout: Duration,
+) -> std::io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut remaining = size;
+    while remaining > 0 {
+        let available = within(block_timeout, reader.fill_buf()).await?;
+        if available.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Client disconnected during BDAT chunk",
+            ));
+        }
+
+        let taken = remaining.min(available.len() as u64) as usize;
+        if let Some(message) = message.as_mut() {
+            message.extend_from_slice(&available[..taken]);
+        }
+        reader.consume(taken);
+        remaining -= taken as u64;
+    }
+    Ok(())
+}
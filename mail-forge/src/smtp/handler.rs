@@ -1,154 +1,386 @@
-use crate::config;
+use crate::config::SpfFailAction;
+use crate::dkim;
+use crate::dmarc::{self, DmarcPolicy, DmarcResult};
+use crate::dns::{Resolver, SharedResolver};
+use crate::smtp::auth::{self, AuthError};
+use crate::smtp::data::{read_chunk, read_data, DataOutcome};
+use crate::smtp::limits::{Limiter, SessionRefused};
+use crate::smtp::params::{parse_mail_from, parse_rcpt_to, BodyType, MailFrom, RcptTo};
+use crate::smtp::proxy;
 use crate::smtp::stream::StreamType;
+use crate::smtp::timeout::{is_timeout, within};
+use crate::spf::{self, SpfResult, SpfVerdict};
+use crate::spool::{Envelope, Spool};
 use crate::webhook::client::forward_to_webhook;
-use crate::webhook::mapping::get_webhook_for_recipient;
-use chrono::Utc;
-use log::{error, info};
-use rustls::ServerConfig;
-use std::fs::File;
-use std::io::Write;
+use crate::webhook::mapping::{get_webhook_for_envelope, get_webhook_for_recipient};
+use crate::{address, arc, authres, config, tls};
+use log::{error, info, warn};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio_rustls::TlsAcceptor;
 
+/// The protocol spoken on a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Protocol {
+    /// ESMTP; accepted messages are spooled and delivered in the background.
+    #[default]
+    Smtp,
+    /// LMTP (RFC 2033); messages are delivered before answering, with one
+    /// reply per recipient, and the client is responsible for retries.
+    Lmtp,
+}
+
 #[derive(Default)]
 struct SessionState {
+    protocol: Protocol,
+    /// Whether mail transactions need an encrypted connection.
+    require_tls: bool,
+    /// Whether mail transactions need SMTP AUTH, which is offered under TLS.
+    require_auth: bool,
+    /// The username the client authenticated as.
+    auth_user: Option<String>,
     helo: Option<String>,
-    mail_from: Option<String>,
-    rcpt_to: Vec<String>,
+    mail_from: Option<MailFrom>,
+    rcpt_to: Vec<RcptTo>,
+    /// Message assembled from BDAT chunks so far, once the first chunk arrived.
+    chunks: Option<Vec<u8>>,
+    /// Messages received in this session, whatever became of them.
+    messages: usize,
+    /// SPF result for the current sender, if it was checked.
+    spf: Option<SpfVerdict>,
+}
+
+/// What all sessions share, whichever listener they came in on.
+pub struct Services {
+    pub config: Arc<config::Config>,
+    pub tls_config: tls::SharedServerConfig,
+    pub spool: Arc<Spool>,
+    pub limiter: Arc<Limiter>,
+    pub resolver: SharedResolver,
 }
 
 impl SessionState {
-    fn new() -> Self {
+    fn new(protocol: Protocol) -> Self {
         Self {
+            protocol,
+            require_tls: false,
+            require_auth: false,
+            auth_user: None,
             helo: None,
             mail_from: None,
             rcpt_to: Vec::new(),
+            chunks: None,
+            messages: 0,
+            spf: None,
+        }
+    }
+
+    /// How many replies the end of a message gets: one per accepted
+    /// recipient under LMTP, a single one under SMTP.
+    fn message_reply_count(&self) -> usize {
+        match self.protocol {
+            Protocol::Smtp => 1,
+            Protocol::Lmtp => self.rcpt_to.len(),
         }
     }
 
     fn is_ready_for_data(&self) -> bool {
         self.mail_from.is_some() && !self.rcpt_to.is_empty()
     }
+
+    /// Forgets everything the client said, as if it had just connected.
+    fn reset_session(&mut self) {
+        self.helo = None;
+        self.auth_user = None;
+        self.reset_transaction();
+    }
+
+    /// Forgets the current mail transaction but keeps the greeting.
+    fn reset_transaction(&mut self) {
+        self.mail_from = None;
+        self.rcpt_to.clear();
+        self.chunks = None;
+        self.spf = None;
+    }
 }
 
 pub async fn handle_client(
     mut socket: TcpStream,
-    tls_config: Arc<ServerConfig>,
-    addr: std::net::SocketAddr,
-    config: Arc<config::Config>,
+    mut addr: std::net::SocketAddr,
+    listener: Arc<config::ListenerConfig>,
+    services: Arc<Services>,
 ) {
+    let config = &services.config;
+    let (protocol, protocol_name) = match listener.mode {
+        config::ListenerMode::Smtp | config::ListenerMode::Smtps => (Protocol::Smtp, "SMTP"),
+        config::ListenerMode::Lmtp => (Protocol::Lmtp, "LMTP"),
+    };
+    let greeting_timeout = Duration::from_secs(config.server.greeting_timeout_secs);
+    let greeting_deadline = Instant::now() + greeting_timeout;
+
+    // Connections still waiting for their PROXY header count as sessions too
+    let mut permit = match services.limiter.open_connection() {
+        Ok(permit) => permit,
+        Err(refused) => {
+            refuse_session(&mut socket, addr, &listener, &config.server.hostname, refused).await;
+            return;
+        }
+    };
+
+    // Behind a load balancer the real client is announced before anything else
+    if listener.proxy_protocol {
+        match within(greeting_timeout, proxy::read_proxy_header(&mut socket)).await {
+            Ok(Some(client)) => {
+                info!("Connection from {} is proxied for {}", addr, client);
+                addr = client;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Invalid PROXY header from {}: {}", addr, e);
+                return;
+            }
+        }
+    }
+
+    // Only now do we know which client the session counts towards
+    if let Err(refused) = permit.assign(addr.ip()) {
+        refuse_session(&mut socket, addr, &listener, &config.server.hostname, refused).await;
+        return;
+    }
+
     info!("Accepted connection from {}", addr);
 
-    // Send the initial SMTP greeting
-    if let Err(e) = socket
-        .write_all(
-            format!(
-                "220 {} Mail Forge SMTP Server Ready\r\n",
-                config.server.hostname
-            )
-            .as_bytes(),
-        )
-        .await
-    {
+    // With implicit TLS the handshake comes before anything else is said
+    let mut stream = if listener.mode == config::ListenerMode::Smtps {
+        let remaining = greeting_deadline.saturating_duration_since(Instant::now());
+        let acceptor = TlsAcceptor::from(services.tls_config.load_full());
+        match within(remaining, acceptor.accept(socket)).await {
+            Ok(tls_stream) => StreamType::tls(tls_stream),
+            Err(e) => {
+                error!("TLS handshake with {} failed: {}", addr, e);
+                return;
+            }
+        }
+    } else {
+        StreamType::plain(socket)
+    };
+
+    // Send the initial greeting
+    let banner = listener
+        .banner
+        .clone()
+        .unwrap_or_else(|| format!("Mail Forge {} Server Ready", protocol_name));
+    let greeting = format!("220 {} {}\r\n", config.server.hostname, banner);
+    if let Err(e) = stream.write_all(greeting.as_bytes()).await {
         error!("Failed to send greeting to {}: {}", addr, e);
         return;
     }
 
     // Initialize the session state
-    let mut session_state = SessionState::new();
+    let mut session_state = SessionState::new(protocol);
+    session_state.require_tls = listener.require_tls;
+    session_state.require_auth = listener.auth;
 
     // Process commands using process_commands
-    let stream = StreamType::Plain(BufReader::new(socket));
-    if let Err(e) = process_commands(stream, &mut session_state, config, tls_config).await {
+    if let Err(e) = process_commands(stream, &mut session_state, &services, addr).await {
         error!("Error processing commands for {}: {}", addr, e);
     }
 
     info!("Connection with {} has been closed.", addr);
 }
 
+/// Tells the client why its session was refused, where the protocol allows.
+async fn refuse_session(
+    socket: &mut TcpStream,
+    addr: std::net::SocketAddr,
+    listener: &config::ListenerConfig,
+    hostname: &str,
+    refused: SessionRefused,
+) {
+    warn!("Refusing connection from {}: {:?}", addr, refused);
+    // Under implicit TLS there is no way to tell the client why
+    if listener.mode != config::ListenerMode::Smtps {
+        let _ = socket.write_all(refused.reply(hostname).as_bytes()).await;
+    }
+}
+
 async fn process_commands<S>(
     mut stream: StreamType<S>,
     state: &mut SessionState,
-    config: Arc<config::Config>,
-    tls_config: Arc<ServerConfig>,
+    services: &Services,
+    addr: std::net::SocketAddr,
 ) -> Result<(), Box<dyn std::error::Error>>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
+    let config = &services.config;
+    let session_end = Instant::now() + Duration::from_secs(config.server.max_session_secs);
+    let command_timeout = Duration::from_secs(config.server.command_timeout_secs);
     let mut line = String::new();
 
     loop {
-        while stream.read_line(&mut line).await? != 0 {
-            let request = line.trim().to_string();
-            line.clear(); // Clear the buffer
+        // Every command, including whatever it reads, has to finish before the session ends
+        let remaining = session_end.saturating_duration_since(Instant::now());
+        let read = within(command_timeout.min(remaining), stream.read_line(&mut line)).await;
+        let result = match read {
+            // The client went away without saying QUIT
+            Ok(0) => return Ok(()),
+            Ok(_) => {
+                let request = line.trim().to_string();
+                line.clear(); // Clear the buffer
 
-            let mut parts = request.splitn(2, ' ');
-            let command = parts.next().unwrap_or("").to_uppercase();
-            let arguments = parts.next().unwrap_or("");
-
-            if command.is_empty() {
-                stream
-                    .write_all(b"500 Syntax error, command unrecognized\r\n")
-                    .await?;
-                continue;
+                let command = request.split(' ').next().unwrap_or("").to_uppercase();
+                match command.as_str() {
+                    "QUIT" => {
+                        handle_quit(&mut stream).await?;
+                        return Ok(());
+                    }
+                    "STARTTLS" if !stream.is_tls() => {
+                        let tls_config = services.tls_config.clone();
+                        stream =
+                            within(remaining, handle_starttls(stream, tls_config, addr)).await?;
+                        // Nothing learned before the handshake can be trusted
+                        // (RFC 3207 section 4.2), so start over
+                        state.reset_session();
+                        continue;
+                    }
+                    _ => {
+                        let handled = handle_command(&mut stream, state, services, addr, &request);
+                        within(remaining, handled).await
+                    }
+                }
             }
+            Err(e) => Err(e.into()),
+        };
 
-            match command.as_str() {
-                "HELO" => handle_helo(&mut stream, state, config.clone(), arguments).await?,
-                "EHLO" => handle_ehlo(&mut stream, state, config.clone(), arguments).await?,
+        match result {
+            Ok(()) => {}
+            Err(e) if is_timeout(&*e) => break,
+            Err(e) => return Err(e),
+        }
+    }
 
-                "RSET" => handle_rset(&mut stream, state).await?,
-                "NOOP" => handle_noop(&mut stream).await?,
-                "DATA" => handle_data(&mut stream, state, config.clone()).await?,
-                "MAIL" if arguments.to_uppercase().starts_with("FROM:") => {
-                    handle_mail_from(&mut stream, state, arguments).await?
-                }
-                "RCPT" if arguments.to_uppercase().starts_with("TO:") => {
-                    handle_rcpt_to(&mut stream, state, config.clone(), arguments).await?
-                }
-                "QUIT" => {
-                    handle_quit(&mut stream).await?;
-                    break;
-                }
-                "STARTTLS" => {
-                    stream = handle_starttls(stream, tls_config.clone()).await?;
-                    continue;
-                }
-                _ => {
-                    stream
-                        .write_all(b"500 Syntax error, command Unrecognized\r\n")
-                        .await?
-                }
-            }
+    info!("Closing connection with {} after a timeout", addr);
+    let reply = format!(
+        "421 4.4.2 {} Timeout exceeded, closing connection\r\n",
+        config.server.hostname
+    );
+    stream.write_all(reply.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Runs a single command that keeps the session going.
+async fn handle_command<S>(
+    stream: &mut StreamType<S>,
+    state: &mut SessionState,
+    services: &Services,
+    addr: std::net::SocketAddr,
+    request: &str,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Services {
+        config,
+        spool,
+        limiter,
+        resolver,
+        ..
+    } = services;
+    let mut parts = request.splitn(2, ' ');
+    let command = parts.next().unwrap_or("").to_uppercase();
+    let arguments = parts.next().unwrap_or("");
+
+    if command.is_empty() {
+        stream
+            .write_all(b"500 Syntax error, command unrecognized\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    match (command.as_str(), state.protocol) {
+        ("HELO", Protocol::Smtp) => handle_helo(stream, state, config.clone(), arguments).await,
+        ("EHLO", Protocol::Smtp) | ("LHLO", Protocol::Lmtp) => {
+            handle_ehlo(stream, state, config.clone(), arguments).await
+        }
+        ("HELO" | "EHLO", Protocol::Lmtp) => {
+            stream
+                .write_all(b"500 5.5.1 This is an LMTP server, use LHLO\r\n")
+                .await?;
+            Ok(())
+        }
+        ("AUTH", _) => handle_auth(stream, state, config.clone(), arguments).await,
+        ("RSET", _) => handle_rset(stream, state).await,
+        ("NOOP", _) => handle_noop(stream).await,
+        ("DATA", _) => handle_data(stream, state, config.clone(), spool, &**resolver, addr).await,
+        ("BDAT", _) => {
+            let resolver = &**resolver;
+            handle_bdat(stream, state, config.clone(), spool, resolver, addr, arguments).await
+        }
+        ("MAIL", _) => {
+            let resolver = &**resolver;
+            handle_mail_from(
+                stream,
+                state,
+                config.clone(),
+                limiter,
+                resolver,
+                addr,
+                arguments,
+            )
+            .await
+        }
+        ("RCPT", _) => {
+            handle_rcpt_to(stream, state, config.clone(), limiter, addr, arguments).await
+        }
+        ("STARTTLS", _) => {
+            stream
+                .write_all(b"503 5.5.1 TLS already active\r\n")
+                .await?;
+            Ok(())
+        }
+        _ => {
+            stream
+                .write_all(b"500 Syntax error, command Unrecognized\r\n")
+                .await?;
+            Ok(())
         }
     }
 }
 
 async fn handle_starttls<S>(
     mut stream: StreamType<S>,
-    tls_config: Arc<ServerConfig>,
+    tls_config: tls::SharedServerConfig,
+    addr: std::net::SocketAddr,
 ) -> Result<StreamType<S>, Box<dyn std::error::Error>>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    // Ensure TLS isn't already active
-    if matches!(stream, StreamType::Tls(_)) {
-        stream.write_all(b"503 TLS already active\r\n").await?;
-        return Err("TLS already active".into());
-    }
-
     stream.write_all(b"220 Ready to start TLS\r\n").await?;
+    stream.flush().await?;
 
     let inner_stream = match stream {
-        StreamType::Plain(inner) => inner.into_inner(),
+        StreamType::Plain(inner) => {
+            // Anything pipelined after STARTTLS was sent in plaintext and
+            // must not be executed inside the TLS session
+            if !inner.buffer().is_empty() {
+                warn!(
+                    "Discarding {} bytes sent by {} after STARTTLS",
+                    inner.buffer().len(),
+                    addr
+                );
+            }
+            inner.into_inner().into_inner()
+        }
         _ => return Err("Stream must be in plain variant".into()),
     };
 
-    let tls_stream = TlsAcceptor::from(tls_config).accept(inner_stream).await?;
+    let tls_stream = TlsAcceptor::from(tls_config.load_full()).accept(inner_stream).await?;
 
-    return Ok(StreamType::Tls(BufReader::new(tls_stream)));
+    Ok(StreamType::tls(tls_stream))
 }
 
 async fn handle_helo<S>(
@@ -181,13 +413,27 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     state.helo = Some(arguments.to_string());
+    // STARTTLS is only on offer while the connection is still in plaintext
+    let starttls = if stream.is_tls() { "" } else { "250-STARTTLS\r\n" };
+    // Passwords are never accepted in the clear
+    let auth_mechanisms = if state.require_auth && stream.is_tls() {
+        "250-AUTH PLAIN LOGIN\r\n"
+    } else {
+        ""
+    };
     stream
         .write_all(
             format!(
                 "250-{} Mail Forge ESMTP Server Ready\r\n\
-                    250-STARTTLS\r\n\
+                    {}\
+                    {}\
+                    250-PIPELINING\r\n\
+                    250-8BITMIME\r\n\
+                    250-CHUNKING\r\n\
+                    250-BINARYMIME\r\n\
+                    250-SMTPUTF8\r\n\
                     250 SIZE {}\r\n",
-                config.server.hostname, config.server.max_size,
+                config.server.hostname, starttls, auth_mechanisms, config.server.max_size,
             )
             .as_bytes(),
         )
@@ -195,34 +441,222 @@ where
     Ok(())
 }
 
+/// SMTP AUTH (RFC 4954) with the PLAIN and LOGIN mechanisms.
+async fn handle_auth<S>(
+    stream: &mut StreamType<S>,
+    state: &mut SessionState,
+    config: Arc<config::Config>,
+    arguments: &str,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if !state.require_auth {
+        stream
+            .write_all(b"502 5.5.1 AUTH not available on this port\r\n")
+            .await?;
+        return Ok(());
+    }
+    if !stream.is_tls() {
+        stream
+            .write_all(b"538 5.7.11 Encryption required for requested authentication mechanism\r\n")
+            .await?;
+        return Ok(());
+    }
+    if state.auth_user.is_some() {
+        stream
+            .write_all(b"503 5.5.1 Already authenticated\r\n")
+            .await?;
+        return Ok(());
+    }
+    if state.mail_from.is_some() {
+        stream
+            .write_all(b"503 5.5.1 AUTH not allowed during a mail transaction\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    let mut args = arguments.split_whitespace();
+    let mechanism = args.next().unwrap_or("").to_uppercase();
+    let initial_response = args.next();
+
+    let login = match mechanism.as_str() {
+        "PLAIN" => {
+            let message = match initial_response {
+                Some(response) => auth::decode_response(response),
+                None => read_auth_response(stream, &config, "").await?,
+            };
+            message.and_then(|message| auth::parse_plain(&message))
+        }
+        "LOGIN" => {
+            let username = match initial_response {
+                Some(response) => auth::decode_response(response),
+                // "Username:" and "Password:", as clients expect
+                None => read_auth_response(stream, &config, "VXNlcm5hbWU6").await?,
+            };
+            match username {
+                Ok(username) => read_auth_response(stream, &config, "UGFzc3dvcmQ6")
+                    .await?
+                    .map(|password| (username, password)),
+                Err(e) => Err(e),
+            }
+        }
+        _ => {
+            stream
+                .write_all(b"504 5.5.4 Unrecognized authentication mechanism\r\n")
+                .await?;
+            return Ok(());
+        }
+    };
+    let (username, password) = match login {
+        Ok(login) => login,
+        Err(e) => {
+            stream.write_all(e.reply()).await?;
+            return Ok(());
+        }
+    };
+
+    // Password hashes are slow on purpose, so check them off the async workers
+    let verified = tokio::task::spawn_blocking(move || {
+        auth::verify(&config.credentials, &username, &password)
+            .map(|credential| credential.username.clone())
+    })
+    .await?;
+
+    match verified {
+        Some(username) => {
+            info!("Client authenticated as {}", username);
+            state.auth_user = Some(username);
+            stream
+                .write_all(b"235 2.7.0 Authentication successful\r\n")
+                .await?;
+        }
+        None => {
+            warn!("Failed AUTH {} attempt", mechanism);
+            stream
+                .write_all(b"535 5.7.8 Authentication credentials invalid\r\n")
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends an AUTH challenge and reads the client's base64 answer.
+async fn read_auth_response<S>(
+    stream: &mut StreamType<S>,
+    config: &config::Config,
+    challenge: &str,
+) -> Result<Result<String, AuthError>, Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_all(format!("334 {}\r\n", challenge).as_bytes())
+        .await?;
+
+    let mut line = String::new();
+    let command_timeout = Duration::from_secs(config.server.command_timeout_secs);
+    if within(command_timeout, stream.read_line(&mut line)).await? == 0 {
+        return Err("Client disconnected during AUTH".into());
+    }
+    Ok(auth::decode_response(&line))
+}
+
 async fn handle_mail_from<S>(
     stream: &mut StreamType<S>,
     state: &mut SessionState,
+    config: Arc<config::Config>,
+    limiter: &Limiter,
+    resolver: &dyn Resolver,
+    addr: std::net::SocketAddr,
     arguments: &str,
 ) -> Result<(), Box<dyn std::error::Error>>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    if !arguments.to_uppercase().starts_with("FROM:") {
+    if state.require_tls && !stream.is_tls() {
+        stream
+            .write_all(b"530 5.7.0 Must issue a STARTTLS command first\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    if state.require_auth && state.auth_user.is_none() {
+        stream
+            .write_all(b"530 5.7.0 Authentication required\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    if state.mail_from.is_some() {
         stream
-            .write_all(b"501 5.5.2 Syntax error in parameters or arguments\r\n")
+            .write_all(b"503 5.5.1 Sender already specified\r\n")
             .await?;
         return Ok(());
     }
 
-    let email_start = arguments.find(':').unwrap_or(0) + 1;
-    let email = arguments[email_start..]
-        .trim()
-        .trim_start_matches('<')
-        .trim_end_matches('>');
-    if email.is_empty() {
+    let max_messages = config.limits.max_messages_per_session;
+    if max_messages > 0 && state.messages >= max_messages {
+        let reply = format!(
+            "421 4.7.0 {} Too many messages in this session, closing connection\r\n",
+            config.server.hostname
+        );
+        stream.write_all(reply.as_bytes()).await?;
+        stream.flush().await?;
+        return Err("Session reached its message limit".into());
+    }
+
+    let mut mail_from = match parse_mail_from(arguments) {
+        Ok(mail_from) => mail_from,
+        Err(e) => {
+            stream.write_all(e.reply().as_bytes()).await?;
+            return Ok(());
+        }
+    };
+
+    if let Some(reply) = check_address(&mail_from.address, mail_from.smtputf8) {
+        stream.write_all(reply).await?;
+        return Ok(());
+    }
+
+    // Only a logged-in client is trusted to say who it submits for; anyone
+    // else's claim counts as AUTH=<> (RFC 4954 section 5)
+    if state.auth_user.is_none() && mail_from.auth.is_some() {
+        mail_from.auth = Some(String::new());
+    }
+
+    // Refuse up front rather than after the client uploaded everything
+    if mail_from
+        .size
+        .is_some_and(|size| size > config.server.max_size)
+    {
+        stream
+            .write_all(b"552 5.3.4 Message size exceeds fixed maximum message size\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    if !limiter.allow_message(addr.ip()) {
+        warn!("Message rate limit reached for {}", addr);
         stream
-            .write_all(b"501 5.5.2 Syntax error: Empty email address\r\n")
+            .write_all(b"451 4.7.1 Too many messages from your address, try again later\r\n")
             .await?;
         return Ok(());
     }
 
-    state.mail_from = Some(email.to_string());
+    // Logged-in users and the local MTAs that speak LMTP to us send on
+    // behalf of others, so their address proves nothing
+    if state.auth_user.is_none() && state.protocol == Protocol::Smtp {
+        let helo = state.helo.as_deref().unwrap_or("");
+        let verdict = spf::verify(resolver, addr.ip(), helo, &mail_from.address).await;
+        info!(
+            "SPF {} for {} from {}",
+            verdict.result, verdict.domain, addr
+        );
+        state.spf = Some(verdict);
+    }
+
+    state.mail_from = Some(mail_from);
     stream.write_all(b"250 2.1.0 OK\r\n").await?;
     Ok(())
 }
@@ -231,134 +665,480 @@ async fn handle_rcpt_to<S>(
     stream: &mut StreamType<S>,
     state: &mut SessionState,
     config: Arc<config::Config>,
+    limiter: &Limiter,
+    addr: std::net::SocketAddr,
     arguments: &str,
 ) -> Result<(), Box<dyn std::error::Error>>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    if !arguments.to_uppercase().starts_with("TO:") {
+    if state.mail_from.is_none() {
         stream
-            .write_all(b"501 5.5.2 Syntax error in parameters or arguments\r\n")
+            .write_all(b"503 5.5.1 Need MAIL before RCPT\r\n")
             .await?;
         return Ok(());
     }
 
-    let email_start = arguments.find(':').unwrap_or(0) + 1;
-    let email = arguments[email_start..]
-        .trim()
-        .trim_start_matches('<')
-        .trim_end_matches('>');
+    let rcpt_to = match parse_rcpt_to(arguments) {
+        Ok(rcpt_to) => rcpt_to,
+        Err(e) => {
+            stream.write_all(e.reply().as_bytes()).await?;
+            return Ok(());
+        }
+    };
+
+    let smtputf8 = state
+        .mail_from
+        .as_ref()
+        .is_some_and(|mail_from| mail_from.smtputf8);
+    if let Some(reply) = check_address(&rcpt_to.address, smtputf8) {
+        stream.write_all(reply).await?;
+        return Ok(());
+    }
+
+    let max_recipients = config.limits.max_recipients;
+    if max_recipients != 0 && state.rcpt_to.len() >= max_recipients {
+        stream
+            .write_all(b"452 4.5.3 Too many recipients\r\n")
+            .await?;
+        return Ok(());
+    }
 
-    if email.is_empty() {
+    if !limiter.allow_recipient(addr.ip()) {
+        warn!("Recipient rate limit reached for {}", addr);
         stream
-            .write_all(b"501 5.5.2 Syntax error: Empty recipient address\r\n")
+            .write_all(b"451 4.7.1 Too many recipients from your address, try again later\r\n")
             .await?;
         return Ok(());
     }
 
-    if get_webhook_for_recipient(email, &config.webhooks).is_some() {
-        state.rcpt_to.push(email.to_string());
-        info!("Adding recipient: {}", email);
+    let webhook = get_webhook_for_recipient(&rcpt_to.address, &config.webhooks);
+    if let (Some(webhook), Some(spf)) = (webhook, &state.spf) {
+        if webhook.on_spf_fail == SpfFailAction::RejectRcpt && spf.result == SpfResult::Fail {
+            info!(
+                "Refusing recipient {} after SPF fail for {}",
+                rcpt_to.address, spf.domain
+            );
+            let reply = format!("550 5.7.23 SPF check failed for {}\r\n", spf.domain);
+            stream.write_all(reply.as_bytes()).await?;
+            return Ok(());
+        }
+    }
+
+    // Authenticated mail goes to the credential's webhook, whoever it is for
+    if state.auth_user.is_some() || webhook.is_some() {
+        info!("Adding recipient: {}", rcpt_to.address);
+        state.rcpt_to.push(rcpt_to);
         stream.write_all(b"250 2.1.5 Recipient OK\r\n").await?;
     } else {
-        info!("Skipping recipient: {}", email);
+        info!("Skipping recipient: {}", rcpt_to.address);
         stream.write_all(b"550 5.7.1 Unable to relay\r\n").await?;
     }
     Ok(())
 }
 
+/// Checks that an address is usable in this transaction, returning the
+/// rejection reply if it isn't.
+fn check_address(addr: &str, smtputf8: bool) -> Option<&'static [u8]> {
+    if !addr.is_ascii() && !smtputf8 {
+        return Some(b"553 5.6.7 Non-ASCII address requires SMTPUTF8\r\n");
+    }
+    if address::to_ascii(addr).is_none() {
+        return Some(b"553 5.1.3 Invalid domain in address\r\n");
+    }
+    None
+}
+
 async fn handle_data<S>(
     stream: &mut StreamType<S>,
     state: &mut SessionState,
     config: Arc<config::Config>,
+    spool: &Arc<Spool>,
+    resolver: &dyn Resolver,
+    addr: std::net::SocketAddr,
 ) -> Result<(), Box<dyn std::error::Error>>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     if !state.is_ready_for_data() {
+        // A pipelining client may have sent DATA before learning that every
+        // recipient was refused; it can carry on with RSET (RFC 2920 section 3.1)
+        let reply: &[u8] = if state.mail_from.is_some() {
+            b"554 5.5.1 No valid recipients\r\n"
+        } else {
+            b"503 5.5.1 Bad sequence of commands\r\n"
+        };
+        stream.write_all(reply).await?;
+        return Ok(());
+    }
+
+    let binary = state
+        .mail_from
+        .as_ref()
+        .is_some_and(|mail_from| mail_from.body == Some(BodyType::BinaryMime));
+    if binary {
+        // Binary content can't survive dot-stuffing (RFC 3030 section 3)
+        stream
+            .write_all(b"503 5.5.1 BODY=BINARYMIME requires BDAT\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    if state.chunks.is_some() {
+        // RFC 3030 doesn't allow mixing DATA into a BDAT transaction
         stream
-            .write_all(b"503 Bad sequence of commands\r\n")
+            .write_all(b"503 5.5.1 BDAT transaction in progress\r\n")
             .await?;
-        return Err("Session is not ready to accept DATA".into());
+        return Ok(());
     }
     stream
         .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
         .await?;
 
-    let mut email_data = String::new();
-    let mut total_size = 0;
+    let block_timeout = Duration::from_secs(config.server.data_block_timeout_secs);
+    let email_data = match read_data(stream, config.server.max_size, block_timeout).await? {
+        DataOutcome::Complete(email_data) => email_data,
+        DataOutcome::TooLarge => {
+            for _ in 0..state.message_reply_count() {
+                stream
+                    .write_all(b"552 5.3.4 Message size exceeds maximum permitted\r\n")
+                    .await?;
+            }
+            state.reset_transaction();
+            return Ok(());
+        }
+    };
 
-    loop {
-        let mut line = String::new();
-        let bytes_read = stream.read_line(&mut line).await?;
+    finish_message(stream, state, email_data, config, spool, resolver, addr).await
+}
 
-        if bytes_read == 0 {
-            return Err("Client disconnected during DATA phase".into());
-        }
+/// Receives one RFC 3030 chunk. The final chunk (`BDAT <size> LAST`) hands the
+/// assembled message to the same delivery path as DATA.
+async fn handle_bdat<S>(
+    stream: &mut StreamType<S>,
+    state: &mut SessionState,
+    config: Arc<config::Config>,
+    spool: &Arc<Spool>,
+    resolver: &dyn Resolver,
+    addr: std::net::SocketAddr,
+    arguments: &str,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut args = arguments.split_whitespace();
+    let size = args.next().and_then(|size| size.parse::<u64>().ok());
+    let last = match (args.next(), args.next()) {
+        (None, _) => Some(false),
+        (Some(arg), None) if arg.eq_ignore_ascii_case("LAST") => Some(true),
+        _ => None,
+    };
+    let block_timeout = Duration::from_secs(config.server.data_block_timeout_secs);
+
+    let Some(size) = size else {
+        // Without a valid size we can't tell where the chunk ends
+        stream
+            .write_all(b"501 5.5.4 Syntax error in BDAT parameters\r\n")
+            .await?;
+        stream.flush().await?;
+        return Err("Unparseable BDAT command".into());
+    };
 
-        total_size += bytes_read;
+    // The chunk data follows the command immediately, so it has to be consumed
+    // whether or not we accept it
+    let Some(last) = last else {
+        read_chunk(&mut *stream, size, None, block_timeout).await?;
+        stream
+            .write_all(b"501 5.5.4 Syntax error in BDAT parameters\r\n")
+            .await?;
+        return Ok(());
+    };
+
+    if !state.is_ready_for_data() {
+        read_chunk(&mut *stream, size, None, block_timeout).await?;
+        stream
+            .write_all(b"503 5.5.1 Bad sequence of commands\r\n")
+            .await?;
+        return Ok(());
+    }
 
-        if total_size > config.server.max_size {
+    let mut message = state.chunks.take().unwrap_or_default();
+    if message.len() as u64 + size > config.server.max_size as u64 {
+        read_chunk(&mut *stream, size, None, block_timeout).await?;
+        let replies = if last { state.message_reply_count() } else { 1 };
+        for _ in 0..replies {
             stream
-                .write_all(b"552 Message size exceeds maximum permitted\r\n")
+                .write_all(b"552 5.3.4 Message size exceeds maximum permitted\r\n")
                 .await?;
-            return Err("Email data size exceeded maximum limit.".into());
         }
+        state.reset_transaction();
+        return Ok(());
+    }
 
-        if line == ".\r\n" {
-            // Log the email to file
-            //save_email(&email_data).expect("Failed to save email to file");
-            break;
-        }
+    read_chunk(&mut *stream, size, Some(&mut message), block_timeout).await?;
 
-        email_data.push_str(&line);
+    if last {
+        return finish_message(stream, state, message, config, spool, resolver, addr).await;
     }
 
-    let mut successfully_forwarded = false;
+    stream
+        .write_all(format!("250 2.0.0 {} octets received\r\n", size).as_bytes())
+        .await?;
+    state.chunks = Some(message);
+    Ok(())
+}
 
-    for recipient in &state.rcpt_to {
-        if let Some(webhook) = get_webhook_for_recipient(recipient, &config.webhooks) {
-            match forward_to_webhook(recipient, webhook, &email_data).await {
-                Ok(_) => {
-                    info!(
-                        "Email successfully forwarded to webhook {} for recipient {}",
-                        webhook.url, recipient
-                    );
-                    successfully_forwarded = true;
-                }
-                Err(e) => {
-                    error!(
-                        "Failed to forward email to webhook {} for recipient {}: {}",
-                        webhook.url, recipient, e
-                    );
+/// Hands a completely received message on according to the protocol.
+async fn finish_message<S>(
+    stream: &mut StreamType<S>,
+    state: &mut SessionState,
+    message: Vec<u8>,
+    config: Arc<config::Config>,
+    spool: &Arc<Spool>,
+    resolver: &dyn Resolver,
+    addr: std::net::SocketAddr,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // The client gives up on us after a while (RFC 5321 section 4.5.3.2.6)
+    let limit = Duration::from_secs(config.server.data_termination_timeout_secs);
+    let deadline = Instant::now() + limit;
+
+    state.messages += 1;
+    let mut envelope = build_envelope(stream, state, addr);
+    // What our own users submit isn't ours to judge
+    if state.auth_user.is_none() {
+        let checks = async {
+            let signatures = dkim::verify(resolver, &message).await;
+            // Over LMTP there is no SPF result to align, so every message
+            // without an aligned signature would fail; DMARC is left to the
+            // MTA that passed it on, which saw the real sender
+            let dmarc = match state.protocol {
+                Protocol::Smtp => {
+                    Some(dmarc::verify(resolver, &message, state.spf.as_ref(), &signatures).await)
                 }
-            }
-        } else {
-            error!("No webhook mapping found for recipient: {}", recipient);
+                Protocol::Lmtp => None,
+            };
+            let arc = arc::verify(resolver, &message).await;
+            Ok::<_, std::io::Error>((signatures, dmarc, arc))
+        };
+        let (signatures, dmarc, arc) = within(limit, checks).await?;
+        envelope.dkim = Some(signatures);
+        envelope.dmarc = dmarc;
+        envelope.arc = Some(arc);
+    }
+
+    let rejection = envelope
+        .recipients
+        .iter()
+        .find_map(|recipient| data_rejection(&recipient.address, &envelope, &config));
+    if let Some((status, text)) = rejection {
+        info!("Refusing message {} from {}: {}", envelope.id, addr, text);
+        for _ in 0..state.message_reply_count() {
+            stream
+                .write_all(format!("550 {} {}\r\n", status, text).as_bytes())
+                .await?;
         }
+        state.reset_transaction();
+        return Ok(());
     }
 
-    if successfully_forwarded {
-        stream.write_all(b"250 OK\r\n").await?;
-    } else {
-        stream
-            .write_all(b"554 Failed to process email for all recipients.\r\n")
-            .await?;
+    let hostname = &config.server.hostname;
+    let mut message = authres::remove_forged(message, hostname);
+    let mut trace = received_header(stream, state, &config, &envelope, addr);
+    trace.push_str(&authres::header(hostname, &envelope));
+    message.splice(0..0, trace.into_bytes());
+
+    let limit = deadline.saturating_duration_since(Instant::now());
+    match state.protocol {
+        Protocol::Smtp => {
+            within(limit, queue_message(stream, state, envelope, message, spool, addr)).await
+        }
+        Protocol::Lmtp => within(limit, deliver_message(stream, state, envelope, message, config)).await,
+    }
+}
+
+/// Why a recipient's webhook refuses a message that is otherwise complete,
+/// as an enhanced status code and reply text. One refusal is enough to
+/// refuse the message for everyone.
+fn data_rejection(
+    recipient: &str,
+    envelope: &Envelope,
+    config: &config::Config,
+) -> Option<(&'static str, String)> {
+    let webhook = get_webhook_for_envelope(recipient, envelope, config)?;
+    if let Some(spf) = &envelope.spf {
+        if webhook.on_spf_fail == SpfFailAction::RejectData && spf.result == SpfResult::Fail {
+            return Some(("5.7.23", format!("SPF check failed for {}", spf.domain)));
+        }
     }
+    if let Some(dmarc) = &envelope.dmarc {
+        let reject = dmarc.result == DmarcResult::Fail && dmarc.policy == Some(DmarcPolicy::Reject);
+        if webhook.honor_dmarc_reject && reject {
+            return Some(("5.7.1", format!("Rejected by the DMARC policy of {}", dmarc.domain)));
+        }
+    }
+    None
+}
+
+/// Delivers a message to each recipient's webhook right away and answers
+/// with one reply per recipient, in RCPT order (RFC 2033 section 4.2).
+/// Nothing is spooled: a 4xx reply tells the client to retry that recipient.
+async fn deliver_message<S>(
+    stream: &mut StreamType<S>,
+    state: &mut SessionState,
+    envelope: Envelope,
+    message: Vec<u8>,
+    config: Arc<config::Config>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    for rcpt_to in std::mem::take(&mut state.rcpt_to) {
+        let recipient = &rcpt_to.address;
+        let reply = match get_webhook_for_envelope(recipient, &envelope, &config) {
+            // The mapping was checked at RCPT time, but be safe
+            None => format!("550 5.1.1 <{}> No webhook mapping found\r\n", recipient),
+            Some(webhook) => {
+                match forward_to_webhook(recipient, webhook, &message, &envelope).await {
+                    Ok(()) => {
+                        info!(
+                            "Delivered message to webhook {} for {}",
+                            webhook.url, recipient
+                        );
+                        format!("250 2.0.0 <{}> Delivered\r\n", recipient)
+                    }
+                    Err(e) if e.is_retryable() => {
+                        error!("Deferred delivery for {}: {}", recipient, e);
+                        format!("451 4.4.0 <{}> {}\r\n", recipient, e)
+                    }
+                    Err(e) => {
+                        error!("Failed delivery for {}: {}", recipient, e);
+                        format!("550 5.0.0 <{}> {}\r\n", recipient, e)
+                    }
+                }
+            }
+        };
+        stream.write_all(reply.as_bytes()).await?;
+    }
+
+    state.reset_transaction();
     Ok(())
 }
 
-fn save_email(raw_email: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let dir_path = "/var/log/mail-forge/emails";
-    std::fs::create_dir_all(dir_path)?;
+/// Spools a completely received message and answers the client.
+async fn queue_message<S>(
+    stream: &mut StreamType<S>,
+    state: &mut SessionState,
+    envelope: Envelope,
+    message: Vec<u8>,
+    spool: &Arc<Spool>,
+    addr: std::net::SocketAddr,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Hand the message over to the spool; the delivery workers take it from there
+    let id = envelope.id.clone();
+    let queued = spool
+        .blocking(move |spool| spool.enqueue(&envelope, &message))
+        .await;
+
+    // The transaction is over either way; only the greeting survives
+    state.reset_transaction();
 
-    let file_path = format!("{}/{}.eml", dir_path, Utc::now().timestamp());
-    info!("Saving email to: {}", file_path);
-    let mut file = File::create(&file_path)?;
-    file.write_all(raw_email.as_bytes())?;
+    match queued {
+        Ok(()) => {
+            stream
+                .write_all(format!("250 2.0.0 OK queued as {}\r\n", id).as_bytes())
+                .await?;
+        }
+        Err(e) => {
+            error!("Failed to spool message from {}: {}", addr, e);
+            stream
+                .write_all(b"451 4.3.0 Failed to queue message, try again later\r\n")
+                .await?;
+        }
+    }
     Ok(())
 }
 
+/// Describes the current transaction for delivery.
+fn build_envelope<S>(
+    stream: &StreamType<S>,
+    state: &SessionState,
+    addr: std::net::SocketAddr,
+) -> Envelope
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut envelope = Envelope::new(
+        state
+            .mail_from
+            .as_ref()
+            .map(|mail_from| mail_from.address.clone())
+            .unwrap_or_default(),
+        state
+            .rcpt_to
+            .iter()
+            .map(|rcpt_to| rcpt_to.address.clone())
+            .collect(),
+    );
+    envelope.helo = state.helo.clone();
+    if let Some(mail_from) = &state.mail_from {
+        envelope.ret = mail_from.ret;
+        envelope.envid = mail_from.envid.clone();
+        envelope.smtputf8 = mail_from.smtputf8;
+        envelope.body = mail_from.body;
+        envelope.auth_identity = mail_from.auth.clone().filter(|auth| !auth.is_empty());
+    }
+    for (recipient, rcpt_to) in envelope.recipients.iter_mut().zip(&state.rcpt_to) {
+        recipient.notify = rcpt_to.notify.clone();
+        recipient.orcpt = rcpt_to.orcpt.clone();
+    }
+    envelope.client_addr = Some(addr.to_string());
+    envelope.tls_client_subject = stream.tls_client_subject();
+    envelope.auth_user = state.auth_user.clone();
+    envelope.spf = state.spf.clone();
+    envelope
+}
+
+/// The trace header every accepted message gets on top (RFC 5321 section
+/// 4.4), naming the protocol as RFC 3848 does.
+fn received_header<S>(
+    stream: &StreamType<S>,
+    state: &SessionState,
+    config: &config::Config,
+    envelope: &Envelope,
+    addr: std::net::SocketAddr,
+) -> String
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let protocol = match (state.protocol, stream.is_tls(), state.auth_user.is_some()) {
+        (Protocol::Smtp, false, false) => "ESMTP",
+        (Protocol::Smtp, false, true) => "ESMTPA",
+        (Protocol::Smtp, true, false) => "ESMTPS",
+        (Protocol::Smtp, true, true) => "ESMTPSA",
+        (Protocol::Lmtp, false, _) => "LMTP",
+        (Protocol::Lmtp, true, _) => "LMTPS",
+    };
+    let client = match addr.ip() {
+        std::net::IpAddr::V4(ip) => format!("[{}]", ip),
+        std::net::IpAddr::V6(ip) => format!("[IPv6:{}]", ip),
+    };
+    format!(
+        "Received: from {} ({})\r\n\tby {} with {} id {};\r\n\t{}\r\n",
+        state.helo.as_deref().unwrap_or("unknown"),
+        client,
+        config.server.hostname,
+        protocol,
+        envelope.id,
+        envelope.received_at.to_rfc2822()
+    )
+}
+
 async fn handle_rset<S>(
     stream: &mut StreamType<S>,
     state: &mut SessionState,
@@ -366,7 +1146,7 @@ async fn handle_rset<S>(
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    *state = SessionState::new();
+    state.reset_transaction();
     stream.write_all(b"250 OK\r\n").await?;
     Ok(())
 }
@@ -384,5 +1164,6 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     stream.write_all(b"221 Bye\r\n").await?;
-    Err("QUIT received. Closing connection.")?
+    stream.flush().await?;
+    Ok(())
 }
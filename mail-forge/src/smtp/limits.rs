@@ -0,0 +1,189 @@
+use crate::config::LimitsConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Rate limit buckets are forgotten once they are full again, but only
+/// looked at when there are this many of them.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Session caps and per-client rate limits, shared by all listeners.
+pub struct Limiter {
+    config: LimitsConfig,
+    sessions: Mutex<Sessions>,
+    messages: Mutex<HashMap<IpAddr, TokenBucket>>,
+    recipients: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+#[derive(Default)]
+struct Sessions {
+    total: usize,
+    /// Open sessions by network, as grouped by `LimitsConfig::network`.
+    per_network: HashMap<IpAddr, usize>,
+}
+
+/// Why a new session was refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionRefused {
+    /// `max_sessions` are already open.
+    TooManySessions,
+    /// The client's network already has `max_sessions_per_ip` open.
+    TooManyFromNetwork,
+}
+
+impl SessionRefused {
+    pub fn reply(&self, hostname: &str) -> String {
+        match self {
+            SessionRefused::TooManySessions => format!(
+                "421 4.7.0 {} Too many connections, try again later\r\n",
+                hostname
+            ),
+            SessionRefused::TooManyFromNetwork => format!(
+                "421 4.7.0 {} Too many connections from your network, try again later\r\n",
+                hostname
+            ),
+        }
+    }
+}
+
+/// Holds a place among the open sessions until dropped.
+pub struct SessionPermit {
+    limiter: Arc<Limiter>,
+    /// The network the session counts towards, once the client is known.
+    network: Option<IpAddr>,
+}
+
+impl SessionPermit {
+    /// Counts the session towards `ip`'s network as well, if that still
+    /// has room under `max_sessions_per_ip`.
+    pub fn assign(&mut self, ip: IpAddr) -> Result<(), SessionRefused> {
+        let network = self.limiter.config.network(ip);
+        let mut sessions = self.limiter.sessions.lock().unwrap();
+        let from_network = sessions.per_network.get(&network).copied().unwrap_or(0);
+        let max_per_network = self.limiter.config.max_sessions_per_ip;
+        if max_per_network > 0 && from_network >= max_per_network {
+            return Err(SessionRefused::TooManyFromNetwork);
+        }
+
+        *sessions.per_network.entry(network).or_default() += 1;
+        self.network = Some(network);
+        Ok(())
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut sessions = self.limiter.sessions.lock().unwrap();
+        sessions.total -= 1;
+        let Some(network) = self.network else {
+            return;
+        };
+        if let Some(count) = sessions.per_network.get_mut(&network) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.per_network.remove(&network);
+            }
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            sessions: Mutex::new(Sessions::default()),
+            messages: Mutex::new(HashMap::new()),
+            recipients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Admits a new session from `ip` if neither session cap is reached.
+    pub fn open_session(self: &Arc<Self>, ip: IpAddr) -> Result<SessionPermit, SessionRefused> {
+        let mut permit = self.open_connection()?;
+        permit.assign(ip)?;
+        Ok(permit)
+    }
+
+    /// Admits a new session under `max_sessions` before its client is known,
+    /// as when a PROXY header has yet to name it; `SessionPermit::assign`
+    /// applies `max_sessions_per_ip` later.
+    pub fn open_connection(self: &Arc<Self>) -> Result<SessionPermit, SessionRefused> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let max_sessions = self.config.max_sessions;
+        if max_sessions > 0 && sessions.total >= max_sessions {
+            return Err(SessionRefused::TooManySessions);
+        }
+
+        sessions.total += 1;
+        Ok(SessionPermit {
+            limiter: self.clone(),
+            network: None,
+        })
+    }
+
+    /// Takes one message from `ip`'s allowance, returning false if it is used up.
+    pub fn allow_message(&self, ip: IpAddr) -> bool {
+        take_token(&self.messages, ip, self.config.messages_per_minute)
+    }
+
+    /// Takes one recipient from `ip`'s allowance, returning false if it is used up.
+    pub fn allow_recipient(&self, ip: IpAddr) -> bool {
+        take_token(&self.recipients, ip, self.config.recipients_per_minute)
+    }
+}
+
+fn take_token(
+    buckets: &Mutex<HashMap<IpAddr, TokenBucket>>,
+    ip: IpAddr,
+    per_minute: Option<u32>,
+) -> bool {
+    let Some(per_minute) = per_minute else {
+        return true;
+    };
+
+    let now = Instant::now();
+    let mut buckets = buckets.lock().unwrap();
+    if buckets.len() >= PRUNE_THRESHOLD {
+        buckets.retain(|_, bucket| !bucket.is_full(per_minute, now));
+    }
+    buckets
+        .entry(ip)
+        .or_insert_with(|| TokenBucket::full(per_minute, now))
+        .take(per_minute, now)
+}
+
+/// Allows bursts of up to `per_minute`, refilling continuously at that rate.
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn full(per_minute: u32, now: Instant) -> Self {
+        Self {
+            tokens: per_minute as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, per_minute: u32, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_minute as f64 / 60.0).min(per_minute as f64);
+        self.updated_at = now;
+    }
+
+    fn take(&mut self, per_minute: u32, now: Instant) -> bool {
+        self.refill(per_minute, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn is_full(&self, per_minute: u32, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens + elapsed * per_minute as f64 / 60.0 >= per_minute as f64
+    }
+}
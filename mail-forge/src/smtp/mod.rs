@@ -1,3 +1,9 @@
+pub mod auth;
+pub mod data;
 pub mod handler;
+pub mod limits;
+pub mod params;
+pub mod proxy;
 pub mod server;
 pub mod stream;
+pub mod timeout;
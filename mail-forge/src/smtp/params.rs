@@ -0,0 +1,260 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A parsed `MAIL FROM:<reverse-path> [parameters]` command.
+#[derive(Debug, Clone, Default)]
+pub struct MailFrom {
+    /// The reverse path; empty for the null sender `<>`.
+    pub address: String,
+    /// Message size declared with `SIZE=` (RFC 1870).
+    pub size: Option<usize>,
+    /// Body type declared with `BODY=` (RFC 6152, RFC 3030).
+    pub body: Option<BodyType>,
+    /// Whether `SMTPUTF8` was given (RFC 6531).
+    pub smtputf8: bool,
+    /// How much of the message a DSN should return (RFC 3461).
+    pub ret: Option<Ret>,
+    /// Envelope identifier to quote in a DSN (RFC 3461), xtext-decoded.
+    pub envid: Option<String>,
+    /// Who the message was originally submitted by, from `AUTH=` (RFC 4954
+    /// section 5), xtext-decoded; empty for `AUTH=<>`.
+    pub auth: Option<String>,
+}
+
+/// A parsed `RCPT TO:<forward-path> [parameters]` command.
+#[derive(Debug, Clone, Default)]
+pub struct RcptTo {
+    pub address: String,
+    /// Conditions under which the sender wants a DSN (RFC 3461).
+    pub notify: Option<Vec<Notify>>,
+    /// Original recipient as `addr-type;address`, xtext-decoded (RFC 3461).
+    pub orcpt: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BodyType {
+    SevenBit,
+    EightBitMime,
+    BinaryMime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Ret {
+    Full,
+    Hdrs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Notify {
+    Never,
+    Success,
+    Failure,
+    Delay,
+}
+
+/// Why a MAIL or RCPT command was refused, mapped to its SMTP reply.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParamError {
+    /// The path or a parameter value is malformed.
+    Syntax(String),
+    /// A parameter we don't implement.
+    Unrecognized(String),
+}
+
+impl ParamError {
+    pub fn reply(&self) -> String {
+        match self {
+            ParamError::Syntax(reason) => format!("501 5.5.4 {}\r\n", reason),
+            ParamError::Unrecognized(param) => {
+                format!("555 5.5.4 Parameter {} not recognized\r\n", param)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::Syntax(reason) => write!(f, "{}", reason),
+            ParamError::Unrecognized(param) => write!(f, "Parameter {} not recognized", param),
+        }
+    }
+}
+
+/// Parses the arguments of `MAIL`, i.e. everything after the command word.
+pub fn parse_mail_from(arguments: &str) -> Result<MailFrom, ParamError> {
+    let (address, params) = split_path(arguments, "FROM:")?;
+    let mut mail_from = MailFrom {
+        address,
+        ..Default::default()
+    };
+
+    for (keyword, value) in params {
+        match (keyword.as_str(), value) {
+            ("SIZE", Some(value)) => {
+                let size = value
+                    .parse()
+                    .map_err(|_| ParamError::Syntax("Invalid SIZE value".to_string()))?;
+                mail_from.size = Some(size);
+            }
+            ("BODY", Some(value)) => {
+                mail_from.body = Some(match value.to_ascii_uppercase().as_str() {
+                    "7BIT" => BodyType::SevenBit,
+                    "8BITMIME" => BodyType::EightBitMime,
+                    "BINARYMIME" => BodyType::BinaryMime,
+                    _ => return Err(ParamError::Syntax("Invalid BODY value".to_string())),
+                });
+            }
+            ("SMTPUTF8", None) => mail_from.smtputf8 = true,
+            ("RET", Some(value)) => {
+                mail_from.ret = Some(match value.to_ascii_uppercase().as_str() {
+                    "FULL" => Ret::Full,
+                    "HDRS" => Ret::Hdrs,
+                    _ => return Err(ParamError::Syntax("Invalid RET value".to_string())),
+                });
+            }
+            ("ENVID", Some(value)) => mail_from.envid = Some(decode_xtext(&value)?),
+            ("AUTH", Some(value)) if value == "<>" => mail_from.auth = Some(String::new()),
+            ("AUTH", Some(value)) => mail_from.auth = Some(decode_xtext(&value)?),
+            (keyword, _) => return Err(unrecognized(keyword)),
+        }
+    }
+
+    Ok(mail_from)
+}
+
+/// Parses the arguments of `RCPT`, i.e. everything after the command word.
+pub fn parse_rcpt_to(arguments: &str) -> Result<RcptTo, ParamError> {
+    let (address, params) = split_path(arguments, "TO:")?;
+    if address.is_empty() {
+        return Err(ParamError::Syntax(
+            "Syntax error: Empty recipient address".to_string(),
+        ));
+    }
+
+    let mut rcpt_to = RcptTo {
+        address,
+        ..Default::default()
+    };
+
+    for (keyword, value) in params {
+        match (keyword.as_str(), value) {
+            ("NOTIFY", Some(value)) => rcpt_to.notify = Some(parse_notify(&value)?),
+            ("ORCPT", Some(value)) => {
+                let (addr_type, addr) = value
+                    .split_once(';')
+                    .ok_or_else(|| ParamError::Syntax("Invalid ORCPT value".to_string()))?;
+                rcpt_to.orcpt = Some(format!("{};{}", addr_type, decode_xtext(addr)?));
+            }
+            (keyword, _) => return Err(unrecognized(keyword)),
+        }
+    }
+
+    Ok(rcpt_to)
+}
+
+fn unrecognized(keyword: &str) -> ParamError {
+    ParamError::Unrecognized(keyword.to_string())
+}
+
+/// An ESMTP parameter keyword (upper-cased) and its value, if it has one.
+type Param = (String, Option<String>);
+
+/// Splits `FROM:<path> KEY=VALUE ...` into the bare address and its parameters.
+fn split_path(arguments: &str, prefix: &str) -> Result<(String, Vec<Param>), ParamError> {
+    let syntax = |reason: &str| ParamError::Syntax(reason.to_string());
+
+    let rest = arguments
+        .get(..prefix.len())
+        .filter(|head| head.eq_ignore_ascii_case(prefix))
+        .map(|_| arguments[prefix.len()..].trim_start())
+        .ok_or_else(|| syntax("Syntax error in parameters or arguments"))?;
+
+    let (mut address, params) = match rest.strip_prefix('<') {
+        Some(rest) => split_bracketed(rest)?,
+        // Older clients and appliances leave out the brackets, which we let
+        // pass: the path then runs up to the first space
+        None => match rest.split_once(char::is_whitespace).unwrap_or((rest, "")) {
+            ("", _) => return Err(syntax("Syntax error: Missing path")),
+            bare => bare,
+        },
+    };
+
+    // Source routes (<@a,@b:user@domain>) are obsolete; keep only the mailbox
+    if address.starts_with('@') {
+        address = address
+            .split_once(':')
+            .map(|(_, mailbox)| mailbox)
+            .ok_or_else(|| syntax("Syntax error: Invalid source route"))?;
+    }
+
+    let params = params
+        .split_whitespace()
+        .map(|param| match param.split_once('=') {
+            Some((keyword, value)) => (keyword.to_ascii_uppercase(), Some(value.to_string())),
+            None => (param.to_ascii_uppercase(), None),
+        })
+        .collect();
+
+    Ok((address.to_string(), params))
+}
+
+/// Splits what follows the `<` of a path into the path and the rest.
+fn split_bracketed(rest: &str) -> Result<(&str, &str), ParamError> {
+    // Find the closing bracket, ignoring any inside a quoted local part
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '>' if !in_quotes => return Ok((&rest[..i], &rest[i + 1..])),
+            _ => {}
+        }
+    }
+    Err(ParamError::Syntax("Syntax error: Unterminated path".to_string()))
+}
+
+fn parse_notify(value: &str) -> Result<Vec<Notify>, ParamError> {
+    let mut notify = Vec::new();
+    for condition in value.split(',') {
+        notify.push(match condition.to_ascii_uppercase().as_str() {
+            "NEVER" => Notify::Never,
+            "SUCCESS" => Notify::Success,
+            "FAILURE" => Notify::Failure,
+            "DELAY" => Notify::Delay,
+            _ => return Err(ParamError::Syntax("Invalid NOTIFY value".to_string())),
+        });
+    }
+
+    // NEVER must stand alone (RFC 3461 section 4.1)
+    if notify.contains(&Notify::Never) && notify.len() > 1 {
+        return Err(ParamError::Syntax("Invalid NOTIFY value".to_string()));
+    }
+    Ok(notify)
+}
+
+/// Decodes RFC 3461 xtext, where `+XX` stands for the byte with hex value XX.
+pub fn decode_xtext(value: &str) -> Result<String, ParamError> {
+    let invalid = || ParamError::Syntax("Invalid xtext encoding".to_string());
+
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'+' {
+            let hex = [
+                bytes.next().ok_or_else(invalid)?,
+                bytes.next().ok_or_else(invalid)?,
+            ];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return Err(invalid());
+            }
+            let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
@@ -0,0 +1,113 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The first twelve bytes of every v2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest possible v1 header, CRLF included.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads the HAProxy PROXY protocol header (v1 or v2) a load balancer sends
+/// ahead of the client's own traffic and returns the client address in it.
+///
+/// `None` means the proxy had no client to report (`UNKNOWN`, `LOCAL` or a
+/// non-IP address family), as with its own health checks. Only the header is
+/// consumed, so the greeting or TLS handshake can follow directly.
+pub async fn read_proxy_header<R>(reader: &mut R) -> std::io::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    // Even "PROXY UNKNOWN\r\n" is longer than the v2 signature
+    let mut header = [0; 12];
+    reader.read_exact(&mut header).await?;
+
+    if &header == V2_SIGNATURE {
+        read_v2(reader).await
+    } else if header.starts_with(b"PROXY ") {
+        read_v1(reader, header.to_vec()).await
+    } else {
+        Err(invalid("Connection does not start with a PROXY header"))
+    }
+}
+
+/// Reads the rest of a `PROXY TCP4 <src> <dst> <sport> <dport>\r\n` line.
+async fn read_v1<R>(reader: &mut R, mut line: Vec<u8>) -> std::io::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    // Byte by byte, so nothing after the header is read along with it
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY v1 header is too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("Invalid source address in PROXY v1 header"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("Source address does not match PROXY v1 family"));
+            }
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid("Invalid source port in PROXY v1 header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Malformed PROXY v1 header")),
+    }
+}
+
+/// Reads the rest of a binary v2 header, after its signature.
+async fn read_v2<R>(reader: &mut R) -> std::io::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    let version_command = reader.read_u8().await?;
+    let family = reader.read_u8().await?;
+    let length = reader.read_u16().await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+
+    // The addresses may be followed by TLVs, which we don't need
+    let mut addresses = vec![0; length as usize];
+    reader.read_exact(&mut addresses).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: the proxy's own connection, no client behind it
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("Unsupported PROXY v2 command")),
+    }
+
+    match family >> 4 {
+        // AF_INET: source and destination address, then source and destination port
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6, laid out the same way
+        0x2 if addresses.len() >= 36 => {
+            let octets: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        0x1 | 0x2 => Err(invalid("PROXY v2 header is too short for its address family")),
+        // AF_UNSPEC or AF_UNIX carry no address we could use
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
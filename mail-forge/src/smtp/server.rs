@@ -1,29 +1,114 @@
-use log::info;
+use log::{error, info};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use crate::config::{load_certs,self};
+use crate::dns::{self, SharedResolver};
+use crate::smtp::handler::Services;
+use crate::smtp::limits::Limiter;
+use crate::spool::{self, Spool};
+use crate::{acme, tls};
+use arc_swap::ArcSwap;
+
+/// How long an accept loop rests after a failed accept, such as when the
+/// process has run out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 pub async fn start(config: config::Config) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(&config.server.smtp_bind_address).await?;
-    info!(
-        "Starting SMTP server on {}",
-        config.server.smtp_bind_address
-    );
-    let tls_config = load_certs(
-        config.server.cert_path.clone().into(),
-        config.server.key_path.clone().into(),
-    )?;
-
-    let tls_config = Arc::new(tls_config); // Wrap in Arc for thread-safe sharing
+    let mut listeners = Vec::new();
+    for listener in config.effective_listeners() {
+        let socket = TcpListener::bind(&listener.address).await?;
+        info!(
+            "Starting {:?} listener on {}",
+            listener.mode, listener.address
+        );
+        listeners.push((socket, listener));
+    }
+    serve_all(listeners, config).await
+}
+
+/// Runs the SMTP server on an already bound listener.
+pub async fn serve(
+    listener: TcpListener,
+    config: config::Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let address = listener.local_addr()?.to_string();
+    let mut listener_config = config::ListenerConfig::new(address, config::ListenerMode::Smtp);
+    listener_config.require_tls = config.server.require_tls;
+    serve_all(vec![(listener, listener_config)], config).await
+}
+
+/// Runs every bound listener with its own settings, sharing one spool and
+/// routing table. Returns when any of them fails.
+pub async fn serve_all(
+    listeners: Vec<(TcpListener, config::ListenerConfig)>,
+    config: config::Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    serve_with_resolver(listeners, config, dns::system_resolver()).await
+}
+
+/// Like `serve_all`, but sender checks look up DNS records through `resolver`.
+pub async fn serve_with_resolver(
+    listeners: Vec<(TcpListener, config::ListenerConfig)>,
+    config: config::Config,
+    resolver: SharedResolver,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(acme) = &config.acme {
+        acme::ensure_certificate(acme)
+            .await
+            .map_err(|e| format!("Failed to obtain certificate via ACME: {}", e))?;
+    }
+    let tls_config = load_certs(&config)?;
+
+    // Shared by all connections and swapped out when the certificates change
+    let tls_config: tls::SharedServerConfig = Arc::new(ArcSwap::from_pointee(tls_config));
     let config = Arc::new(config);
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        info!("Connection from {}", addr);
-
-        let config = Arc::clone(&config);
-        let tls_config = tls_config.clone();
-        tokio::spawn(async move {
-            super::handler::handle_client(socket, tls_config, addr, config).await;
+    tls::spawn_reloader(config.clone(), tls_config.clone());
+    acme::spawn_renewal(config.clone(), tls_config.clone());
+
+    let (spool, receiver) = Spool::open(&config.spool.dir)?;
+    let spool = Arc::new(spool);
+    spool::worker::start(spool.clone(), receiver, config.clone())?;
+    let services = Arc::new(Services {
+        limiter: Arc::new(Limiter::new(config.limits.clone())),
+        config,
+        tls_config,
+        spool,
+        resolver,
+    });
+
+    let mut accept_loops = tokio::task::JoinSet::new();
+    for (listener, listener_config) in listeners {
+        let listener_config = Arc::new(listener_config);
+        let services = services.clone();
+        accept_loops.spawn(async move {
+            loop {
+                let (socket, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Sessions closing will free up what we ran out of
+                        error!(
+                            "Failed to accept connection on {}: {}",
+                            listener_config.address, e
+                        );
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                info!("Connection from {} on {}", addr, listener_config.address);
+
+                let listener_config = listener_config.clone();
+                let services = services.clone();
+                tokio::spawn(async move {
+                    super::handler::handle_client(socket, addr, listener_config, services).await;
+                });
+            }
         });
     }
+
+    // Accept loops only ever end by panicking
+    if let Some(result) = accept_loops.join_next().await {
+        result?;
+    }
+    Ok(())
 }
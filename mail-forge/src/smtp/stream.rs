@@ -1,13 +1,62 @@
+use crate::tls;
 use std::pin::Pin;
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader, BufWriter};
 use tokio_rustls::server::TlsStream;
 
+/// A client connection, before or after STARTTLS.
+///
+/// Replies are buffered and only flushed once we would otherwise block
+/// waiting for the client, which is exactly the batching RFC 2920 asks for:
+/// a pipelined group of commands gets its replies in one write, while a
+/// client waiting for an answer always receives it before we read again.
 pub enum StreamType<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    Plain(BufReader<S>),
-    Tls(BufReader<TlsStream<S>>),
+    Plain(BufReader<BufWriter<S>>),
+    Tls(Box<BufReader<BufWriter<TlsStream<S>>>>),
+}
+
+impl<S> StreamType<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn plain(stream: S) -> Self {
+        StreamType::Plain(BufReader::new(BufWriter::new(stream)))
+    }
+
+    pub fn tls(stream: TlsStream<S>) -> Self {
+        StreamType::Tls(Box::new(BufReader::new(BufWriter::new(stream))))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, StreamType::Tls(_))
+    }
+
+    /// Subject of the verified client certificate, if the client sent one.
+    pub fn tls_client_subject(&self) -> Option<String> {
+        match self {
+            StreamType::Plain(_) => None,
+            StreamType::Tls(inner) => {
+                let (_, connection) = inner.get_ref().get_ref().get_ref();
+                tls::client_subject(connection)
+            }
+        }
+    }
+}
+
+fn poll_flush_if_idle<T>(
+    inner: &mut BufReader<BufWriter<T>>,
+    cx: &mut Context<'_>,
+) -> Poll<std::io::Result<()>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    if inner.buffer().is_empty() {
+        ready!(Pin::new(inner.get_mut()).poll_flush(cx))?;
+    }
+    Poll::Ready(Ok(()))
 }
 
 impl<S> AsyncBufRead for StreamType<S>
@@ -19,15 +68,21 @@ where
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<std::io::Result<&[u8]>> {
         match self.get_mut() {
-            StreamType::Plain(inner) => Pin::new(inner).poll_fill_buf(cx),
-            StreamType::Tls(inner) => Pin::new(inner).poll_fill_buf(cx),
+            StreamType::Plain(inner) => {
+                ready!(poll_flush_if_idle(inner, cx))?;
+                Pin::new(inner).poll_fill_buf(cx)
+            }
+            StreamType::Tls(inner) => {
+                ready!(poll_flush_if_idle(inner, cx))?;
+                Pin::new(inner.as_mut()).poll_fill_buf(cx)
+            }
         }
     }
 
     fn consume(self: std::pin::Pin<&mut Self>, amt: usize) {
         match self.get_mut() {
             StreamType::Plain(inner) => Pin::new(inner).consume(amt),
-            StreamType::Tls(inner) => Pin::new(inner).consume(amt),
+            StreamType::Tls(inner) => Pin::new(inner.as_mut()).consume(amt),
         }
     }
 }
@@ -43,7 +98,7 @@ where
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
         match self.get_mut() {
             StreamType::Plain(inner) => Pin::new(inner).poll_write(cx, buf),
-            StreamType::Tls(inner) => Pin::new(inner).poll_write(cx, buf),
+            StreamType::Tls(inner) => Pin::new(inner.as_mut()).poll_write(cx, buf),
         }
     }
 
@@ -53,7 +108,7 @@ where
     ) -> std::task::Poll<Result<(), std::io::Error>> {
         match self.get_mut() {
             StreamType::Plain(inner) => Pin::new(inner).poll_flush(cx),
-            StreamType::Tls(inner) => Pin::new(inner).poll_flush(cx),
+            StreamType::Tls(inner) => Pin::new(inner.as_mut()).poll_flush(cx),
         }
     }
 
@@ -63,7 +118,7 @@ where
     ) -> std::task::Poll<Result<(), std::io::Error>> {
         match self.get_mut() {
             StreamType::Plain(inner) => Pin::new(inner).poll_shutdown(cx),
-            StreamType::Tls(inner) => Pin::new(inner).poll_shutdown(cx),
+            StreamType::Tls(inner) => Pin::new(inner.as_mut()).poll_shutdown(cx),
         }
     }
 }
@@ -78,8 +133,14 @@ where
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
         match self.get_mut() {
-            StreamType::Plain(inner) => Pin::new(inner).poll_read(cx, buf),
-            StreamType::Tls(inner) => Pin::new(inner).poll_read(cx, buf),
+            StreamType::Plain(inner) => {
+                ready!(poll_flush_if_idle(inner, cx))?;
+                Pin::new(inner).poll_read(cx, buf)
+            }
+            StreamType::Tls(inner) => {
+                ready!(poll_flush_if_idle(inner, cx))?;
+                Pin::new(inner.as_mut()).poll_read(cx, buf)
+            }
         }
     }
 }
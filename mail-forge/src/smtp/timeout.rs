@@ -0,0 +1,22 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// Runs `future`, failing with an `ErrorKind::TimedOut` I/O error if it
+/// takes longer than `limit`.
+pub async fn within<T, E>(limit: Duration, future: impl Future<Output = Result<T, E>>) -> Result<T, E>
+where
+    E: From<Error>,
+{
+    match tokio::time::timeout(limit, future).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::from(ErrorKind::TimedOut).into()),
+    }
+}
+
+/// Whether the session failed because the client ran out of time.
+pub fn is_timeout(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::TimedOut)
+}
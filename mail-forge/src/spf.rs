@@ -0,0 +1,628 @@
+//! Sender Policy Framework (RFC 7208) checks of the HELO and MAIL FROM
+//! identities against the connecting client.
+
+use crate::dns::Resolver;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+
+/// Mechanisms and modifiers that cause DNS queries, per evaluation (section 4.6.4).
+const MAX_LOOKUPS: usize = 10;
+
+/// Queries answered with no records, per evaluation (section 4.6.4).
+const MAX_VOID_LOOKUPS: usize = 2;
+
+/// Names an `mx` or `ptr` mechanism looks at (sections 4.6.4 and 5.5).
+const MAX_NAMES: usize = 10;
+
+/// The result of a check, named as in `Received-SPF` and `Authentication-Results`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpfResult {
+    /// The domain publishes no SPF record, or there was no domain to check.
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    /// A DNS failure that may go away if the check is repeated.
+    TempError,
+    /// The domain's records are broken and need fixing by its owner.
+    PermError,
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SpfResult::None => "none",
+            SpfResult::Neutral => "neutral",
+            SpfResult::Pass => "pass",
+            SpfResult::Fail => "fail",
+            SpfResult::SoftFail => "softfail",
+            SpfResult::TempError => "temperror",
+            SpfResult::PermError => "permerror",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What SPF says about the sender of one message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpfVerdict {
+    /// Result for the MAIL FROM identity, which for bounces is the HELO one.
+    pub result: SpfResult,
+    /// The domain the result is for.
+    pub domain: String,
+    /// Result for the HELO identity on its own, if it named a domain.
+    pub helo: Option<SpfResult>,
+}
+
+/// Checks whether `ip` may send mail for the HELO name and the MAIL FROM
+/// address. A null reverse path is checked as `postmaster@<helo>`
+/// (section 2.4).
+pub async fn verify(
+    resolver: &dyn Resolver,
+    ip: IpAddr,
+    helo: &str,
+    mail_from: &str,
+) -> SpfVerdict {
+    let helo = helo.trim_end_matches('.').to_ascii_lowercase();
+    let helo_result = if is_domain(&helo) {
+        let sender = format!("postmaster@{}", helo);
+        Some(check_host(resolver, ip, &helo, &sender, &helo).await)
+    } else {
+        // Address literals and the like leave nothing to look up
+        None
+    };
+
+    match mail_from.rsplit_once('@') {
+        Some((local, domain)) => {
+            let domain = idna::domain_to_ascii(domain).unwrap_or_default();
+            let sender = format!("{}@{}", local, domain);
+            SpfVerdict {
+                result: check_host(resolver, ip, &domain, &sender, &helo).await,
+                domain,
+                helo: helo_result,
+            }
+        }
+        _ => SpfVerdict {
+            result: helo_result.unwrap_or(SpfResult::None),
+            domain: helo,
+            helo: helo_result,
+        },
+    }
+}
+
+/// The `check_host()` function of section 4: what `domain` says about mail
+/// from `ip` with `sender` as the identity being checked.
+pub async fn check_host(
+    resolver: &dyn Resolver,
+    ip: IpAddr,
+    domain: &str,
+    sender: &str,
+    helo: &str,
+) -> SpfResult {
+    let mut evaluation = Evaluation {
+        resolver,
+        ip: ip.to_canonical(),
+        sender,
+        helo,
+        lookups: 0,
+        void_lookups: 0,
+    };
+    match evaluation.check(domain.to_string()).await {
+        Ok(result) | Err(result) => result,
+    }
+}
+
+/// Ends an evaluation early with the given result.
+type Outcome<T> = Result<T, SpfResult>;
+
+struct Evaluation<'a> {
+    resolver: &'a dyn Resolver,
+    ip: IpAddr,
+    sender: &'a str,
+    helo: &'a str,
+    /// Shared with included records, as the limits are for the whole check.
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl<'a> Evaluation<'a> {
+    /// Evaluates the record of `domain`; boxed, as `include` and `redirect`
+    /// come back here.
+    fn check(
+        &mut self,
+        domain: String,
+    ) -> Pin<Box<dyn Future<Output = Outcome<SpfResult>> + Send + '_>> {
+        Box::pin(async move {
+            if !is_domain(&domain) {
+                return Ok(SpfResult::None);
+            }
+
+            let records = self
+                .resolver
+                .txt(&domain)
+                .await
+                .map_err(|_| SpfResult::TempError)?;
+            let mut records = records.iter().filter(|record| is_spf_record(record));
+            let record = match (records.next(), records.next()) {
+                (None, _) => return Ok(SpfResult::None),
+                (Some(record), None) => parse_record(record)?,
+                (Some(_), Some(_)) => return Err(SpfResult::PermError),
+            };
+
+            for (qualifier, mechanism) in &record.directives {
+                if self.matches(mechanism, &domain).await? {
+                    return Ok(*qualifier);
+                }
+            }
+
+            match &record.redirect {
+                Some(target) => {
+                    self.count_lookup()?;
+                    let target = self.expand_domain(target, &domain)?;
+                    match self.check(target).await? {
+                        SpfResult::None => Err(SpfResult::PermError),
+                        result => Ok(result),
+                    }
+                }
+                None => Ok(SpfResult::Neutral),
+            }
+        })
+    }
+
+    async fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Outcome<bool> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip4(network, prefix) => Ok(match self.ip {
+                IpAddr::V4(ip) => in_network4(ip, *network, *prefix),
+                IpAddr::V6(_) => false,
+            }),
+            Mechanism::Ip6(network, prefix) => Ok(match self.ip {
+                IpAddr::V6(ip) => in_network6(ip, *network, *prefix),
+                IpAddr::V4(_) => false,
+            }),
+            Mechanism::Include(target) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(target, domain)?;
+                match self.check(target).await? {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                    SpfResult::TempError => Err(SpfResult::TempError),
+                    SpfResult::None | SpfResult::PermError => Err(SpfResult::PermError),
+                }
+            }
+            Mechanism::A(target, prefix4, prefix6) => {
+                self.count_lookup()?;
+                let target = self.target(target.as_deref(), domain)?;
+                let addresses = self.addresses(&target).await?;
+                self.count_void(addresses.is_empty())?;
+                Ok(addresses
+                    .into_iter()
+                    .any(|address| self.in_network(address, *prefix4, *prefix6)))
+            }
+            Mechanism::Mx(target, prefix4, prefix6) => {
+                self.count_lookup()?;
+                let target = self.target(target.as_deref(), domain)?;
+                let exchanges = self
+                    .resolver
+                    .mx(&target)
+                    .await
+                    .map_err(|_| SpfResult::TempError)?;
+                self.count_void(exchanges.is_empty())?;
+                if exchanges.len() > MAX_NAMES {
+                    return Err(SpfResult::PermError);
+                }
+                for exchange in exchanges {
+                    let addresses = self.addresses(&exchange).await?;
+                    if addresses
+                        .into_iter()
+                        .any(|address| self.in_network(address, *prefix4, *prefix6))
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(target) => {
+                self.count_lookup()?;
+                let target = self.target(target.as_deref(), domain)?;
+                // DNS errors here only mean the mechanism doesn't match (section 5.5)
+                let names = self.resolver.ptr(self.ip).await.unwrap_or_default();
+                self.count_void(names.is_empty())?;
+                for name in names.iter().take(MAX_NAMES) {
+                    let name = name.trim_end_matches('.').to_ascii_lowercase();
+                    if !is_subdomain(&name, &target) {
+                        continue;
+                    }
+                    let addresses = self.addresses(&name).await.unwrap_or_default();
+                    if addresses.contains(&self.ip) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Exists(target) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(target, domain)?;
+                // Always an A query, whatever the client's address family
+                let addresses = self
+                    .resolver
+                    .a(&target)
+                    .await
+                    .map_err(|_| SpfResult::TempError)?;
+                self.count_void(addresses.is_empty())?;
+                Ok(!addresses.is_empty())
+            }
+        }
+    }
+
+    /// The A or AAAA records of `name`, whichever matches the client.
+    async fn addresses(&self, name: &str) -> Outcome<Vec<IpAddr>> {
+        let addresses = match self.ip {
+            IpAddr::V4(_) => self
+                .resolver
+                .a(name)
+                .await
+                .map(|ips| ips.into_iter().map(IpAddr::V4).collect()),
+            IpAddr::V6(_) => self
+                .resolver
+                .aaaa(name)
+                .await
+                .map(|ips| ips.into_iter().map(IpAddr::V6).collect()),
+        };
+        addresses.map_err(|_| SpfResult::TempError)
+    }
+
+    fn in_network(&self, address: IpAddr, prefix4: u8, prefix6: u8) -> bool {
+        match (self.ip, address) {
+            (IpAddr::V4(ip), IpAddr::V4(network)) => in_network4(ip, network, prefix4),
+            (IpAddr::V6(ip), IpAddr::V6(network)) => in_network6(ip, network, prefix6),
+            _ => false,
+        }
+    }
+
+    fn count_lookup(&mut self) -> Outcome<()> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(SpfResult::PermError);
+        }
+        Ok(())
+    }
+
+    fn count_void(&mut self, void: bool) -> Outcome<()> {
+        if void {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                return Err(SpfResult::PermError);
+            }
+        }
+        Ok(())
+    }
+
+    /// The domain a mechanism applies to: its own, or the current one.
+    fn target(&self, target: Option<&str>, domain: &str) -> Outcome<String> {
+        match target {
+            Some(target) => self.expand_domain(target, domain),
+            None => Ok(domain.to_string()),
+        }
+    }
+
+    /// Expands the macros in a domain-spec and shortens the result to fit in
+    /// a DNS name (section 4.8).
+    fn expand_domain(&self, spec: &str, domain: &str) -> Outcome<String> {
+        let mut expanded = self
+            .expand(spec, domain)?
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        while expanded.len() > 253 {
+            match expanded.split_once('.') {
+                Some((_, rest)) => expanded = rest.to_string(),
+                None => return Err(SpfResult::PermError),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Expands a macro-string (section 7).
+    fn expand(&self, spec: &str, domain: &str) -> Outcome<String> {
+        let mut expanded = String::new();
+        let mut chars = spec.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => expanded.push('%'),
+                Some('_') => expanded.push(' '),
+                Some('-') => expanded.push_str("%20"),
+                Some('{') => {
+                    let mut expression = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => expression.push(c),
+                            None => return Err(SpfResult::PermError),
+                        }
+                    }
+                    expanded.push_str(&self.expand_macro(&expression, domain)?);
+                }
+                _ => return Err(SpfResult::PermError),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Expands one `%{...}` macro: letter, digits, `r` and delimiters.
+    fn expand_macro(&self, expression: &str, domain: &str) -> Outcome<String> {
+        let mut chars = expression.chars();
+        let letter = chars.next().ok_or(SpfResult::PermError)?;
+        let rest = chars.as_str();
+
+        let (local, sender_domain) = self
+            .sender
+            .rsplit_once('@')
+            .unwrap_or(("postmaster", self.sender));
+        let value = match letter.to_ascii_lowercase() {
+            's' => self.sender.to_string(),
+            'l' => local.to_string(),
+            'o' => sender_domain.to_string(),
+            'd' => domain.to_string(),
+            'i' => match self.ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => nibbles(ip),
+            },
+            // Validating the client's name would cost more lookups than the
+            // macro is worth, which section 7.3 allows for
+            'p' => "unknown".to_string(),
+            'v' => match self.ip {
+                IpAddr::V4(_) => "in-addr".to_string(),
+                IpAddr::V6(_) => "ip6".to_string(),
+            },
+            'h' => self.helo.to_string(),
+            _ => return Err(SpfResult::PermError),
+        };
+
+        let digits_end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let keep = match &rest[..digits_end] {
+            "" => None,
+            digits => match digits.parse::<usize>() {
+                Ok(0) | Err(_) => return Err(SpfResult::PermError),
+                Ok(keep) => Some(keep),
+            },
+        };
+        let rest = &rest[digits_end..];
+        let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+            Some(delimiters) => (true, delimiters),
+            None => (false, rest),
+        };
+        if !delimiters.chars().all(|c| ".-+,/_=".contains(c)) {
+            return Err(SpfResult::PermError);
+        }
+        let delimiters = if delimiters.is_empty() {
+            "."
+        } else {
+            delimiters
+        };
+
+        let mut parts: Vec<&str> = value.split(|c| delimiters.contains(c)).collect();
+        if reverse {
+            parts.reverse();
+        }
+        if let Some(keep) = keep {
+            parts.drain(..parts.len().saturating_sub(keep));
+        }
+        let value = parts.join(".");
+
+        if letter.is_ascii_uppercase() {
+            Ok(url_escape(&value))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+enum Mechanism {
+    All,
+    Include(String),
+    /// Optional domain-spec and the IPv4 and IPv6 prefix lengths.
+    A(Option<String>, u8, u8),
+    Mx(Option<String>, u8, u8),
+    Ptr(Option<String>),
+    Ip4(Ipv4Addr, u8),
+    Ip6(Ipv6Addr, u8),
+    Exists(String),
+}
+
+struct Record {
+    directives: Vec<(SpfResult, Mechanism)>,
+    redirect: Option<String>,
+}
+
+fn is_spf_record(record: &str) -> bool {
+    let version = record.split(' ').next().unwrap_or("");
+    version.eq_ignore_ascii_case("v=spf1")
+}
+
+/// Parses a whole record up front, as a syntax error anywhere in it makes
+/// the result `permerror` (section 4.6).
+fn parse_record(record: &str) -> Outcome<Record> {
+    let mut parsed = Record {
+        directives: Vec::new(),
+        redirect: None,
+    };
+    let mut explanation_seen = false;
+
+    for term in record.split(' ').skip(1).filter(|term| !term.is_empty()) {
+        if let Some((name, value)) = modifier(term) {
+            if name.eq_ignore_ascii_case("redirect") {
+                if parsed.redirect.replace(value.to_string()).is_some() {
+                    return Err(SpfResult::PermError);
+                }
+            } else if name.eq_ignore_ascii_case("exp") {
+                // Explanations are for rejection texts, which we write ourselves
+                if explanation_seen {
+                    return Err(SpfResult::PermError);
+                }
+                explanation_seen = true;
+            }
+            continue;
+        }
+        parsed.directives.push(parse_directive(term)?);
+    }
+    Ok(parsed)
+}
+
+/// Splits `name=value` if `term` is a modifier rather than a mechanism.
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    valid.then_some((name, value))
+}
+
+fn parse_directive(term: &str) -> Outcome<(SpfResult, Mechanism)> {
+    let (qualifier, term) = match term.chars().next() {
+        Some('+') => (SpfResult::Pass, &term[1..]),
+        Some('-') => (SpfResult::Fail, &term[1..]),
+        Some('~') => (SpfResult::SoftFail, &term[1..]),
+        Some('?') => (SpfResult::Neutral, &term[1..]),
+        _ => (SpfResult::Pass, term),
+    };
+
+    let name_end = term.find([':', '/']).unwrap_or(term.len());
+    let (name, argument) = term.split_at(name_end);
+    let domain_spec = |argument: &str| -> Outcome<Option<String>> {
+        match argument.strip_prefix(':') {
+            Some("") => Err(SpfResult::PermError),
+            Some(spec) => Ok(Some(spec.to_string())),
+            None if argument.is_empty() => Ok(None),
+            None => Err(SpfResult::PermError),
+        }
+    };
+
+    let mechanism = match name.to_ascii_lowercase().as_str() {
+        "all" if argument.is_empty() => Mechanism::All,
+        "include" => Mechanism::Include(domain_spec(argument)?.ok_or(SpfResult::PermError)?),
+        "exists" => Mechanism::Exists(domain_spec(argument)?.ok_or(SpfResult::PermError)?),
+        "ptr" => Mechanism::Ptr(domain_spec(argument)?),
+        "a" | "mx" => {
+            let (argument, prefix4, prefix6) = split_prefixes(argument)?;
+            let target = domain_spec(argument)?;
+            if name.eq_ignore_ascii_case("a") {
+                Mechanism::A(target, prefix4, prefix6)
+            } else {
+                Mechanism::Mx(target, prefix4, prefix6)
+            }
+        }
+        "ip4" => {
+            let argument = argument.strip_prefix(':').ok_or(SpfResult::PermError)?;
+            let (address, prefix) = split_network(argument, 32)?;
+            Mechanism::Ip4(address.parse().map_err(|_| SpfResult::PermError)?, prefix)
+        }
+        "ip6" => {
+            let argument = argument.strip_prefix(':').ok_or(SpfResult::PermError)?;
+            let (address, prefix) = split_network(argument, 128)?;
+            Mechanism::Ip6(address.parse().map_err(|_| SpfResult::PermError)?, prefix)
+        }
+        _ => return Err(SpfResult::PermError),
+    };
+    Ok((qualifier, mechanism))
+}
+
+/// Splits the `/<ip4-cidr>` and `//<ip6-cidr>` suffixes off an `a` or `mx`
+/// argument, defaulting to single addresses.
+fn split_prefixes(argument: &str) -> Outcome<(&str, u8, u8)> {
+    let mut argument = argument;
+    let mut prefix6 = 128;
+    if let Some((rest, prefix)) = argument.rsplit_once("//") {
+        prefix6 = parse_prefix(prefix, 128)?;
+        argument = rest;
+    }
+    let mut prefix4 = 32;
+    if let Some((rest, prefix)) = argument.rsplit_once('/') {
+        if prefix.chars().all(|c| c.is_ascii_digit()) {
+            prefix4 = parse_prefix(prefix, 32)?;
+            argument = rest;
+        }
+    }
+    Ok((argument, prefix4, prefix6))
+}
+
+fn split_network(argument: &str, max: u8) -> Outcome<(&str, u8)> {
+    match argument.split_once('/') {
+        Some((address, prefix)) => Ok((address, parse_prefix(prefix, max)?)),
+        None => Ok((argument, max)),
+    }
+}
+
+/// A prefix length, without leading zeros (section 12).
+fn parse_prefix(prefix: &str, max: u8) -> Outcome<u8> {
+    if prefix.is_empty() || (prefix.len() > 1 && prefix.starts_with('0')) {
+        return Err(SpfResult::PermError);
+    }
+    match prefix.parse::<u8>() {
+        Ok(prefix) if prefix <= max => Ok(prefix),
+        _ => Err(SpfResult::PermError),
+    }
+}
+
+fn in_network4(ip: Ipv4Addr, network: Ipv4Addr, prefix: u8) -> bool {
+    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+    u32::from(ip) & mask == u32::from(network) & mask
+}
+
+fn in_network6(ip: Ipv6Addr, network: Ipv6Addr, prefix: u8) -> bool {
+    let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+    u128::from(ip) & mask == u128::from(network) & mask
+}
+
+/// Whether `domain` is a fully qualified name with at least two labels
+/// (section 4.3).
+fn is_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+    domain.len() <= 253
+        && labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+fn is_subdomain(name: &str, domain: &str) -> bool {
+    name == domain || name.ends_with(&format!(".{}", domain))
+}
+
+/// The dot-separated nibbles of an IPv6 address for the `i` macro.
+fn nibbles(ip: Ipv6Addr) -> String {
+    let nibbles: Vec<String> = ip
+        .octets()
+        .iter()
+        .flat_map(|octet| [octet >> 4, octet & 0x0f])
+        .map(|nibble| format!("{:x}", nibble))
+        .collect();
+    nibbles.join(".")
+}
+
+/// Escapes everything but RFC 3986 unreserved characters, for upper-case macros.
+fn url_escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    escaped
+}
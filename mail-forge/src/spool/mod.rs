@@ -0,0 +1,315 @@
+pub mod retry;
+pub mod worker;
+
+use crate::arc::ArcVerdict;
+use crate::dkim::DkimSignature;
+use crate::dmarc::DmarcVerdict;
+use crate::smtp::params::{BodyType, Notify, Ret};
+use crate::spf::SpfVerdict;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Everything we know about an accepted message apart from its content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub id: String,
+    pub mail_from: String,
+    /// Recipients that still have to be delivered (or, in the dead-letter
+    /// directory, the ones that never were).
+    pub recipients: Vec<Recipient>,
+    pub helo: Option<String>,
+    pub client_addr: Option<String>,
+    pub received_at: DateTime<Utc>,
+    /// DSN parameters from `MAIL FROM` (RFC 3461).
+    #[serde(default)]
+    pub ret: Option<Ret>,
+    #[serde(default)]
+    pub envid: Option<String>,
+    #[serde(default)]
+    pub smtputf8: bool,
+    /// Body type declared with `BODY=`.
+    #[serde(default)]
+    pub body: Option<BodyType>,
+    /// Subject of the client certificate the sender authenticated with.
+    #[serde(default)]
+    pub tls_client_subject: Option<String>,
+    /// Username the sender logged in with via SMTP AUTH.
+    #[serde(default)]
+    pub auth_user: Option<String>,
+    /// Who a logged-in sender said the message was submitted by, with `AUTH=`.
+    #[serde(default)]
+    pub auth_identity: Option<String>,
+    /// SPF result for the sender, unless the session was exempt from checks.
+    #[serde(default)]
+    pub spf: Option<SpfVerdict>,
+    /// Results for the message's DKIM signatures, unless it was exempt from checks.
+    #[serde(default)]
+    pub dkim: Option<Vec<DkimSignature>>,
+    /// DMARC verdict for the From domain, unless the message was exempt from checks.
+    #[serde(default)]
+    pub dmarc: Option<DmarcVerdict>,
+    /// ARC chain verdict for forwarded mail, unless the message was exempt from checks.
+    #[serde(default)]
+    pub arc: Option<ArcVerdict>,
+}
+
+impl Envelope {
+    pub fn new(mail_from: String, rcpt_to: Vec<String>) -> Self {
+        let received_at = Utc::now();
+        Self {
+            id: generate_id(),
+            mail_from,
+            recipients: rcpt_to
+                .into_iter()
+                .map(|address| Recipient::new(address, received_at))
+                .collect(),
+            helo: None,
+            client_addr: None,
+            received_at,
+            ret: None,
+            envid: None,
+            smtputf8: false,
+            body: None,
+            tls_client_subject: None,
+            auth_user: None,
+            auth_identity: None,
+            spf: None,
+            dkim: None,
+            dmarc: None,
+            arc: None,
+        }
+    }
+}
+
+/// Delivery state of a single envelope recipient.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recipient {
+    pub address: String,
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    /// Enhanced status code once delivery has been given up on.
+    #[serde(default)]
+    pub status: Option<String>,
+    /// DSN parameters from `RCPT TO` (RFC 3461).
+    #[serde(default)]
+    pub notify: Option<Vec<Notify>>,
+    #[serde(default)]
+    pub orcpt: Option<String>,
+}
+
+impl Recipient {
+    pub fn new(address: String, next_attempt_at: DateTime<Utc>) -> Self {
+        Self {
+            address,
+            attempts: 0,
+            next_attempt_at,
+            last_error: None,
+            status: None,
+            notify: None,
+            orcpt: None,
+        }
+    }
+}
+
+/// On-disk queue of messages waiting for webhook delivery.
+///
+/// Each message is stored as `<id>.eml` (the raw message) and `<id>.json`
+/// (its envelope). The envelope is written last, so a message only becomes
+/// visible to the workers once both files are safely on disk. Recipients
+/// that can't be delivered end up in the same layout under `dead/`.
+pub struct Spool {
+    queue_dir: PathBuf,
+    dead_dir: PathBuf,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl Spool {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<(Self, mpsc::UnboundedReceiver<String>)> {
+        let queue_dir = dir.as_ref().join("queue");
+        let dead_dir = dir.as_ref().join("dead");
+        fs::create_dir_all(&queue_dir)?;
+        fs::create_dir_all(&dead_dir)?;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Ok((
+            Self {
+                queue_dir,
+                dead_dir,
+                sender,
+            },
+            receiver,
+        ))
+    }
+
+    /// Runs `work` on the blocking thread pool. The spool reads, writes and
+    /// fsyncs synchronously, which async code must not wait for in place.
+    pub async fn blocking<T, F>(self: &Arc<Self>, work: F) -> io::Result<T>
+    where
+        F: FnOnce(&Spool) -> io::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let spool = Arc::clone(self);
+        tokio::task::spawn_blocking(move || work(&spool))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Durably stores a message and hands it to the delivery workers.
+    pub fn enqueue(&self, envelope: &Envelope, message: &[u8]) -> io::Result<()> {
+        write_atomically(&message_path(&self.queue_dir, &envelope.id), message)?;
+        self.update(envelope)?;
+        sync_dir(&self.queue_dir)?;
+
+        info!(
+            "Spooled message {} for {} recipient(s)",
+            envelope.id,
+            envelope.recipients.len()
+        );
+        self.schedule(&envelope.id);
+        Ok(())
+    }
+
+    /// Queues an already spooled message for another delivery attempt.
+    pub fn schedule(&self, id: &str) {
+        if self.sender.send(id.to_string()).is_err() {
+            warn!(
+                "Delivery workers have stopped; message {} stays spooled",
+                id
+            );
+        }
+    }
+
+    pub fn load(&self, id: &str) -> io::Result<(Envelope, Vec<u8>)> {
+        load_from(&self.queue_dir, id)
+    }
+
+    /// Rewrites the envelope of a spooled message, e.g. after some recipients were delivered.
+    pub fn update(&self, envelope: &Envelope) -> io::Result<()> {
+        write_envelope(&self.queue_dir, envelope)
+    }
+
+    pub fn remove(&self, id: &str) -> io::Result<()> {
+        fs::remove_file(envelope_path(&self.queue_dir, id))?;
+        fs::remove_file(message_path(&self.queue_dir, id))?;
+        Ok(())
+    }
+
+    /// IDs of all messages currently in the spool, oldest first.
+    pub fn pending_ids(&self) -> io::Result<Vec<String>> {
+        list_ids(&self.queue_dir)
+    }
+
+    /// Moves recipients that will never be delivered to the dead-letter directory.
+    ///
+    /// Recipients of the same message that give up at different times are
+    /// collected into a single dead-letter entry.
+    pub fn dead_letter(&self, envelope: &Envelope, message: &[u8]) -> io::Result<()> {
+        let mut dead = envelope.clone();
+        if let Ok((existing, _)) = load_from(&self.dead_dir, &envelope.id) {
+            let mut recipients = existing.recipients;
+            recipients.extend(dead.recipients);
+            dead.recipients = recipients;
+        } else {
+            write_atomically(&message_path(&self.dead_dir, &envelope.id), message)?;
+        }
+        write_envelope(&self.dead_dir, &dead)?;
+        sync_dir(&self.dead_dir)?;
+
+        for recipient in &envelope.recipients {
+            warn!(
+                "Dead-lettered message {} for recipient {}: {}",
+                envelope.id,
+                recipient.address,
+                recipient.last_error.as_deref().unwrap_or("unknown error")
+            );
+        }
+        Ok(())
+    }
+
+    pub fn load_dead_letter(&self, id: &str) -> io::Result<(Envelope, Vec<u8>)> {
+        load_from(&self.dead_dir, id)
+    }
+
+    /// Rewrites the envelope of a dead letter, e.g. after some of its
+    /// recipients were redelivered.
+    pub fn update_dead_letter(&self, envelope: &Envelope) -> io::Result<()> {
+        write_envelope(&self.dead_dir, envelope)
+    }
+
+    pub fn remove_dead_letter(&self, id: &str) -> io::Result<()> {
+        fs::remove_file(envelope_path(&self.dead_dir, id))?;
+        fs::remove_file(message_path(&self.dead_dir, id))?;
+        Ok(())
+    }
+
+    /// IDs of all dead-lettered messages, oldest first.
+    pub fn dead_letter_ids(&self) -> io::Result<Vec<String>> {
+        list_ids(&self.dead_dir)
+    }
+}
+
+fn load_from(dir: &Path, id: &str) -> io::Result<(Envelope, Vec<u8>)> {
+    let envelope = fs::read(envelope_path(dir, id))?;
+    let envelope = serde_json::from_slice(&envelope)?;
+    let message = fs::read(message_path(dir, id))?;
+    Ok((envelope, message))
+}
+
+fn write_envelope(dir: &Path, envelope: &Envelope) -> io::Result<()> {
+    let contents = serde_json::to_vec_pretty(envelope)?;
+    write_atomically(&envelope_path(dir, &envelope.id), &contents)
+}
+
+fn list_ids(dir: &Path) -> io::Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                ids.push(stem.to_string());
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+fn envelope_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.json", id))
+}
+
+fn message_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.eml", id))
+}
+
+fn generate_id() -> String {
+    let suffix: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+
+    format!("{}-{}", Utc::now().format("%Y%m%d%H%M%S%3f"), suffix)
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
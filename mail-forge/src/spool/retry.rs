@@ -0,0 +1,58 @@
+use crate::config::RetryPolicy;
+use crate::spool::Recipient;
+use crate::webhook::client::DeliveryError;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::time::Duration;
+
+/// Delay before the next attempt once `attempts` attempts have failed.
+pub fn backoff_delay(policy: &RetryPolicy, attempts: u32) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+    let secs = policy.initial_delay_secs as f64 * policy.backoff_factor.powi(exponent);
+
+    // Never wait longer than the message is allowed to live anyway
+    Duration::from_secs_f64(secs.clamp(0.0, policy.max_age_secs as f64))
+}
+
+/// Records a failed delivery attempt and schedules the next one.
+///
+/// Returns `false` when the recipient should be dead-lettered instead: the
+/// webhook rejected the message outright, the attempts are used up, or the
+/// next attempt would fall outside the message's maximum age. The recipient's
+/// `status` then holds the enhanced status code to report in a DSN.
+pub fn record_failure(
+    policy: &RetryPolicy,
+    recipient: &mut Recipient,
+    received_at: DateTime<Utc>,
+    error: &DeliveryError,
+    now: DateTime<Utc>,
+) -> bool {
+    recipient.attempts += 1;
+    recipient.last_error = Some(error.to_string());
+
+    if !error.is_retryable() {
+        recipient.status = Some("5.0.0".to_string());
+        return false;
+    }
+
+    // 5.4.7: delivery time expired (RFC 3463)
+    recipient.status = Some("5.4.7".to_string());
+
+    if matches!(error, DeliveryError::ServerError(_)) && recipient.attempts >= policy.max_attempts {
+        return false;
+    }
+
+    let Some(next_attempt_at) = TimeDelta::from_std(backoff_delay(policy, recipient.attempts))
+        .ok()
+        .and_then(|delay| now.checked_add_signed(delay))
+    else {
+        return false;
+    };
+    let max_age = TimeDelta::seconds(policy.max_age_secs.min(i64::MAX as u64) as i64);
+    if next_attempt_at - received_at > max_age {
+        return false;
+    }
+
+    recipient.next_attempt_at = next_attempt_at;
+    recipient.status = None;
+    true
+}
@@ -0,0 +1,160 @@
+use crate::config;
+use crate::dsn;
+use crate::spool::{retry, Spool};
+use crate::webhook::client::forward_to_webhook;
+use crate::webhook::mapping::get_webhook_for_envelope;
+use chrono::{TimeDelta, Utc};
+use log::{error, info, warn};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+/// Starts the delivery workers and queues everything left in the spool by a previous run.
+pub fn start(
+    spool: Arc<Spool>,
+    receiver: mpsc::UnboundedReceiver<String>,
+    config: Arc<config::Config>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for _ in 0..config.spool.workers.max(1) {
+        let spool = spool.clone();
+        let receiver = receiver.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            loop {
+                let Some(id) = receiver.lock().await.recv().await else {
+                    break;
+                };
+                deliver(&spool, &config, &id).await;
+            }
+        });
+    }
+
+    let pending = spool.pending_ids()?;
+    if !pending.is_empty() {
+        info!("Resuming delivery of {} spooled message(s)", pending.len());
+    }
+    for id in pending {
+        spool.schedule(&id);
+    }
+
+    Ok(())
+}
+
+/// Attempts every recipient of a spooled message that is due, then either
+/// removes the message or schedules the next attempt.
+async fn deliver(spool: &Arc<Spool>, config: &config::Config, id: &str) {
+    let loaded = {
+        let id = id.to_string();
+        spool.blocking(move |spool| spool.load(&id)).await
+    };
+    let (mut envelope, message) = match loaded {
+        Ok((envelope, message)) => (envelope, Arc::new(message)),
+        Err(e) => {
+            error!("Failed to load spooled message {}: {}", id, e);
+            return;
+        }
+    };
+
+    let now = Utc::now();
+    let mut pending = Vec::new();
+    let mut failed = Vec::new();
+
+    for mut recipient in std::mem::take(&mut envelope.recipients) {
+        if recipient.next_attempt_at > now {
+            pending.push(recipient);
+            continue;
+        }
+
+        let Some(webhook) = get_webhook_for_envelope(&recipient.address, &envelope, config) else {
+            recipient.last_error = Some("No webhook mapping found for recipient".to_string());
+            recipient.status = Some("5.1.1".to_string());
+            failed.push(recipient);
+            continue;
+        };
+
+        match forward_to_webhook(&recipient.address, webhook, &message, &envelope).await {
+            Ok(_) => info!(
+                "Message {} successfully forwarded to webhook {} for recipient {}",
+                id, webhook.url, recipient.address
+            ),
+            Err(e) => {
+                error!(
+                    "Failed to forward message {} to webhook {} for recipient {}: {}",
+                    id, webhook.url, recipient.address, e
+                );
+                if retry::record_failure(
+                    &webhook.retry,
+                    &mut recipient,
+                    envelope.received_at,
+                    &e,
+                    now,
+                ) {
+                    pending.push(recipient);
+                } else {
+                    failed.push(recipient);
+                }
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        let mut dead = envelope.clone();
+        dead.recipients = failed;
+        let dead_lettered = {
+            let (dead, message) = (dead.clone(), message.clone());
+            spool
+                .blocking(move |spool| spool.dead_letter(&dead, &message))
+                .await
+        };
+        match dead_lettered {
+            Ok(()) => dsn::notify_failure(config, &dead, &message).await,
+            Err(e) => {
+                // Keep the recipients in the queue rather than lose them
+                error!("Failed to dead-letter message {}: {}", id, e);
+                for mut recipient in dead.recipients {
+                    recipient.next_attempt_at = now + TimeDelta::minutes(1);
+                    pending.push(recipient);
+                }
+            }
+        }
+    }
+
+    if pending.is_empty() {
+        let owned = id.to_string();
+        if let Err(e) = spool.blocking(move |spool| spool.remove(&owned)).await {
+            error!("Failed to remove message {} from spool: {}", id, e);
+        }
+        return;
+    }
+
+    envelope.recipients = pending;
+    let updated = {
+        let envelope = envelope.clone();
+        spool.blocking(move |spool| spool.update(&envelope)).await
+    };
+    if let Err(e) = updated {
+        error!("Failed to update envelope of message {}: {}", id, e);
+    }
+
+    let next_attempt_at = envelope
+        .recipients
+        .iter()
+        .map(|recipient| recipient.next_attempt_at)
+        .min()
+        .unwrap_or(now);
+    let delay = (next_attempt_at - Utc::now()).to_std().unwrap_or_default();
+
+    warn!(
+        "Message {} has {} undelivered recipient(s), next attempt in {}s",
+        id,
+        envelope.recipients.len(),
+        delay.as_secs()
+    );
+    let spool = spool.clone();
+    let id = id.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        spool.schedule(&id);
+    });
+}
@@ -0,0 +1,245 @@
+use crate::config;
+use arc_swap::ArcSwap;
+use log::{error, info};
+use rustls::crypto::CryptoProvider;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::RootCertStore;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+/// Picks the certificate for a handshake from the server name the client
+/// sent (SNI), falling back to the default certificate when the client sent
+/// none or one we have no certificate for.
+#[derive(Debug)]
+pub struct CertResolver {
+    default: Arc<CertifiedKey>,
+    /// Keyed by lower-case server name, including `*.` wildcard names.
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    /// Loads the `[server]` certificate and every `[[tls.certificates]]` entry.
+    pub fn load(
+        config: &config::Config,
+        provider: &CryptoProvider,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (cert_path, key_path) = config.default_certificate();
+        let default = load_certified_key(&cert_path, &key_path, provider)?;
+
+        let mut by_name = HashMap::new();
+        for certificate in &config.tls.certificates {
+            let key = load_certified_key(&certificate.cert_path, &certificate.key_path, provider)
+                .map_err(|e| {
+                format!(
+                    "Failed to load certificate for {}: {}",
+                    certificate.server_name, e
+                )
+            })?;
+            by_name.insert(certificate.server_name.to_ascii_lowercase(), key);
+        }
+
+        Ok(Self { default, by_name })
+    }
+
+    fn lookup(&self, server_name: &str) -> Option<&Arc<CertifiedKey>> {
+        let server_name = server_name.trim_end_matches('.').to_ascii_lowercase();
+        self.by_name.get(&server_name).or_else(|| {
+            let (_, parent) = server_name.split_once('.')?;
+            self.by_name.get(&format!("*.{}", parent))
+        })
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello
+            .server_name()
+            .and_then(|server_name| self.lookup(server_name))
+            .unwrap_or(&self.default);
+        Some(key.clone())
+    }
+}
+
+/// Reads a PEM certificate chain and private key and checks that they belong together.
+pub fn load_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> Result<Arc<CertifiedKey>, Box<dyn std::error::Error>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+
+    let signing_key = provider.key_provider.load_private_key(key)?;
+    let certified_key = CertifiedKey::new(certs, signing_key);
+    certified_key.keys_match()?;
+
+    Ok(Arc::new(certified_key))
+}
+
+/// The default crypto provider, narrowed down to `tls.cipher_suites` if set.
+pub fn crypto_provider(
+    tls: &config::TlsConfig,
+) -> Result<CryptoProvider, Box<dyn std::error::Error>> {
+    let mut provider = rustls::crypto::aws_lc_rs::default_provider();
+    if tls.cipher_suites.is_empty() {
+        return Ok(provider);
+    }
+
+    let mut cipher_suites = Vec::new();
+    for name in &tls.cipher_suites {
+        let suite = provider
+            .cipher_suites
+            .iter()
+            .find(|suite| suite.suite().as_str() == Some(name.as_str()))
+            .ok_or_else(|| format!("Unsupported TLS cipher suite {}", name))?;
+        cipher_suites.push(*suite);
+    }
+    provider.cipher_suites = cipher_suites;
+    Ok(provider)
+}
+
+/// Checks client certificates against `tls.client_ca_path` when
+/// `tls.client_auth` asks for them.
+pub fn client_cert_verifier(
+    tls: &config::TlsConfig,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn ClientCertVerifier>, Box<dyn std::error::Error>> {
+    let ca_path = match (tls.client_auth, &tls.client_ca_path) {
+        (config::ClientAuth::None, _) => return Ok(WebPkiClientVerifier::no_client_auth()),
+        (_, Some(ca_path)) => ca_path,
+        (_, None) => return Err("tls.client_auth needs tls.client_ca_path".into()),
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca_path)? {
+        roots.add(cert?)?;
+    }
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    let verifier = match tls.client_auth {
+        config::ClientAuth::Optional => builder.allow_unauthenticated().build()?,
+        _ => builder.build()?,
+    };
+    Ok(verifier)
+}
+
+/// The subject of the certificate a client authenticated with, like
+/// `CN=relay.example.net, O=Example`.
+pub fn client_subject(connection: &rustls::ServerConnection) -> Option<String> {
+    let cert = connection.peer_certificates()?.first()?;
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(parsed.subject().to_string())
+}
+
+/// The TLS configuration used for new handshakes. Reloading swaps in a whole
+/// new `ServerConfig`; sessions already established keep the one they began with.
+pub type SharedServerConfig = Arc<ArcSwap<rustls::ServerConfig>>;
+
+/// Reloads the certificates when one of their files changes, checked every
+/// `tls.reload_interval_secs`, and on SIGHUP. A reload that fails (say, the
+/// certificate was renewed but the key not yet) keeps the current
+/// configuration and is retried at the next check.
+pub fn spawn_reloader(config: Arc<config::Config>, shared: SharedServerConfig) {
+    tokio::spawn(async move {
+        let interval = match config.tls.reload_interval_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let mut hangup = Hangup::new();
+        let mut loaded = modification_times(&config);
+
+        loop {
+            let forced = tokio::select! {
+                _ = sleep(interval) => false,
+                _ = hangup.recv() => true,
+            };
+
+            let current = modification_times(&config);
+            if !forced && current == loaded {
+                continue;
+            }
+
+            match reload(&config, &shared) {
+                Ok(()) => loaded = current,
+                Err(e) => error!(
+                    "Failed to reload TLS certificates, keeping the old ones: {}",
+                    e
+                ),
+            }
+        }
+    });
+}
+
+/// Rebuilds the TLS configuration from the certificate files and swaps it in.
+pub fn reload(
+    config: &config::Config,
+    shared: &SharedServerConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    shared.store(Arc::new(config::load_certs(config)?));
+    info!("Reloaded TLS certificates");
+    Ok(())
+}
+
+async fn sleep(interval: Option<Duration>) {
+    match interval {
+        Some(interval) => tokio::time::sleep(interval).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Every certificate and key file in the configuration.
+fn certificate_paths(config: &config::Config) -> Vec<PathBuf> {
+    let (cert_path, key_path) = config.default_certificate();
+    let mut paths = vec![cert_path, key_path];
+    for certificate in &config.tls.certificates {
+        paths.push(certificate.cert_path.clone());
+        paths.push(certificate.key_path.clone());
+    }
+    paths
+}
+
+/// Follows symlinks, so a renewal that repoints `live/` at new files counts as a change.
+fn modification_times(config: &config::Config) -> Vec<Option<SystemTime>> {
+    certificate_paths(config)
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+/// SIGHUP as a reload trigger; never fires where there are no Unix signals.
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let signal = signal(SignalKind::hangup())
+                .map_err(|e| error!("Failed to listen for SIGHUP: {}", e))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                info!("SIGHUP received, reloading TLS certificates");
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    }
+}
@@ -1,59 +1,168 @@
-use crate::config;
+use crate::spool::Envelope;
 use crate::webhook::utils;
+use crate::{address, config};
 use chrono::Utc;
 use log::{error, info};
 use mailparse::MailHeaderMap;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use reqwest::{multipart, Client};
+use reqwest::{multipart, Client, StatusCode};
 use serde_json::json;
+use std::collections::HashMap;
 use std::env::temp_dir;
+use std::fmt;
 use std::fs::File;
 use std::io::Write;
 use std::path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// Why a message could not be handed to a webhook.
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// The webhook refused the message with a 4xx status; retrying will not help.
+    Rejected(StatusCode),
+    /// The webhook failed with a 5xx status (or asked us to slow down).
+    ServerError(StatusCode),
+    /// The webhook could not be reached or did not answer.
+    Network(String),
+    /// The message could not be turned into a webhook request.
+    InvalidMessage(String),
+}
+
+impl DeliveryError {
+    /// Whether a later attempt could succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            DeliveryError::ServerError(_) | DeliveryError::Network(_)
+        )
+    }
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::Rejected(status) => write!(f, "Webhook rejected message: {}", status),
+            DeliveryError::ServerError(status) => write!(f, "Webhook returned status: {}", status),
+            DeliveryError::Network(err) => write!(f, "Failed to reach webhook: {}", err),
+            DeliveryError::InvalidMessage(err) => write!(f, "Invalid message: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
 
 pub async fn forward_to_webhook(
     recipient: &str,
     webhook: &config::WebhookConfig,
-    raw_email: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let client = Client::new();
+    raw_email: &[u8],
+    envelope: &Envelope,
+) -> Result<(), DeliveryError> {
+    let client = shared_client(webhook.connect_timeout_secs)?;
 
     let (timestamp, token, signature) = generate_auth(&webhook.api_key);
 
+    let invalid = |e: Box<dyn std::error::Error>| DeliveryError::InvalidMessage(e.to_string());
+
     // Extract email data (subject, from, to, etc.)
-    let email_data = extract_email_data(recipient, raw_email)?;
+    let mut email_data = extract_email_data(recipient, raw_email).map_err(invalid)?;
+    add_envelope_data(&mut email_data, envelope);
 
     // Parse email and extract attachments
-    let attachments = extract_attachments(raw_email)?;
+    let attachments = extract_attachments(raw_email).map_err(invalid)?;
 
     // Save all attachments to temporary files
-    let temp_files = save_attachments_to_temp_files(&attachments)?;
+    let temp_files = save_attachments_to_temp_files(&attachments).map_err(invalid)?;
 
     // Create multipart form
-    let form =
-        create_multipart_form(&email_data, &timestamp, &token, &signature, &temp_files).await?;
+    let form = create_multipart_form(&email_data, &timestamp, &token, &signature, &temp_files)
+        .await
+        .map_err(invalid)?;
 
     // Send to webhook
-    send_to_webhook(&client, &webhook.url, form).await?;
+    let timeout = Duration::from_secs(webhook.timeout_secs);
+    send_to_webhook(&client, &webhook.url, timeout, form).await?;
 
     Ok(())
 }
 
+/// The client for webhooks with the given connect timeout. Clients are
+/// shared so deliveries reuse connections; reqwest only sets the connect
+/// timeout per client, hence one for each value in use.
+fn shared_client(connect_timeout_secs: u64) -> Result<Client, DeliveryError> {
+    static CLIENTS: OnceLock<Mutex<HashMap<u64, Client>>> = OnceLock::new();
+    let mut clients = CLIENTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(client) = clients.get(&connect_timeout_secs) {
+        return Ok(client.clone());
+    }
+    let client = Client::builder()
+        .connect_timeout(Duration::from_secs(connect_timeout_secs))
+        .build()
+        .map_err(|e| DeliveryError::Network(e.to_string()))?;
+    clients.insert(connect_timeout_secs, client.clone());
+    Ok(client)
+}
+
+/// Builds the fields `forward_to_webhook` would post for `recipient`, with
+/// attachments listed by name and size instead of their content.
+pub fn preview_payload(
+    recipient: &str,
+    raw_email: &[u8],
+    envelope: &Envelope,
+) -> Result<serde_json::Value, DeliveryError> {
+    let invalid = |e: Box<dyn std::error::Error>| DeliveryError::InvalidMessage(e.to_string());
+
+    let mut payload = extract_email_data(recipient, raw_email).map_err(invalid)?;
+    add_envelope_data(&mut payload, envelope);
+    let attachments = extract_attachments(raw_email).map_err(invalid)?;
+
+    payload["attachments"] = attachments
+        .iter()
+        .enumerate()
+        .map(|(i, (filename, data))| {
+            json!({
+                "field": format!("attachment-{}", i + 1),
+                "filename": filename,
+                "size": data.len(),
+            })
+        })
+        .collect();
+
+    Ok(payload)
+}
+
 async fn send_to_webhook(
     client: &Client,
     webhook_url: &str,
+    timeout: Duration,
     form: multipart::Form,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let response = client.post(webhook_url).multipart(form).send().await;
+) -> Result<(), DeliveryError> {
+    let response = client
+        .post(webhook_url)
+        .timeout(timeout)
+        .multipart(form)
+        .send()
+        .await;
 
     match response {
         Ok(resp) => {
             let status = resp.status();
-            let body = resp.text().await.unwrap_or_else(|err| {
-                error!("Failed to read response body: {}", err);
-                "Unable to read body".to_string()
-            });
+            let body = match resp.text().await {
+                Ok(body) => body,
+                // A webhook that stops halfway through its answer hasn't answered
+                Err(err) if err.is_timeout() => {
+                    error!("Timed out reading response from {}: {}", webhook_url, err);
+                    return Err(DeliveryError::Network(err.to_string()));
+                }
+                Err(err) => {
+                    error!("Failed to read response body: {}", err);
+                    "Unable to read body".to_string()
+                }
+            };
 
             if status.is_success() {
                 info!("Successfully forward to webhook: {}", webhook_url);
@@ -63,15 +172,28 @@ async fn send_to_webhook(
                     "Webhook responded with error. Status: {}, Body: {}",
                     status, body
                 );
-                Err(format!("Webhook returned status: {}", status).into())
+                Err(classify_status(status))
             }
         }
         Err(err) => {
             error!("Failed to send webhook request to {}: {}", webhook_url, err);
-            Err(err.into())
+            Err(DeliveryError::Network(err.to_string()))
         }
     }
 }
+
+fn classify_status(status: StatusCode) -> DeliveryError {
+    // Timeouts and rate limiting are the webhook's way of asking us to come back later
+    if status.is_client_error()
+        && status != StatusCode::REQUEST_TIMEOUT
+        && status != StatusCode::TOO_MANY_REQUESTS
+    {
+        DeliveryError::Rejected(status)
+    } else {
+        DeliveryError::ServerError(status)
+    }
+}
+
 fn generate_auth(api_key: &str) -> (String, String, String) {
     let timestamp = Utc::now().timestamp().to_string();
 
@@ -81,16 +203,16 @@ fn generate_auth(api_key: &str) -> (String, String, String) {
         .map(char::from)
         .collect();
 
-    let signature = utils::generate_signature(&api_key, &timestamp, &token);
+    let signature = utils::generate_signature(api_key, &timestamp, &token);
 
     (timestamp, token, signature)
 }
 
-fn extract_attachments(
-    raw_email: &str,
-) -> Result<Vec<(String, Vec<u8>)>, Box<dyn std::error::Error>> {
-    let parsed_mail = mailparse::parse_mail(raw_email.as_bytes())
-        .map_err(|e| format!("Failed to parse email: {}", e))?;
+type Attachment = (String, Vec<u8>);
+
+fn extract_attachments(raw_email: &[u8]) -> Result<Vec<Attachment>, Box<dyn std::error::Error>> {
+    let parsed_mail =
+        mailparse::parse_mail(raw_email).map_err(|e| format!("Failed to parse email: {}", e))?;
     let mut attachments = Vec::new();
 
     parse_mime_parts(&parsed_mail, &mut attachments)
@@ -101,7 +223,7 @@ fn extract_attachments(
 
 fn parse_mime_parts(
     part: &mailparse::ParsedMail,
-    attachments: &mut Vec<(String, Vec<u8>)>,
+    attachments: &mut Vec<Attachment>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     for (index, subpart) in part.subparts.iter().enumerate() {
         if let Some(content_disposition) =
@@ -134,22 +256,19 @@ fn parse_mime_parts(
 fn extract_filename_from_content_disposition(content_disposition: &str) -> Option<String> {
     content_disposition.split(';').find_map(|kv| {
         let kv = kv.trim();
-        if kv.starts_with("filename=") {
-            Some(kv["filename=".len()..].trim_matches('"').to_string())
-        } else {
-            None
-        }
+        kv.strip_prefix("filename=")
+            .map(|filename| filename.trim_matches('"').to_string())
     })
 }
 fn save_attachments_to_temp_files(
-    attachments: &[(String, Vec<u8>)],
+    attachments: &[Attachment],
 ) -> Result<Vec<path::PathBuf>, Box<dyn std::error::Error>> {
     let temp_dir = temp_dir();
     let mut file_paths = Vec::new();
 
     for (filename, data) in attachments {
         // Ensure filename is sanitized and not empty
-        let sanitized_filename = sanitize_filename::sanitize(&filename);
+        let sanitized_filename = sanitize_filename::sanitize(filename);
         if sanitized_filename.is_empty() {
             return Err(format!(
                 "Attachment filename '{}' is invalid after sanitization.",
@@ -199,8 +318,15 @@ async fn create_multipart_form(
 
     if let Some(obj) = email_data.as_object() {
         for (key, value) in obj {
-            if let Some(text_value) = value.as_str() {
-                form = form.text(key.clone(), text_value.to_string());
+            match value {
+                serde_json::Value::String(text_value) => {
+                    form = form.text(key.clone(), text_value.to_string());
+                }
+                // Structured fields such as `spf`, `dkim`, `dmarc` and `arc` are posted as JSON text
+                serde_json::Value::Object(_) | serde_json::Value::Array(_) => {
+                    form = form.text(key.clone(), value.to_string());
+                }
+                _ => {}
             }
         }
     }
@@ -215,10 +341,10 @@ async fn create_multipart_form(
 
 fn extract_email_data(
     recipient: &str,
-    raw_email: &str,
+    raw_email: &[u8],
 ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
     // Parse the email
-    let parsed_mail = mailparse::parse_mail(raw_email.as_bytes())?;
+    let parsed_mail = mailparse::parse_mail(raw_email)?;
 
     // Extract headers
     let headers = parsed_mail.get_headers();
@@ -226,11 +352,11 @@ fn extract_email_data(
 
     // Extract and split "From" header into display name and email
     let full_from = headers.get_first_value("From").unwrap_or_default();
-    let from_email = extract_email_address(&full_from);
+    let from_email = address::to_unicode(&extract_email_address(&full_from));
 
     // Extract and split "To" header into display name and email
     let full_to = headers.get_first_value("To").unwrap_or_default();
-    let to_email = extract_email_address(&recipient);
+    let to_email = address::to_unicode(&extract_email_address(recipient));
 
     let date = headers.get_first_value("Date").unwrap_or_default();
 
@@ -269,8 +395,10 @@ fn extract_email_data(
         "Subject": subject,
         "From": full_from,
         "from": from_email,
+        "from-ascii": address::to_ascii(&from_email).unwrap_or_default(),
         "To": full_to,
         "to": to_email,
+        "to-ascii": address::to_ascii(&to_email).unwrap_or_default(),
         "date": date,
         "body-plain": body_plain,
         "body-html": body_html,
@@ -279,6 +407,34 @@ fn extract_email_data(
     Ok(json_payload)
 }
 
+/// Adds what the SMTP session told us about the sender, for the fields it knows.
+fn add_envelope_data(payload: &mut serde_json::Value, envelope: &Envelope) {
+    if let Some(client_addr) = &envelope.client_addr {
+        payload["client-addr"] = json!(client_addr);
+    }
+    if let Some(subject) = &envelope.tls_client_subject {
+        payload["tls-client-subject"] = json!(subject);
+    }
+    if let Some(user) = &envelope.auth_user {
+        payload["auth-user"] = json!(user);
+    }
+    if let Some(identity) = &envelope.auth_identity {
+        payload["auth-identity"] = json!(identity);
+    }
+    if let Some(spf) = &envelope.spf {
+        payload["spf"] = json!(spf);
+    }
+    if let Some(dkim) = &envelope.dkim {
+        payload["dkim"] = json!(dkim);
+    }
+    if let Some(dmarc) = &envelope.dmarc {
+        payload["dmarc"] = json!(dmarc);
+    }
+    if let Some(arc) = &envelope.arc {
+        payload["arc"] = json!(arc);
+    }
+}
+
 fn extract_email_address(header_value: &str) -> String {
     let email_regex = regex::Regex::new(r"<([^>]+)>").unwrap();
     if let Some(captures) = email_regex.captures(header_value) {
@@ -1,6 +1,29 @@
 use std::collections::HashMap;
-use crate::config;
+use crate::spool::Envelope;
+use crate::{address, config};
 
+/// Finds the webhook for one recipient of `envelope`: the credential's own
+/// webhook if the sender authenticated, else the recipient mapping.
+pub fn get_webhook_for_envelope<'a>(
+    recipient: &str,
+    envelope: &Envelope,
+    config: &'a config::Config,
+) -> Option<&'a config::WebhookConfig> {
+    match &envelope.auth_user {
+        Some(username) => config
+            .credentials
+            .iter()
+            .find(|credential| &credential.username == username)
+            .map(|credential| &credential.webhook),
+        None => get_webhook_for_recipient(recipient, &config.webhooks),
+    }
+}
+
+/// Finds the webhook for `recipient`: an exact address entry wins over a
+/// `*@domain` wildcard. A wildcard also covers the domain's subdomains, and
+/// the most specific one matching wins. Domains are compared in their
+/// punycode form, so an IDN matches whether it was written in Unicode or
+/// ASCII.
 pub fn get_webhook_for_recipient<'a>(
     recipient: &str,
     webhook_mapping: &'a HashMap<String, config::WebhookConfig>,
@@ -9,14 +32,39 @@ pub fn get_webhook_for_recipient<'a>(
         return Some(webhook);
     }
 
+    let recipient = address::to_ascii(recipient)?;
+    let (local, domain) = address::split(&recipient)?;
+
     for (pattern, webhook) in webhook_mapping {
         if pattern.starts_with("*@") {
-            let domain = &pattern[2..];
-            if recipient.ends_with(domain) {
-                return Some(webhook);
-            }
+            continue;
+        }
+        let Some((pattern_local, pattern_domain)) = address::split(pattern) else {
+            continue;
+        };
+        if pattern_local == local && normalize_domain(pattern_domain).as_deref() == Some(domain) {
+            return Some(webhook);
         }
     }
 
-    None
+    webhook_mapping
+        .iter()
+        .filter_map(|(pattern, webhook)| {
+            let pattern_domain = normalize_domain(pattern.strip_prefix("*@")?)?;
+            covers(&pattern_domain, domain).then_some((pattern_domain.len(), webhook))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, webhook)| webhook)
+}
+
+/// Whether `domain` is `pattern_domain` or one of its subdomains.
+fn covers(pattern_domain: &str, domain: &str) -> bool {
+    domain == pattern_domain
+        || domain
+            .strip_suffix(pattern_domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.'))
+}
+
+fn normalize_domain(domain: &str) -> Option<String> {
+    idna::domain_to_ascii(domain).ok()
 }
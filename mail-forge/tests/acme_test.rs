@@ -0,0 +1,170 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{test_dir, write_test_cert};
+    use mail_forge::acme::{self, http::ChallengeResponder};
+    use mail_forge::config::AcmeConfig;
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    fn acme_config(state_dir: &Path, extra: &str) -> AcmeConfig {
+        toml::from_str(&format!(
+            "domains = [\"mx.example.com\"]\nstate_dir = \"{}\"\n{}",
+            state_dir.display(),
+            extra
+        ))
+        .expect("Failed to parse ACME config")
+    }
+
+    async fn http_get(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: mx.example.com\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_http01_responder_serves_only_known_tokens() {
+        let responder = ChallengeResponder::start("127.0.0.1:0").await.unwrap();
+        let addr = responder.local_addr().to_string();
+        responder.add("token-1".to_string(), "token-1.thumbprint".to_string());
+
+        let response = http_get(&addr, "/.well-known/acme-challenge/token-1").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("\r\n\r\ntoken-1.thumbprint"));
+
+        let response = http_get(&addr, "/.well-known/acme-challenge/token-2").await;
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_written_files_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("acme-private");
+        let path = dir.join("key.pem");
+        // Neither an older copy nor a leftover temporary file lends its mode
+        for stale in [path.clone(), dir.join("key.pem.tmp")] {
+            std::fs::write(&stale, "stale").unwrap();
+            std::fs::set_permissions(&stale, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        acme::write_atomically(&path, b"secret").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "secret");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!dir.join("key.pem.tmp").exists());
+    }
+
+    #[test]
+    fn test_renewal_follows_certificate_expiry() {
+        let dir = test_dir("acme-renewal");
+        let acme = acme_config(&dir, "");
+
+        // Nothing issued yet
+        assert!(acme::needs_renewal(&acme));
+
+        let (cert_path, _) = write_test_cert(&dir, "mx.example.com");
+        std::fs::rename(cert_path, acme.cert_path()).unwrap();
+        assert!(!acme::needs_renewal(&acme));
+
+        // Test certificates run until the year 4096; ask for a longer margin
+        let acme = acme_config(&dir, "renew_before_days = 1000000");
+        assert!(acme::needs_renewal(&acme));
+    }
+
+    #[test]
+    fn test_renewal_follows_configured_domains() {
+        let dir = test_dir("acme-domains");
+        let acme = acme_config(&dir, "");
+        let (cert_path, _) = write_test_cert(&dir, "mx.example.com");
+        std::fs::rename(cert_path, acme.cert_path()).unwrap();
+        assert!(!acme::needs_renewal(&acme));
+
+        // A name added to the config needs a new certificate
+        let acme: AcmeConfig = toml::from_str(&format!(
+            "domains = [\"mx.example.com\", \"mx2.example.com\"]\nstate_dir = \"{}\"",
+            dir.display()
+        ))
+        .unwrap();
+        assert!(acme::needs_renewal(&acme));
+    }
+
+    #[tokio::test]
+    async fn test_failed_renewal_keeps_serving_the_current_certificate() {
+        let dir = test_dir("acme-failed-renewal");
+        // Nobody listens there, so every order fails
+        let unreachable = "directory_url = \"http://127.0.0.1:1/directory\"";
+
+        let acme = acme_config(&dir, unreachable);
+        assert!(acme::ensure_certificate(&acme).await.is_err());
+
+        let (cert_path, key_path) = write_test_cert(&dir, "mx.example.com");
+        std::fs::rename(cert_path, acme.cert_path()).unwrap();
+        std::fs::rename(key_path, acme.key_path()).unwrap();
+        let acme = acme_config(
+            &dir,
+            &format!("{}\nrenew_before_days = 1000000", unreachable),
+        );
+        assert!(acme::needs_renewal(&acme));
+        acme::ensure_certificate(&acme)
+            .await
+            .expect("A certificate that is still valid should be served");
+    }
+
+    /// Issues a real certificate from a local Pebble server. Run Pebble with
+    /// its HTTP-01 port pointing at this machine, then e.g.
+    ///
+    /// PEBBLE_DIRECTORY_URL=https://localhost:14000/dir PEBBLE_ROOT_CA=pebble.minica.pem \
+    ///     cargo test --test acme_test -- --ignored
+    ///
+    /// `PEBBLE_HTTP_ADDRESS` (default `0.0.0.0:5002`) and `PEBBLE_DOMAIN`
+    /// (default `mx.example.com`) adjust the challenge setup.
+    #[tokio::test]
+    #[ignore]
+    async fn test_certificate_from_pebble() {
+        let Ok(directory_url) = std::env::var("PEBBLE_DIRECTORY_URL") else {
+            eprintln!("PEBBLE_DIRECTORY_URL not set, skipping");
+            return;
+        };
+        let root_ca = std::env::var("PEBBLE_ROOT_CA").expect("PEBBLE_ROOT_CA not set");
+        let http_address =
+            std::env::var("PEBBLE_HTTP_ADDRESS").unwrap_or_else(|_| "0.0.0.0:5002".to_string());
+        let domain =
+            std::env::var("PEBBLE_DOMAIN").unwrap_or_else(|_| "mx.example.com".to_string());
+
+        let dir = test_dir("acme-pebble");
+        let acme: AcmeConfig = toml::from_str(&format!(
+            r#"
+            directory_url = "{}"
+            domains = ["{}"]
+            state_dir = "{}"
+            http_bind_address = "{}"
+            root_ca_path = "{}"
+            "#,
+            directory_url,
+            domain,
+            dir.display(),
+            http_address,
+            root_ca
+        ))
+        .unwrap();
+
+        acme::ensure_certificate(&acme)
+            .await
+            .expect("Failed to obtain certificate");
+        assert!(acme.account_path().exists());
+        assert!(!acme::needs_renewal(&acme));
+
+        // A second run reuses the account and the still valid certificate
+        acme::ensure_certificate(&acme).await.unwrap();
+    }
+}
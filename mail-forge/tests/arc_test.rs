@@ -0,0 +1,185 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{
+        deliver_and_load_spooled, spawn_webhook_recorder, spooling_test_config,
+        start_test_server_with_resolver, test_config, test_dir, SmtpClient, StubResolver,
+    };
+    use mail_forge::arc::{verify, ArcResult};
+    use std::time::Duration;
+
+    // A list at manchego.org forwarding through scamorza.org, each hop sealed
+    const FORWARDED_MESSAGE: &str = "ARC-Seal: i=2; a=rsa-sha256; s=rsa; d=manchego.org; cv=pass;\r
+        b=wpAAy6QusmF4O8SeziNaKxXL6EleeBYxQ0HrXl2cDgzHLOvYG0N1Wpz0bpVbA8VgteD2X8XCW\r
+        yrdlZ5dIPTcCvgfLGLXLRTIcYUdKyfFh5IVEciaUOUsxlSRPpekENZKzdHFkL4j1mAAvpDNJ7Ft\r
+        OFIp0ku5dACn80g7D4cSEU0=;\r
+ARC-Message-Signature: i=2; a=rsa-sha256; s=rsa; d=manchego.org; c=relaxed/relaxed;\r
+        h=Subject:To:From:DKIM-Signature; t=1674137914; bh=4ET7siw2kYV7jcN+fzsuYng/\r
+        sr/BmIzzEjh43dVAv40=; b=V3tMBI1RsyJJY7HUABcebHf0mDJ9odbPm++ZMY5AsCaUYNoSsAm\r
+        wCf5wYlJQ26KmsluOYXoPwML0a/xvnMXPv6Rs4Z9k4IwzpzhGLsijDXymGPsW3hgq/6ivVTPkwU\r
+        +pGSCC70rHNrAFFk5P67Ly0tbGYjJ0wZVHBzqL8IJBXK4=;\r
+ARC-Authentication-Results: i=2; manchego.org;\r
+        dkim=pass header.d=manchego.org header.s=rsa header.b=IN4oMvqq\r
+Authentication-Results: manchego.org;\r
+        dkim=pass header.d=manchego.org header.s=rsa header.b=IN4oMvqq\r
+ARC-Seal: i=1; a=ed25519-sha256; s=ed; d=scamorza.org; cv=none;\r
+        b=k/MAHECtaer9v4oczoe00a6XMjrxU4QUVVPlZI8XYegbiOgDSaeR6IrwBSKVcN0ELYU+HXlNW\r
+        RuUGkRuZXQODA==;\r
+ARC-Message-Signature: i=1; a=ed25519-sha256; s=ed; d=scamorza.org; c=relaxed/relaxed;\r
+        h=Subject:To:From:DKIM-Signature; t=1674137914; bh=4ET7siw2kYV7jcN+fzsuYng/\r
+        sr/BmIzzEjh43dVAv40=; b=ZVPqB/5+mbOEKIgBsq+S71Sfj2JZUlGmYEA0Ygbj0S1VmTAnsVu\r
+        FQSInMY4/qcIeqU23BtzMgCFVZfAg5i3zDw==;\r
+ARC-Authentication-Results: i=1; scamorza.org;\r
+        dkim=pass header.d=manchego.org header.s=rsa header.b=IN4oMvqq\r
+Authentication-Results: scamorza.org;\r
+        dkim=pass header.d=manchego.org header.s=rsa header.b=IN4oMvqq\r
+DKIM-Signature: v=1; a=rsa-sha256; s=rsa; d=manchego.org; c=relaxed/relaxed;\r
+        h=Subject:To:From; t=1674137914; bh=4ET7siw2kYV7jcN+fzsuYng/sr/BmIzzEjh43dV\r
+        Av40=; b=IN4oMvqqxWCEyC38F7fZecYJcnq+7zP3G/xjcI64M3/Dzys2lmQeLYAXipwwYvEa5a\r
+        VwCcJ7XUX0kSxtr6igC8FIJEDI6UmdvJgMEj/hnEjXR8m4GPrphigjJy7hagaQymBT9WhlzsDPI\r
+        QRlUVoW0y5v1aDp3KF9bLVCKTELJPM=;\r
+From: queso@manchego.org\r
+To: affumicata@scamorza.org\r
+Subject: Say cheese\r
+\r
+We need to settle which one of us is tastier.\r
+";
+
+    fn sealer_keys() -> StubResolver {
+        StubResolver::default()
+            .txt("rsa._domainkey.manchego.org", "v=DKIM1; t=s; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDwIRP/UC3SBsEmGqZ9ZJW3/DkMoGeLnQg1fWn7/zYtIxN2SnFCjxOCKG9v3b4jYfcTNh5ijSsq631uBItLa7od+v/RtdC2UzJ1lWT947qR+Rcac2gbto/NMqJ0fzfVjH4OuKhitdY9tf6mcwGjaNBcWToIMmPSPDdQPNUYckcQ2QIDAQAB")
+            .txt("ed._domainkey.scamorza.org", "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=")
+    }
+
+    #[tokio::test]
+    async fn test_valid_chain_passes() {
+        let verdict = verify(&sealer_keys(), FORWARDED_MESSAGE.as_bytes()).await;
+        assert_eq!(verdict.result, ArcResult::Pass);
+        assert_eq!(verdict.reason, None);
+        assert_eq!(verdict.hops.len(), 2);
+
+        let original = &verdict.hops[0];
+        assert_eq!(original.instance, 1);
+        assert_eq!(original.sealer, "scamorza.org");
+        assert_eq!(original.authserv_id, "scamorza.org");
+        assert_eq!(
+            original.results,
+            "dkim=pass header.d=manchego.org header.s=rsa header.b=IN4oMvqq"
+        );
+        assert_eq!(verdict.hops[1].sealer, "manchego.org");
+
+        let verdict = verify(&sealer_keys(), b"Subject: Direct\r\n\r\nHello\r\n").await;
+        assert_eq!(verdict.result, ArcResult::None);
+        assert!(verdict.hops.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tampering_breaks_the_chain() {
+        let body = FORWARDED_MESSAGE.replace("tastier", "saltier");
+        let verdict = verify(&sealer_keys(), body.as_bytes()).await;
+        assert_eq!(verdict.result, ArcResult::Fail);
+        assert_eq!(
+            verdict.reason.as_deref(),
+            Some("message signature: body hash mismatch")
+        );
+        // What the hops said is still there to look at
+        assert_eq!(verdict.hops.len(), 2);
+
+        // Rewriting what an earlier hop recorded breaks its seal
+        let results = FORWARDED_MESSAGE.replacen(
+            "ARC-Authentication-Results: i=1; scamorza.org;\r\n        dkim=pass",
+            "ARC-Authentication-Results: i=1; scamorza.org;\r\n        dkim=fail",
+            1,
+        );
+        assert_ne!(results, FORWARDED_MESSAGE);
+        let verdict = verify(&sealer_keys(), results.as_bytes()).await;
+        assert_eq!(verdict.result, ArcResult::Fail);
+        assert!(verdict.reason.unwrap().starts_with("seal 2: "));
+    }
+
+    #[tokio::test]
+    async fn test_broken_chains_fail() {
+        // A missing header leaves a hop incomplete
+        let start = FORWARDED_MESSAGE.find("ARC-Seal: i=1").unwrap();
+        let end = FORWARDED_MESSAGE
+            .find("ARC-Message-Signature: i=1")
+            .unwrap();
+        let incomplete = format!(
+            "{}{}",
+            &FORWARDED_MESSAGE[..start],
+            &FORWARDED_MESSAGE[end..]
+        );
+        let verdict = verify(&sealer_keys(), incomplete.as_bytes()).await;
+        assert_eq!(verdict.result, ArcResult::Fail);
+        assert_eq!(verdict.reason.as_deref(), Some("incomplete ARC set"));
+
+        let failed = FORWARDED_MESSAGE.replacen("cv=pass", "cv=fail", 1);
+        let verdict = verify(&sealer_keys(), failed.as_bytes()).await;
+        assert_eq!(verdict.reason.as_deref(), Some("chain already failed"));
+
+        let unnumbered = FORWARDED_MESSAGE.replacen("ARC-Seal: i=2", "ARC-Seal: i=51", 1);
+        let verdict = verify(&sealer_keys(), unnumbered.as_bytes()).await;
+        assert_eq!(verdict.reason.as_deref(), Some("invalid instance"));
+
+        // The sealers' keys are needed to check anything
+        let verdict = verify(&StubResolver::default(), FORWARDED_MESSAGE.as_bytes()).await;
+        assert_eq!(verdict.result, ArcResult::Fail);
+        assert_eq!(
+            verdict.reason.as_deref(),
+            Some("message signature: no key for signature")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verdict_is_passed_to_the_webhook() {
+        let dir = test_dir("arc-payload");
+        let (url, mut requests) = spawn_webhook_recorder(200).await;
+        let config = test_config(&url, &dir, "", "");
+        let addr = start_test_server_with_resolver(config, sealer_keys()).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<list@manchego.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(FORWARDED_MESSAGE.as_bytes()).await;
+        client.send(b".\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+
+        let body = tokio::time::timeout(Duration::from_secs(10), requests.recv())
+            .await
+            .expect("Webhook was never called")
+            .unwrap();
+        assert!(body.contains("name=\"arc\""));
+        assert!(body.contains(
+            r#"{"authserv_id":"scamorza.org","instance":1,"results":"dkim=pass header.d=manchego.org header.s=rsa header.b=IN4oMvqq","sealer":"scamorza.org"}"#
+        ));
+        assert!(body.contains(r#""result":"pass""#));
+    }
+
+    #[tokio::test]
+    async fn test_verdict_is_recorded_in_authentication_results() {
+        let dir = test_dir("arc-header");
+        let config = spooling_test_config(&dir).await;
+        let addr = start_test_server_with_resolver(config, sealer_keys()).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        let (_, message) = deliver_and_load_spooled(
+            &mut client,
+            &dir,
+            "list@manchego.org",
+            FORWARDED_MESSAGE.as_bytes(),
+        )
+        .await;
+        assert!(message.contains("\tdkim=pass header.d=manchego.org header.s=rsa;\r\n"));
+        assert!(message.contains("\tarc=pass\r\n"));
+    }
+}
@@ -0,0 +1,197 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{
+        spawn_webhook_recorder, spawn_webhook_stub, start_test_listeners, test_cert_path,
+        test_config, test_dir, SmtpClient,
+    };
+    use argon2::password_hash::rand_core::OsRng;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use argon2::Argon2;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use mail_forge::config::{ListenerConfig, ListenerMode};
+    use mail_forge::smtp::auth::{decode_response, parse_plain, verify_password, AuthError};
+    use std::time::Duration;
+
+    #[test]
+    fn test_plain_messages_are_parsed() {
+        assert_eq!(
+            parse_plain("\0billing\0s3cret").unwrap(),
+            ("billing".to_string(), "s3cret".to_string())
+        );
+        assert!(parse_plain("billing\0billing\0s3cret").is_ok());
+        // Acting on behalf of someone else is not supported
+        assert_eq!(
+            parse_plain("admin\0billing\0s3cret").unwrap_err(),
+            AuthError::Malformed
+        );
+        assert_eq!(decode_response("*").unwrap_err(), AuthError::Cancelled);
+        assert_eq!(decode_response("not base64!").unwrap_err(), AuthError::Malformed);
+    }
+
+    #[test]
+    fn test_argon2_and_bcrypt_hashes_are_verified() {
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2 = Argon2::default()
+            .hash_password(b"s3cret", &salt)
+            .unwrap()
+            .to_string();
+        assert!(verify_password(&argon2, "s3cret"));
+        assert!(!verify_password(&argon2, "guess"));
+
+        let bcrypt = bcrypt::hash("s3cret", 4).unwrap();
+        assert!(verify_password(&bcrypt, "s3cret"));
+        assert!(!verify_password(&bcrypt, "guess"));
+
+        assert!(!verify_password("s3cret", "s3cret"));
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_submission_goes_to_the_credential_webhook() {
+        let dir = test_dir("auth-submission");
+        let (url, mut requests) = spawn_webhook_recorder(200).await;
+        let hash = bcrypt::hash("s3cret", 4).unwrap();
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "",
+            &format!(
+                r#"
+                [[credentials]]
+                username = "billing"
+                password_hash = "{}"
+                webhook = {{ url = "{}", api_key = "12345" }}
+                "#,
+                hash, url
+            ),
+        );
+        let mut listener = ListenerConfig::new("127.0.0.1:0".to_string(), ListenerMode::Smtp);
+        listener.auth = true;
+        let addr = start_test_listeners(config, vec![listener]).await[0];
+        let cert = test_cert_path(&dir);
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        let ehlo = client.command("EHLO app.example.org").await;
+        assert!(!ehlo.lines.iter().any(|line| line.starts_with("AUTH")));
+        assert_eq!(client.command("AUTH PLAIN AGJpbGxpbmcAczNjcmV0").await.code, 538);
+        assert_eq!(client.command("MAIL FROM:<app@example.org>").await.code, 530);
+
+        assert_eq!(client.command("STARTTLS").await.code, 220);
+        let mut client = client.start_tls(&cert).await;
+        let ehlo = client.command("EHLO app.example.org").await;
+        assert!(ehlo.lines.iter().any(|line| line == "AUTH PLAIN LOGIN"));
+        assert_eq!(client.command("MAIL FROM:<app@example.org>").await.code, 530);
+
+        let wrong = STANDARD.encode("\0billing\0guess");
+        assert_eq!(client.command(&format!("AUTH PLAIN {}", wrong)).await.code, 535);
+
+        assert_eq!(client.command("AUTH LOGIN").await.code, 334);
+        assert_eq!(client.command(&STANDARD.encode("billing")).await.code, 334);
+        assert_eq!(client.command(&STANDARD.encode("s3cret")).await.code, 235);
+
+        // Any recipient will do; the credential decides where the mail goes
+        assert_eq!(client.command("MAIL FROM:<app@example.org>").await.code, 250);
+        assert_eq!(
+            client.command("RCPT TO:<customer@elsewhere.example>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: Invoice\r\n\r\nHello\r\n.\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+
+        let body = tokio::time::timeout(Duration::from_secs(10), requests.recv())
+            .await
+            .expect("Credential webhook was never called")
+            .unwrap();
+        assert!(body.contains("name=\"auth-user\""));
+        assert!(body.contains("billing"));
+    }
+
+    #[tokio::test]
+    async fn test_auth_parameter_is_only_trusted_after_login() {
+        let dir = test_dir("auth-parameter");
+        let (url, mut requests) = spawn_webhook_recorder(200).await;
+        let hash = bcrypt::hash("s3cret", 4).unwrap();
+        let config = test_config(
+            &url,
+            &dir,
+            "",
+            &format!(
+                r#"
+                [[credentials]]
+                username = "billing"
+                password_hash = "{}"
+                webhook = {{ url = "{}", api_key = "12345" }}
+                "#,
+                hash, url
+            ),
+        );
+        let mut listener = ListenerConfig::new("127.0.0.1:0".to_string(), ListenerMode::Smtp);
+        listener.auth = true;
+        let anyone = ListenerConfig::new("127.0.0.1:0".to_string(), ListenerMode::Smtp);
+        let addrs = start_test_listeners(config, vec![listener, anyone]).await;
+        let cert = test_cert_path(&dir);
+
+        // A stranger's claim is accepted but not believed
+        let (mut client, _) = SmtpClient::connect(addrs[1]).await;
+        assert_eq!(client.command("EHLO relay.example.net").await.code, 250);
+        assert_eq!(
+            client
+                .command("MAIL FROM:<app@example.org> AUTH=ceo@example.org")
+                .await
+                .code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: Stranger\r\n\r\nHello\r\n.\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+        let body = tokio::time::timeout(Duration::from_secs(10), requests.recv())
+            .await
+            .expect("Webhook was never called")
+            .unwrap();
+        assert!(!body.contains("name=\"auth-identity\""));
+
+        let (mut client, _) = SmtpClient::connect(addrs[0]).await;
+        assert_eq!(client.command("EHLO app.example.org").await.code, 250);
+        assert_eq!(client.command("STARTTLS").await.code, 220);
+        let mut client = client.start_tls(&cert).await;
+        assert_eq!(client.command("EHLO app.example.org").await.code, 250);
+        let login = STANDARD.encode("\0billing\0s3cret");
+        assert_eq!(client.command(&format!("AUTH PLAIN {}", login)).await.code, 235);
+        assert_eq!(
+            client
+                .command("MAIL FROM:<app@example.org> AUTH=<>")
+                .await
+                .code,
+            250
+        );
+        assert_eq!(client.command("RSET").await.code, 250);
+        assert_eq!(
+            client
+                .command("MAIL FROM:<app@example.org> AUTH=clerk+2Bbills@example.org")
+                .await
+                .code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<customer@elsewhere.example>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: Invoice\r\n\r\nHello\r\n.\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+        let body = tokio::time::timeout(Duration::from_secs(10), requests.recv())
+            .await
+            .expect("Credential webhook was never called")
+            .unwrap();
+        assert!(body.contains("name=\"auth-identity\""));
+        assert!(body.contains("clerk+bills@example.org"));
+    }
+}
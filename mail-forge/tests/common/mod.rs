@@ -0,0 +1,517 @@
+#![allow(dead_code)]
+
+use async_trait::async_trait;
+use mail_forge::config::{Config, ListenerConfig, ListenerMode};
+use mail_forge::dns::Resolver;
+use mail_forge::smtp;
+use mail_forge::spool::{Envelope, Spool};
+use std::collections::{HashMap, HashSet};
+use std::env::temp_dir;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Starts a minimal HTTP endpoint that answers every request with `status`.
+pub async fn spawn_webhook_stub(status: u16) -> String {
+    spawn_webhook_recorder(status).await.0
+}
+
+/// Starts an endpoint that takes connections and never answers them.
+pub async fn spawn_silent_webhook() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind stub");
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = listener.accept().await {
+            held.push(socket);
+        }
+    });
+    format!("http://{}/", addr)
+}
+
+/// Like `spawn_webhook_stub`, but also hands over each request body.
+pub async fn spawn_webhook_recorder(status: u16) -> (String, mpsc::UnboundedReceiver<String>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind stub");
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let mut reader = BufReader::new(socket);
+                let mut content_length = 0;
+                let mut chunked = false;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let lower = line.to_ascii_lowercase();
+                    if let Some(value) = lower.strip_prefix("content-length:") {
+                        content_length = value.trim().parse().unwrap_or(0);
+                    }
+                    if lower.starts_with("transfer-encoding:") && lower.contains("chunked") {
+                        chunked = true;
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+
+                let mut body = Vec::new();
+                if chunked {
+                    loop {
+                        let mut size = String::new();
+                        reader.read_line(&mut size).await.unwrap();
+                        let size = usize::from_str_radix(size.trim(), 16).unwrap_or(0);
+                        let mut chunk = vec![0; size + 2];
+                        reader.read_exact(&mut chunk).await.unwrap();
+                        if size == 0 {
+                            break;
+                        }
+                        body.extend_from_slice(&chunk[..size]);
+                    }
+                } else {
+                    body.resize(content_length, 0);
+                    reader.read_exact(&mut body).await.unwrap();
+                }
+                let _ = sender.send(String::from_utf8_lossy(&body).into_owned());
+
+                let response = format!(
+                    "HTTP/1.1 {} Stub\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = reader.get_mut().write_all(response.as_bytes()).await;
+            });
+        }
+    });
+
+    (format!("http://{}/inbound", addr), receiver)
+}
+
+/// An empty scratch directory unique to this test binary.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = temp_dir().join(format!("mail-forge-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes a self-signed certificate for `server_name` and returns its (cert, key) paths.
+pub fn write_test_cert(dir: &Path, server_name: &str) -> (PathBuf, PathBuf) {
+    let certified = rcgen::generate_simple_self_signed(vec![server_name.to_string()]).unwrap();
+    let cert_path = dir.join(format!("{}.crt", server_name));
+    let key_path = dir.join(format!("{}.key", server_name));
+    std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified.signing_key.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+/// A config routing `*@example.com` to `url`, with `server_extra` appended to
+/// `[server]` and `extra` appended as further sections.
+pub fn test_config(url: &str, dir: &Path, server_extra: &str, extra: &str) -> Config {
+    let (cert_path, key_path) = write_test_cert(dir, "mx.example.com");
+    toml::from_str(&format!(
+        r#"
+        [server]
+        smtp_bind_address = "127.0.0.1:0"
+        hostname = "mx.example.com"
+        cert_path = "{}"
+        key_path = "{}"
+        max_size = 1048576
+        {}
+
+        [spool]
+        dir = "{}"
+
+        [webhooks]
+        "*@example.com" = {{ url = "{}", api_key = "12345" }}
+
+        {}
+        "#,
+        cert_path.display(),
+        key_path.display(),
+        server_extra,
+        dir.join("spool").display(),
+        url,
+        extra
+    ))
+    .expect("Failed to parse test config")
+}
+
+/// Like `test_config`, but every delivery fails, so accepted messages stay
+/// in the spool for `deliver_and_load_spooled` to look at.
+pub async fn spooling_test_config(dir: &Path) -> Config {
+    test_config(&spawn_webhook_stub(503).await, dir, "", "")
+}
+
+/// Sends `message` from `mail_from` to shane@example.com and returns the
+/// envelope and message the server spooled under `dir` for it.
+pub async fn deliver_and_load_spooled(
+    client: &mut SmtpClient,
+    dir: &Path,
+    mail_from: &str,
+    message: &[u8],
+) -> (Envelope, String) {
+    assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+    let reply = client.command(&format!("MAIL FROM:<{}>", mail_from)).await;
+    assert_eq!(reply.code, 250);
+    let reply = client.command("RCPT TO:<shane@example.com>").await;
+    assert_eq!(reply.code, 250);
+    assert_eq!(client.command("DATA").await.code, 354);
+    client.send(message).await;
+    client.send(b".\r\n").await;
+    let reply = client.read_reply().await;
+    assert_eq!(reply.code, 250);
+    let id = reply.text().rsplit(' ').next().unwrap().to_string();
+
+    let (spool, _receiver) = Spool::open(dir.join("spool")).unwrap();
+    let (envelope, message) = spool.load(&id).unwrap();
+    (envelope, String::from_utf8(message).unwrap())
+}
+
+/// Serves `config` on an ephemeral port and returns its address.
+pub async fn start_test_server(config: Config) -> SocketAddr {
+    start_test_server_with_resolver(config, StubResolver::default()).await
+}
+
+/// Like `start_test_server`, answering DNS lookups from `resolver`.
+pub async fn start_test_server_with_resolver(config: Config, resolver: StubResolver) -> SocketAddr {
+    let mut listener = ListenerConfig::new("127.0.0.1:0".to_string(), ListenerMode::Smtp);
+    listener.require_tls = config.server.require_tls;
+    start_test_listeners_with_resolver(config, vec![listener], resolver).await[0]
+}
+
+/// Serves `config` on one ephemeral port per listener (their addresses are
+/// ignored) and returns the bound addresses in the same order.
+pub async fn start_test_listeners(
+    config: Config,
+    listeners: Vec<ListenerConfig>,
+) -> Vec<SocketAddr> {
+    start_test_listeners_with_resolver(config, listeners, StubResolver::default()).await
+}
+
+/// Like `start_test_listeners`, answering DNS lookups from `resolver`.
+pub async fn start_test_listeners_with_resolver(
+    config: Config,
+    listeners: Vec<ListenerConfig>,
+    resolver: StubResolver,
+) -> Vec<SocketAddr> {
+    let mut bound = Vec::new();
+    let mut addrs = Vec::new();
+    for listener in listeners {
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        addrs.push(socket.local_addr().unwrap());
+        bound.push((socket, listener));
+    }
+    tokio::spawn(async move {
+        smtp::server::serve_with_resolver(bound, config, Arc::new(resolver))
+            .await
+            .unwrap();
+    });
+    addrs
+}
+
+/// Serves `config` on a single ephemeral listener of the given mode.
+pub async fn start_test_listener(config: Config, mode: ListenerMode) -> SocketAddr {
+    let listener = ListenerConfig::new("127.0.0.1:0".to_string(), mode);
+    start_test_listeners(config, vec![listener]).await[0]
+}
+
+/// Answers DNS lookups from records set up by the test; everything else
+/// doesn't exist.
+#[derive(Default)]
+pub struct StubResolver {
+    txt: HashMap<String, Vec<String>>,
+    a: HashMap<String, Vec<Ipv4Addr>>,
+    aaaa: HashMap<String, Vec<Ipv6Addr>>,
+    mx: HashMap<String, Vec<String>>,
+    ptr: HashMap<IpAddr, Vec<String>>,
+    /// Names whose lookups fail as if the name servers were unreachable.
+    failing: HashSet<String>,
+}
+
+impl StubResolver {
+    pub fn txt(mut self, name: &str, record: &str) -> Self {
+        self.txt
+            .entry(key(name))
+            .or_default()
+            .push(record.to_string());
+        self
+    }
+
+    pub fn a(mut self, name: &str, ip: &str) -> Self {
+        self.a
+            .entry(key(name))
+            .or_default()
+            .push(ip.parse().unwrap());
+        self
+    }
+
+    pub fn aaaa(mut self, name: &str, ip: &str) -> Self {
+        self.aaaa
+            .entry(key(name))
+            .or_default()
+            .push(ip.parse().unwrap());
+        self
+    }
+
+    pub fn mx(mut self, name: &str, exchange: &str) -> Self {
+        self.mx
+            .entry(key(name))
+            .or_default()
+            .push(exchange.to_string());
+        self
+    }
+
+    pub fn ptr(mut self, ip: &str, name: &str) -> Self {
+        self.ptr
+            .entry(ip.parse().unwrap())
+            .or_default()
+            .push(name.to_string());
+        self
+    }
+
+    pub fn failing(mut self, name: &str) -> Self {
+        self.failing.insert(key(name));
+        self
+    }
+
+    fn lookup<T: Clone>(
+        &self,
+        records: &HashMap<String, Vec<T>>,
+        name: &str,
+    ) -> std::io::Result<Vec<T>> {
+        let name = key(name);
+        if self.failing.contains(&name) {
+            return Err(std::io::Error::other("SERVFAIL"));
+        }
+        Ok(records.get(&name).cloned().unwrap_or_default())
+    }
+}
+
+fn key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[async_trait]
+impl Resolver for StubResolver {
+    async fn txt(&self, name: &str) -> std::io::Result<Vec<String>> {
+        self.lookup(&self.txt, name)
+    }
+
+    async fn a(&self, name: &str) -> std::io::Result<Vec<Ipv4Addr>> {
+        self.lookup(&self.a, name)
+    }
+
+    async fn aaaa(&self, name: &str) -> std::io::Result<Vec<Ipv6Addr>> {
+        self.lookup(&self.aaaa, name)
+    }
+
+    async fn mx(&self, name: &str) -> std::io::Result<Vec<String>> {
+        self.lookup(&self.mx, name)
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> std::io::Result<Vec<String>> {
+        Ok(self.ptr.get(&ip).cloned().unwrap_or_default())
+    }
+}
+
+/// The certificate `test_config` writes for the server into `dir`.
+pub fn test_cert_path(dir: &Path) -> PathBuf {
+    dir.join("mx.example.com.crt")
+}
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Just enough of an SMTP client to script a session.
+pub struct SmtpClient {
+    stream: BufReader<Box<dyn Stream>>,
+}
+
+impl SmtpClient {
+    /// Connects and returns the client along with the greeting.
+    pub async fn connect(addr: SocketAddr) -> (Self, Reply) {
+        let stream = TcpStream::connect(addr).await.expect("Failed to connect");
+        let mut client = Self {
+            stream: BufReader::new(Box::new(stream)),
+        };
+        let greeting = client.read_reply().await;
+        (client, greeting)
+    }
+
+    /// Connects and sends `header` first, as a load balancer speaking the
+    /// PROXY protocol would.
+    pub async fn connect_via_proxy(addr: SocketAddr, header: &[u8]) -> (Self, Reply) {
+        let mut stream = TcpStream::connect(addr).await.expect("Failed to connect");
+        stream.write_all(header).await.unwrap();
+        let mut client = Self {
+            stream: BufReader::new(Box::new(stream)),
+        };
+        let greeting = client.read_reply().await;
+        (client, greeting)
+    }
+
+    /// Connects with implicit TLS, trusting only `cert_path`.
+    pub async fn connect_tls(addr: SocketAddr, cert_path: &Path) -> (Self, Reply) {
+        Self::connect_tls_as(addr, cert_path, "mx.example.com").await
+    }
+
+    /// Connects with implicit TLS, asking for `server_name` via SNI.
+    pub async fn connect_tls_as(
+        addr: SocketAddr,
+        cert_path: &Path,
+        server_name: &str,
+    ) -> (Self, Reply) {
+        let stream = TcpStream::connect(addr).await.expect("Failed to connect");
+        let stream = tls_handshake(stream, cert_path, server_name, None).await;
+        let mut client = Self {
+            stream: BufReader::new(Box::new(stream)),
+        };
+        let greeting = client.read_reply().await;
+        (client, greeting)
+    }
+
+    /// Connects with implicit TLS, authenticating with a client certificate.
+    pub async fn connect_tls_with_client_cert(
+        addr: SocketAddr,
+        cert_path: &Path,
+        client_cert: (&Path, &Path),
+    ) -> (Self, Reply) {
+        let stream = TcpStream::connect(addr).await.expect("Failed to connect");
+        let stream = tls_handshake(stream, cert_path, "mx.example.com", Some(client_cert)).await;
+        let mut client = Self {
+            stream: BufReader::new(Box::new(stream)),
+        };
+        let greeting = client.read_reply().await;
+        (client, greeting)
+    }
+
+    /// Upgrades the connection after the server accepted STARTTLS.
+    pub async fn start_tls(self, cert_path: &Path) -> Self {
+        assert!(
+            self.stream.buffer().is_empty(),
+            "Unread data before TLS handshake"
+        );
+        let stream =
+            tls_handshake(self.stream.into_inner(), cert_path, "mx.example.com", None).await;
+        Self {
+            stream: BufReader::new(Box::new(stream)),
+        }
+    }
+
+    pub async fn send(&mut self, data: &[u8]) {
+        self.stream.get_mut().write_all(data).await.unwrap();
+    }
+
+    /// Closes our sending side, as a client that hangs up without QUIT would.
+    pub async fn shutdown(&mut self) {
+        self.stream.get_mut().shutdown().await.unwrap();
+    }
+
+    pub async fn command(&mut self, command: &str) -> Reply {
+        self.send(format!("{}\r\n", command).as_bytes()).await;
+        self.read_reply().await
+    }
+
+    /// Reads one (possibly multi-line) reply; a closed connection reads as code 0.
+    pub async fn read_reply(&mut self) -> Reply {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return Reply { code: 0, lines };
+            }
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .unwrap_or(0);
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            lines.push(line.get(4..).unwrap_or("").trim_end().to_string());
+            if last {
+                return Reply { code, lines };
+            }
+        }
+    }
+}
+
+/// Whether a TLS handshake for `server_name` succeeds when trusting only `cert_path`.
+pub async fn tls_handshake_succeeds(addr: SocketAddr, cert_path: &Path, server_name: &str) -> bool {
+    let stream = TcpStream::connect(addr).await.expect("Failed to connect");
+    try_tls_handshake(stream, cert_path, server_name, None)
+        .await
+        .is_ok()
+}
+
+async fn tls_handshake<S>(
+    stream: S,
+    cert_path: &Path,
+    server_name: &str,
+    client_cert: Option<(&Path, &Path)>,
+) -> impl Stream
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    try_tls_handshake(stream, cert_path, server_name, client_cert)
+        .await
+        .expect("TLS handshake failed")
+}
+
+/// Handshakes trusting only `cert_path`, presenting `client_cert` (a cert
+/// and key path) if given.
+async fn try_tls_handshake<S>(
+    stream: S,
+    cert_path: &Path,
+    server_name: &str,
+    client_cert: Option<(&Path, &Path)>,
+) -> std::io::Result<impl Stream>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(cert_path).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let client_config = match client_cert {
+        Some((cert_path, key_path)) => {
+            let chain = CertificateDer::pem_file_iter(cert_path)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let key = PrivateKeyDer::from_pem_file(key_path).unwrap();
+            builder.with_client_auth_cert(chain, key).unwrap()
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+    TlsConnector::from(Arc::new(client_config))
+        .connect(server_name, stream)
+        .await
+}
+
+#[derive(Debug)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn text(&self) -> String {
+        self.lines.join("\n")
+    }
+}
@@ -0,0 +1,56 @@
+
+#[cfg(test)]
+mod tests {
+
+    use mail_forge::smtp::data::{read_data, DataOutcome};
+    use mail_forge::spool::Envelope;
+    use mail_forge::webhook::client::preview_payload;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_data_is_unstuffed_and_binary_safe() {
+        let mut input: &[u8] = b"Subject: Dots\r\n\r\n..leading dot\r\n..\r\n\xe9t\xe9\r\n.\r\nNOOP\r\n";
+
+        let outcome = read_data(&mut input, 1024, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(
+            outcome,
+            DataOutcome::Complete(b"Subject: Dots\r\n\r\n.leading dot\r\n.\r\n\xe9t\xe9\r\n".to_vec())
+        );
+
+        // Only the DATA block was consumed
+        assert_eq!(input, b"NOOP\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_data_is_drained() {
+        let mut input: &[u8] = b"0123456789\r\n0123456789\r\n.\r\nQUIT\r\n";
+
+        let outcome = read_data(&mut input, 16, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(outcome, DataOutcome::TooLarge);
+        assert_eq!(input, b"QUIT\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_size_limit_counts_unstuffed_bytes() {
+        let mut input: &[u8] = b"..\r\n.\r\n";
+
+        let outcome = read_data(&mut input, 3, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(outcome, DataOutcome::Complete(b".\r\n".to_vec()));
+    }
+
+    #[test]
+    fn test_legacy_charset_body_is_decoded() {
+        let raw_email = b"Subject: Caf\xe9\r\n\
+            Content-Type: text/plain; charset=iso-8859-1\r\n\
+            Content-Transfer-Encoding: 8bit\r\n\
+            \r\n\
+            Caf\xe9 au lait\r\n";
+
+        let payload = preview_payload(
+            "shane@textify.asgcom.net",
+            raw_email,
+            &Envelope::new(String::new(), Vec::new()),
+        ).unwrap();
+        assert_eq!(payload["body-plain"], "Café au lait\r\n");
+    }
+}
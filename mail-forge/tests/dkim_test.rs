@@ -0,0 +1,219 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{
+        spawn_webhook_recorder, start_test_server_with_resolver, test_config, test_dir, SmtpClient,
+        StubResolver,
+    };
+    use aws_lc_rs::rand::SystemRandom;
+    use aws_lc_rs::signature::{Ed25519KeyPair, KeyPair};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use mail_forge::dkim::{verify, DkimResult, DkimSignature};
+    use sha2::{Digest, Sha256};
+    use std::time::Duration;
+
+    // The example from RFC 8463 appendix A, signed with both algorithms
+    const SIGNED_MESSAGE: &str = "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r
+ d=football.example.com; i=@football.example.com;\r
+ q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r
+ subject : date : message-id : from : subject : date;\r
+ bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r
+ b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus\r
+ Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==\r
+DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed;\r
+ d=football.example.com; i=@football.example.com;\r
+ q=dns/txt; s=test; t=1528637909; h=from : to : subject :\r
+ date : message-id : from : subject : date;\r
+ bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r
+ b=F45dVWDfMbQDGHJFlXUNB2HKfbCeLRyhDXgFpEL8GwpsRe0IeIixNTe3\r
+ DhCVlUrSjV4BwcVcOF6+FF3Zo9Rpo1tFOeS9mPYQTnGdaSGsgeefOsk2Jz\r
+ dA+L10TeYt9BgDfQNZtKdN1WO//KgIqXP7OdEFE4LjFYNcUxZQ4FADY+8=\r
+From: Joe SixPack <joe@football.example.com>\r
+To: Suzie Q <suzie@shopping.example.net>\r
+Subject: Is dinner ready?\r
+Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r
+Message-ID: <20030712040037.46341.5F8J@football.example.com>\r
+\r
+Hi.\r
+\r
+We lost the game.  Are you hungry yet?\r
+\r
+Joe.\r
+";
+
+    const ED25519_KEY: &str = "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+    const RSA_KEY: &str = "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDkHlOQoBTzWRiGs5V6NpP3idY6Wk08a5qhdR6wy5bdOKb2jLQiY/J16JYi0Qvx/byYzCNb3W91y3FutACDfzwQ/BC/e/8uBsCR+yz1Lxj+PL6lHvqMKrM3rG4hstT5QjvHO9PzoxZyVYLzBfO2EeC3Ip3G+2kryOTIKT+l/K4w3QIDAQAB";
+
+    fn football_keys() -> StubResolver {
+        StubResolver::default()
+            .txt("brisbane._domainkey.football.example.com", ED25519_KEY)
+            .txt("test._domainkey.football.example.com", RSA_KEY)
+    }
+
+    fn results(signatures: &[DkimSignature]) -> Vec<(&str, DkimResult)> {
+        signatures
+            .iter()
+            .map(|s| (s.selector.as_str(), s.result))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_rfc8463_signatures_pass() {
+        let signatures = verify(&football_keys(), SIGNED_MESSAGE.as_bytes()).await;
+        assert_eq!(
+            results(&signatures),
+            vec![("brisbane", DkimResult::Pass), ("test", DkimResult::Pass)]
+        );
+        assert_eq!(signatures[0].domain, "football.example.com");
+        assert_eq!(signatures[0].reason, None);
+
+        // Bare LF line endings are read as CRLF
+        let unix = SIGNED_MESSAGE.replace("\r\n", "\n");
+        let signatures = verify(&football_keys(), unix.as_bytes()).await;
+        assert_eq!(
+            results(&signatures),
+            vec![("brisbane", DkimResult::Pass), ("test", DkimResult::Pass)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_altered_messages_fail() {
+        let body = SIGNED_MESSAGE.replace("We lost", "We won");
+        let signatures = verify(&football_keys(), body.as_bytes()).await;
+        assert_eq!(signatures[0].result, DkimResult::Fail);
+        assert_eq!(signatures[0].reason.as_deref(), Some("body hash mismatch"));
+
+        let subject = SIGNED_MESSAGE.replace("dinner ready", "lunch ready");
+        let signatures = verify(&football_keys(), subject.as_bytes()).await;
+        assert_eq!(
+            results(&signatures),
+            vec![("brisbane", DkimResult::Fail), ("test", DkimResult::Fail)]
+        );
+        assert_eq!(
+            signatures[1].reason.as_deref(),
+            Some("signature did not verify")
+        );
+
+        // Relaxed canonicalization doesn't care about whitespace changes
+        let spaced = SIGNED_MESSAGE.replace("Subject: Is", "Subject:   Is");
+        let signatures = verify(&football_keys(), spaced.as_bytes()).await;
+        assert_eq!(signatures[0].result, DkimResult::Pass);
+    }
+
+    #[tokio::test]
+    async fn test_key_problems() {
+        // No key published
+        let resolver = StubResolver::default().txt("test._domainkey.football.example.com", RSA_KEY);
+        let signatures = verify(&resolver, SIGNED_MESSAGE.as_bytes()).await;
+        assert_eq!(
+            results(&signatures),
+            vec![
+                ("brisbane", DkimResult::PermError),
+                ("test", DkimResult::Pass)
+            ]
+        );
+
+        // Key revoked
+        let resolver = StubResolver::default()
+            .txt(
+                "brisbane._domainkey.football.example.com",
+                "v=DKIM1; k=ed25519; p=",
+            )
+            .txt("test._domainkey.football.example.com", RSA_KEY);
+        let signatures = verify(&resolver, SIGNED_MESSAGE.as_bytes()).await;
+        assert_eq!(signatures[0].result, DkimResult::PermError);
+        assert_eq!(signatures[0].reason.as_deref(), Some("key revoked"));
+
+        // Key of the wrong type
+        let resolver = StubResolver::default()
+            .txt("brisbane._domainkey.football.example.com", RSA_KEY)
+            .txt("test._domainkey.football.example.com", RSA_KEY);
+        let signatures = verify(&resolver, SIGNED_MESSAGE.as_bytes()).await;
+        assert_eq!(signatures[0].result, DkimResult::PermError);
+
+        // DNS failure
+        let resolver = StubResolver::default()
+            .failing("brisbane._domainkey.football.example.com")
+            .txt("test._domainkey.football.example.com", RSA_KEY);
+        let signatures = verify(&resolver, SIGNED_MESSAGE.as_bytes()).await;
+        assert_eq!(signatures[0].result, DkimResult::TempError);
+    }
+
+    #[tokio::test]
+    async fn test_simple_canonicalization() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let record = format!(
+            "v=DKIM1; k=ed25519; p={}",
+            STANDARD.encode(key_pair.public_key().as_ref())
+        );
+        let resolver = StubResolver::default().txt("mail._domainkey.example.org", &record);
+
+        let from = "From: Alice <alice@example.org>\r\n";
+        let subject = "Subject: Simple\r\n";
+        // Trailing empty lines are ignored by the simple body canonicalization
+        let body = "Hello\r\n\r\n\r\n";
+        let body_hash = STANDARD.encode(Sha256::digest(b"Hello\r\n"));
+        let unsigned = format!(
+            "DKIM-Signature: v=1; a=ed25519-sha256; c=simple/simple; d=example.org;\r\n \
+             s=mail; h=from:subject; bh={}; b=",
+            body_hash
+        );
+        let data = format!("{}{}{}", from, subject, unsigned);
+        let signature = key_pair.sign(&Sha256::digest(data.as_bytes()));
+        let message = format!(
+            "{}{}\r\n{}{}\r\n{}",
+            unsigned,
+            STANDARD.encode(signature.as_ref()),
+            from,
+            subject,
+            body
+        );
+
+        let signatures = verify(&resolver, message.as_bytes()).await;
+        assert_eq!(results(&signatures), vec![("mail", DkimResult::Pass)]);
+
+        // Simple canonicalization does care about whitespace changes
+        let spaced = message.replace("Subject: Simple", "Subject:  Simple");
+        let signatures = verify(&resolver, spaced.as_bytes()).await;
+        assert_eq!(signatures[0].result, DkimResult::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_results_are_passed_to_the_webhook() {
+        let dir = test_dir("dkim-payload");
+        let (url, mut requests) = spawn_webhook_recorder(200).await;
+        let config = test_config(&url, &dir, "", "");
+        let addr = start_test_server_with_resolver(config, football_keys()).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client
+                .command("MAIL FROM:<joe@football.example.com>")
+                .await
+                .code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(SIGNED_MESSAGE.as_bytes()).await;
+        client.send(b".\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+
+        let body = tokio::time::timeout(Duration::from_secs(10), requests.recv())
+            .await
+            .expect("Webhook was never called")
+            .unwrap();
+        assert!(body.contains("name=\"dkim\""));
+        assert!(body.contains(
+            r#"[{"domain":"football.example.com","result":"pass","selector":"brisbane"},{"domain":"football.example.com","result":"pass","selector":"test"}]"#
+        ));
+    }
+}
@@ -0,0 +1,323 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{
+        deliver_and_load_spooled, spawn_webhook_recorder, spawn_webhook_stub,
+        spooling_test_config, start_test_listeners_with_resolver, start_test_server_with_resolver,
+        test_config, test_dir, SmtpClient, StubResolver,
+    };
+    use mail_forge::config::{ListenerConfig, ListenerMode};
+    use mail_forge::dkim::{DkimResult, DkimSignature};
+    use mail_forge::dmarc::{verify, DmarcPolicy, DmarcResult};
+    use mail_forge::spf::{SpfResult, SpfVerdict};
+    use std::time::Duration;
+
+    fn message(from: &str) -> Vec<u8> {
+        format!("From: {}\r\nSubject: DMARC\r\n\r\nHello\r\n", from).into_bytes()
+    }
+
+    fn dkim_pass(domain: &str) -> Vec<DkimSignature> {
+        vec![DkimSignature {
+            domain: domain.to_string(),
+            selector: "mail".to_string(),
+            result: DkimResult::Pass,
+            reason: None,
+        }]
+    }
+
+    fn spf(result: SpfResult, domain: &str) -> SpfVerdict {
+        SpfVerdict {
+            result,
+            domain: domain.to_string(),
+            helo: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_alignment() {
+        let resolver = StubResolver::default()
+            .txt("_dmarc.example.org", "v=DMARC1; p=reject")
+            .txt(
+                "_dmarc.example.net",
+                "v=DMARC1; p=quarantine; adkim=s; aspf=s",
+            );
+        let from = message("Alice <alice@example.org>");
+
+        // A signature from a subdomain is aligned in relaxed mode
+        let verdict = verify(&resolver, &from, None, &dkim_pass("mail.example.org")).await;
+        assert_eq!(verdict.result, DmarcResult::Pass);
+        assert!(verdict.dkim_aligned);
+        assert_eq!(verdict.domain, "example.org");
+
+        let bounce = spf(SpfResult::Pass, "bounces.example.org");
+        let verdict = verify(&resolver, &from, Some(&bounce), &[]).await;
+        assert_eq!(verdict.result, DmarcResult::Pass);
+        assert!(verdict.spf_aligned);
+        assert!(!verdict.dkim_aligned);
+
+        // Passing for someone else's domain doesn't count
+        let other = spf(SpfResult::Pass, "example.com");
+        let verdict = verify(&resolver, &from, Some(&other), &dkim_pass("example.com")).await;
+        assert_eq!(verdict.result, DmarcResult::Fail);
+        assert_eq!(verdict.policy, Some(DmarcPolicy::Reject));
+
+        // Neither does failing for the right one
+        let failed = spf(SpfResult::Fail, "example.org");
+        let verdict = verify(&resolver, &from, Some(&failed), &[]).await;
+        assert_eq!(verdict.result, DmarcResult::Fail);
+
+        // Strict alignment wants the very same domain
+        let from = message("bob@example.net");
+        let bounce = spf(SpfResult::Pass, "bounces.example.net");
+        let verdict = verify(
+            &resolver,
+            &from,
+            Some(&bounce),
+            &dkim_pass("mail.example.net"),
+        )
+        .await;
+        assert_eq!(verdict.result, DmarcResult::Fail);
+        assert_eq!(verdict.policy, Some(DmarcPolicy::Quarantine));
+        let verdict = verify(&resolver, &from, None, &dkim_pass("example.net")).await;
+        assert_eq!(verdict.result, DmarcResult::Pass);
+    }
+
+    #[tokio::test]
+    async fn test_private_suffixes_separate_organizations() {
+        let resolver = StubResolver::default().txt("_dmarc.a.github.io", "v=DMARC1; p=reject");
+        let from = message("alice@a.github.io");
+
+        // Neighbours under a private suffix are different organizations
+        let verdict = verify(&resolver, &from, None, &dkim_pass("b.github.io")).await;
+        assert_eq!(verdict.result, DmarcResult::Fail);
+        assert!(!verdict.dkim_aligned);
+        let verdict = verify(&resolver, &from, None, &dkim_pass("mail.a.github.io")).await;
+        assert_eq!(verdict.result, DmarcResult::Pass);
+
+        // Policy discovery stops at the registered domain, not the suffix
+        let verdict = verify(&resolver, &message("a@news.a.github.io"), None, &[]).await;
+        assert_eq!(verdict.record.unwrap().domain, "a.github.io");
+        let verdict = verify(&resolver, &message("a@b.github.io"), None, &[]).await;
+        assert_eq!(verdict.result, DmarcResult::None);
+    }
+
+    #[tokio::test]
+    async fn test_policy_discovery() {
+        let resolver = StubResolver::default()
+            .txt("_dmarc.example.org", "v=DMARC1; p=reject; sp=none; pct=100")
+            .txt("_dmarc.example.co.uk", "v=DMARC1; p=reject")
+            .txt("_dmarc.example.net", "v=DMARC1; p=reject; pct=0")
+            .txt("_dmarc.example.com", "v=spf1 -all")
+            .txt("_dmarc.broken.example", "v=DMARC1; p=sometimes");
+
+        let verdict = verify(&resolver, &message("a@example.org"), None, &[]).await;
+        assert_eq!(verdict.policy, Some(DmarcPolicy::Reject));
+        let record = verdict.record.unwrap();
+        assert_eq!(record.domain, "example.org");
+        assert_eq!(record.subdomain_policy, DmarcPolicy::None);
+
+        // Subdomains fall back to the organizational domain's sp=
+        let verdict = verify(&resolver, &message("a@news.example.org"), None, &[]).await;
+        assert_eq!(verdict.result, DmarcResult::Fail);
+        assert_eq!(verdict.domain, "news.example.org");
+        assert_eq!(verdict.policy, Some(DmarcPolicy::None));
+
+        let verdict = verify(&resolver, &message("a@shop.example.co.uk"), None, &[]).await;
+        assert_eq!(verdict.policy, Some(DmarcPolicy::Reject));
+        assert_eq!(verdict.record.unwrap().domain, "example.co.uk");
+
+        // Messages left out by pct= get the next less severe policy
+        let verdict = verify(&resolver, &message("a@example.net"), None, &[]).await;
+        assert_eq!(verdict.policy, Some(DmarcPolicy::Quarantine));
+
+        let verdict = verify(&resolver, &message("a@example.com"), None, &[]).await;
+        assert_eq!(verdict.result, DmarcResult::None);
+        assert_eq!(verdict.record, None);
+
+        let verdict = verify(&resolver, &message("a@broken.example"), None, &[]).await;
+        assert_eq!(verdict.result, DmarcResult::PermError);
+    }
+
+    #[tokio::test]
+    async fn test_unusable_from_headers_and_dns_failures() {
+        let resolver = StubResolver::default()
+            .txt("_dmarc.example.org", "v=DMARC1; p=reject")
+            .failing("_dmarc.example.net");
+
+        let verdict = verify(&resolver, &message("a@example.net"), None, &[]).await;
+        assert_eq!(verdict.result, DmarcResult::TempError);
+
+        let two_domains = message("a@example.org, b@example.com");
+        let verdict = verify(&resolver, &two_domains, None, &[]).await;
+        assert_eq!(verdict.result, DmarcResult::PermError);
+
+        // Several authors in one domain are fine
+        let two_authors = message("a@example.org, b@example.org");
+        let verdict = verify(&resolver, &two_authors, None, &dkim_pass("example.org")).await;
+        assert_eq!(verdict.result, DmarcResult::Pass);
+
+        let no_from = b"Subject: Anonymous\r\n\r\nHello\r\n";
+        let verdict = verify(&resolver, no_from, None, &[]).await;
+        assert_eq!(verdict.result, DmarcResult::PermError);
+
+        let two_froms = b"From: a@example.org\r\nFrom: b@example.org\r\n\r\nHello\r\n";
+        let verdict = verify(&resolver, two_froms, None, &[]).await;
+        assert_eq!(verdict.result, DmarcResult::PermError);
+    }
+
+    #[tokio::test]
+    async fn test_authentication_results_header() {
+        let dir = test_dir("dmarc-header");
+        let config = spooling_test_config(&dir).await;
+        let resolver = StubResolver::default()
+            .txt("example.org", "v=spf1 ip4:127.0.0.0/8 -all")
+            .txt("_dmarc.example.org", "v=DMARC1; p=reject");
+        let addr = start_test_server_with_resolver(config, resolver).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        let (_, message) = deliver_and_load_spooled(
+            &mut client,
+            &dir,
+            "sender@example.org",
+            b"Authentication-Results: mx.example.com; dmarc=pass\r\nAuthentication-Results: elsewhere.example; none\r\nFrom: sender@example.org\r\n\r\nHello\r\n",
+        )
+        .await;
+        assert!(message.starts_with("Received: "));
+        assert!(message.contains(
+            "Authentication-Results: mx.example.com;\r\n\
+             \tspf=pass smtp.mailfrom=example.org;\r\n\
+             \tdmarc=pass (p=reject dis=reject) header.from=example.org\r\n"
+        ));
+        // The one claiming to be ours was forged, the other one wasn't
+        assert!(!message.contains("mx.example.com; dmarc=pass"));
+        assert!(message.ends_with(
+            "Authentication-Results: elsewhere.example; none\r\nFrom: sender@example.org\r\n\r\nHello\r\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verdict_is_passed_to_the_webhook() {
+        let dir = test_dir("dmarc-payload");
+        let (url, mut requests) = spawn_webhook_recorder(200).await;
+        let config = test_config(&url, &dir, "", "");
+        let resolver = StubResolver::default().txt("_dmarc.example.org", "v=DMARC1; p=none");
+        let addr = start_test_server_with_resolver(config, resolver).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client
+            .send(b"From: sender@example.org\r\n\r\nHello\r\n.\r\n")
+            .await;
+        assert_eq!(client.read_reply().await.code, 250);
+
+        let body = tokio::time::timeout(Duration::from_secs(10), requests.recv())
+            .await
+            .expect("Webhook was never called")
+            .unwrap();
+        assert!(body.contains("name=\"dmarc\""));
+        assert!(body.contains(r#""result":"fail""#));
+        assert!(body.contains(
+            r#""record":{"dkim_alignment":"relaxed","domain":"example.org","percent":100,"policy":"none","spf_alignment":"relaxed","subdomain_policy":"none"}"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reject_policy_is_honored_when_asked() {
+        let dir = test_dir("dmarc-reject");
+        let mut config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        config
+            .webhooks
+            .get_mut("*@example.com")
+            .unwrap()
+            .honor_dmarc_reject = true;
+        let resolver = StubResolver::default()
+            .txt("_dmarc.example.org", "v=DMARC1; p=reject")
+            .txt("_dmarc.example.net", "v=DMARC1; p=quarantine");
+        let addr = start_test_server_with_resolver(config, resolver).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client
+            .send(b"From: sender@example.org\r\n\r\nHello\r\n.\r\n")
+            .await;
+        let reply = client.read_reply().await;
+        assert_eq!(reply.code, 550);
+        assert_eq!(
+            reply.text(),
+            "5.7.1 Rejected by the DMARC policy of example.org"
+        );
+
+        // Quarantine is left to the webhook
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.net>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client
+            .send(b"From: sender@example.net\r\n\r\nHello\r\n.\r\n")
+            .await;
+        assert_eq!(client.read_reply().await.code, 250);
+    }
+
+    #[tokio::test]
+    async fn test_lmtp_leaves_dmarc_to_the_mta() {
+        let dir = test_dir("dmarc-lmtp");
+        let (url, mut requests) = spawn_webhook_recorder(200).await;
+        let mut config = test_config(&url, &dir, "", "");
+        config
+            .webhooks
+            .get_mut("*@example.com")
+            .unwrap()
+            .honor_dmarc_reject = true;
+        let resolver = StubResolver::default().txt("_dmarc.example.org", "v=DMARC1; p=reject");
+        let listener = ListenerConfig::new("127.0.0.1:0".to_string(), ListenerMode::Lmtp);
+        let addr = start_test_listeners_with_resolver(config, vec![listener], resolver).await[0];
+
+        // There is no SPF result over LMTP, so unsigned mail can't pass
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("LHLO mta.example.com").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client
+            .send(b"From: sender@example.org\r\n\r\nHello\r\n.\r\n")
+            .await;
+        assert_eq!(client.read_reply().await.code, 250);
+
+        let body = tokio::time::timeout(Duration::from_secs(10), requests.recv())
+            .await
+            .expect("Webhook was never called")
+            .unwrap();
+        assert!(!body.contains("name=\"dmarc\""));
+    }
+}
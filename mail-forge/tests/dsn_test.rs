@@ -0,0 +1,306 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{spawn_webhook_stub, start_test_server, test_config, test_dir, SmtpClient};
+    use chrono::Utc;
+    use mail_forge::dsn::{build_failure_report, notify_failure};
+    use mail_forge::smtp::params::{BodyType, Notify, Ret};
+    use mail_forge::spool::Envelope;
+    use std::time::Duration;
+
+    const MESSAGE: &[u8] = b"Subject: Hello\r\nFrom: sender@example.org\r\n\r\nSecret body\r\n";
+
+    fn failed_envelope() -> Envelope {
+        let mut envelope = Envelope::new(
+            "sender@example.org".to_string(),
+            vec!["shane@example.com".to_string()],
+        );
+        let recipient = &mut envelope.recipients[0];
+        recipient.last_error = Some("Webhook rejected the message with 400".to_string());
+        recipient.status = Some("5.0.0".to_string());
+        envelope
+    }
+
+    #[test]
+    fn test_failure_report_follows_dsn_parameters() {
+        let mut envelope = failed_envelope();
+        envelope.ret = Some(Ret::Full);
+        envelope.envid = Some("QQ314159".to_string());
+        envelope.recipients[0].orcpt = Some("rfc822;Shane@Example.com".to_string());
+
+        let report = build_failure_report("mx.example.com", &envelope, MESSAGE, Utc::now())
+            .expect("Expected a report");
+        let report = String::from_utf8(report).unwrap();
+
+        assert!(report.contains("To: <sender@example.org>\r\n"));
+        assert!(report.contains("Content-Type: multipart/report; report-type=delivery-status;"));
+        assert!(report.contains("Content-Type: message/delivery-status\r\n"));
+        assert!(report.contains("Reporting-MTA: dns; mx.example.com\r\n"));
+        assert!(report.contains("Original-Envelope-Id: QQ314159\r\n"));
+        assert!(report.contains("Final-Recipient: rfc822; shane@example.com\r\n"));
+        assert!(report.contains("Original-Recipient: rfc822; Shane@Example.com\r\n"));
+        assert!(report.contains("Action: failed\r\nStatus: 5.0.0\r\n"));
+
+        // RET=FULL returns the whole message
+        assert!(report.contains("Content-Type: message/rfc822\r\n"));
+        assert!(report.contains("Secret body"));
+
+        // Without RET only the headers go back
+        envelope.ret = None;
+        let report = build_failure_report("mx.example.com", &envelope, MESSAGE, Utc::now())
+            .expect("Expected a report");
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("Content-Type: text/rfc822-headers\r\n"));
+        assert!(report.contains("Subject: Hello\r\n"));
+        assert!(!report.contains("Secret body"));
+    }
+
+    #[test]
+    fn test_binary_messages_only_return_their_headers() {
+        let mut envelope = failed_envelope();
+        envelope.ret = Some(Ret::Full);
+        envelope.body = Some(BodyType::BinaryMime);
+        let mut message = b"Subject: Binary\r\nContent-Type: application/octet-stream\r\n\r\n".to_vec();
+        message.extend_from_slice(&[0, 0xff, b'\n', b'.', 0x80]);
+
+        let report = build_failure_report("mx.example.com", &envelope, &message, Utc::now())
+            .expect("Expected a report");
+        assert!(report.is_ascii());
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("Content-Type: text/rfc822-headers\r\n"));
+        assert!(report.contains("Subject: Binary\r\n"));
+        assert!(!report.contains("Content-Type: message/rfc822"));
+    }
+
+    #[test]
+    fn test_no_report_when_nobody_wants_one() {
+        let mut envelope = failed_envelope();
+        envelope.recipients[0].notify = Some(vec![Notify::Never]);
+        assert!(build_failure_report("mx.example.com", &envelope, MESSAGE, Utc::now()).is_none());
+
+        envelope.recipients[0].notify = Some(vec![Notify::Delay]);
+        assert!(build_failure_report("mx.example.com", &envelope, MESSAGE, Utc::now()).is_none());
+
+        // Bounces are never bounced
+        let mut envelope = failed_envelope();
+        envelope.mail_from = String::new();
+        assert!(build_failure_report("mx.example.com", &envelope, MESSAGE, Utc::now()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_message_bounces_to_outbox() {
+        let dir = test_dir("dsn-outbox");
+        let outbox = dir.join("outbox");
+        let config = test_config(
+            &spawn_webhook_stub(400).await,
+            &dir,
+            "",
+            &format!("[dsn]\noutbox_dir = \"{}\"", outbox.display()),
+        );
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        let reply = client
+            .command("MAIL FROM:<sender@example.org> RET=HDRS ENVID=abc+2B1")
+            .await;
+        assert_eq!(reply.code, 250);
+        assert_eq!(
+            client
+                .command("RCPT TO:<shane@example.com> NOTIFY=FAILURE")
+                .await
+                .code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(MESSAGE).await;
+        client.send(b".\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+
+        let mut bounce = None;
+        for _ in 0..50 {
+            let written = std::fs::read_dir(&outbox).ok().and_then(|mut entries| {
+                entries.find_map(|entry| {
+                    let path = entry.ok()?.path();
+                    (path.extension()? == "eml").then_some(path)
+                })
+            });
+            if let Some(path) = written {
+                bounce = Some(std::fs::read_to_string(path).unwrap());
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let bounce = bounce.expect("No bounce was written to the outbox");
+        assert!(bounce.contains("To: <sender@example.org>\r\n"));
+        assert!(bounce.contains("Original-Envelope-Id: abc+1\r\n"));
+        assert!(bounce.contains("Final-Recipient: rfc822; shane@example.com\r\n"));
+        assert!(bounce.contains("Status: 5.0.0\r\n"));
+        assert!(!bounce.contains("Secret body"));
+    }
+
+    #[tokio::test]
+    async fn test_silent_smarthost_falls_back_to_outbox() {
+        let dir = test_dir("dsn-silent-smarthost");
+        let outbox = dir.join("outbox");
+        // Takes the connection and never says a word
+        let smarthost = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let smarthost_addr = smarthost.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = smarthost.accept().await {
+                held.push(socket);
+            }
+        });
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "command_timeout_secs = 1",
+            &format!(
+                "[dsn]\nsmarthost = \"{}\"\noutbox_dir = \"{}\"",
+                smarthost_addr,
+                outbox.display()
+            ),
+        );
+
+        let envelope = failed_envelope();
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            notify_failure(&config, &envelope, MESSAGE),
+        )
+        .await
+        .expect("Bounce relaying never gave up on the smarthost");
+
+        let written = std::fs::read_dir(&outbox).unwrap().count();
+        assert_eq!(written, 1);
+    }
+
+    #[tokio::test]
+    async fn test_each_report_gets_its_own_outbox_file() {
+        let dir = test_dir("dsn-outbox-names");
+        let outbox = dir.join("outbox");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "",
+            &format!("[dsn]\noutbox_dir = \"{}\"", outbox.display()),
+        );
+
+        // Recipients of the same message dead-lettered at different times
+        let envelope = failed_envelope();
+        notify_failure(&config, &envelope, MESSAGE).await;
+        notify_failure(&config, &envelope, MESSAGE).await;
+
+        let mut names: Vec<String> = std::fs::read_dir(&outbox)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 2);
+        for name in names {
+            assert!(name.starts_with(&format!("{}-", envelope.id)));
+            assert!(name.ends_with(".eml"));
+        }
+    }
+
+    /// A smarthost that advertises `extensions` and reports the MAIL
+    /// commands it is sent.
+    async fn spawn_smarthost(
+        extensions: &'static [&'static str],
+    ) -> (
+        std::net::SocketAddr,
+        tokio::sync::mpsc::UnboundedReceiver<String>,
+    ) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let mut socket = BufReader::new(socket);
+                    socket.get_mut().write_all(b"220 smarthost\r\n").await.unwrap();
+                    let mut in_data = false;
+                    let mut line = String::new();
+                    while socket.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        let reply = if in_data {
+                            in_data = line != ".\r\n";
+                            (!in_data).then(|| "250 Queued\r\n".to_string())
+                        } else if line.starts_with("EHLO") {
+                            let mut reply = String::from("250-smarthost\r\n");
+                            for extension in extensions {
+                                reply.push_str(&format!("250-{}\r\n", extension));
+                            }
+                            reply.push_str("250 HELP\r\n");
+                            Some(reply)
+                        } else if line.starts_with("MAIL") {
+                            sender.send(line.trim_end().to_string()).unwrap();
+                            Some("250 OK\r\n".to_string())
+                        } else if line.starts_with("DATA") {
+                            in_data = true;
+                            Some("354 Go ahead\r\n".to_string())
+                        } else if line.starts_with("QUIT") {
+                            Some("221 Bye\r\n".to_string())
+                        } else {
+                            Some("250 OK\r\n".to_string())
+                        };
+                        if let Some(reply) = reply {
+                            socket.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (addr, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_relay_declares_what_the_bounce_needs() {
+        let dir = test_dir("dsn-relay-params");
+        let outbox = dir.join("outbox");
+        let webhook = spawn_webhook_stub(200).await;
+        let dsn_config = |smarthost: std::net::SocketAddr| {
+            test_config(
+                &webhook,
+                &dir,
+                "",
+                &format!(
+                    "[dsn]\nsmarthost = \"{}\"\noutbox_dir = \"{}\"",
+                    smarthost,
+                    outbox.display()
+                ),
+            )
+        };
+        let (smarthost, mut mails) = spawn_smarthost(&["8BITMIME", "SMTPUTF8"]).await;
+        let config = dsn_config(smarthost);
+
+        let envelope = failed_envelope();
+        notify_failure(&config, &envelope, MESSAGE).await;
+        assert_eq!(mails.recv().await.unwrap(), "MAIL FROM:<>");
+
+        // The whole of an 8-bit internationalized message goes back
+        let mut envelope = failed_envelope();
+        envelope.smtputf8 = true;
+        envelope.ret = Some(Ret::Full);
+        let message = "Subject: Grüße\r\nFrom: sender@example.org\r\n\r\nBis später\r\n";
+        notify_failure(&config, &envelope, message.as_bytes()).await;
+        assert_eq!(
+            mails.recv().await.unwrap(),
+            "MAIL FROM:<> BODY=8BITMIME SMTPUTF8"
+        );
+        assert!(!outbox.exists());
+
+        // A smarthost that can't take it leaves the bounce to the outbox
+        let (smarthost, mut mails) = spawn_smarthost(&[]).await;
+        let config = dsn_config(smarthost);
+        notify_failure(&config, &envelope, message.as_bytes()).await;
+        assert!(mails.try_recv().is_err());
+        assert_eq!(std::fs::read_dir(&outbox).unwrap().count(), 1);
+    }
+}
@@ -1,27 +1,58 @@
-
-#[cfg(test)]
-mod tests {
-
-    use std::fs;
-    use mail_forge::{config, webhook};
-    use mail_forge::webhook::mapping::get_webhook_for_recipient;
-
-    #[tokio::test]
-    async fn test_forward_multiple_emails() {
-        for entry in fs::read_dir("tests/emails").expect("Failed to read email test directory") {
-            let path = entry.expect("Failed to read entry").path();
-            let raw_email = fs::read_to_string(&path).expect("Failed to read email file");
-            let config = config::Config::load("../config.toml").expect("Failed to parse config.toml");
-
-            let webhook = get_webhook_for_recipient("shane@textify.asgcom.net", &config.webhooks).expect("Failed to get webhook");
-
-            // Assert that the webhook forward succeeds
-            match webhook::client::forward_to_webhook(&webhook, &raw_email).await {
-                Ok(_) => println!("Forwarding succeeded for email at: {:?}", path),
-                Err(e) => {
-                    panic!("Forwarding failed for email at {:?}: {}", path, e);
-                }
-            }
-        }
-    }
-}
\ No newline at end of file
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use mail_forge::{config, webhook};
+    use mail_forge::spool::Envelope;
+    use mail_forge::webhook::mapping::get_webhook_for_recipient;
+    use crate::common::{spawn_silent_webhook, spawn_webhook_stub, test_config, test_dir};
+    use mail_forge::webhook::client::DeliveryError;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_forward_multiple_emails() {
+        let url = spawn_webhook_stub(200).await;
+
+        for entry in fs::read_dir("tests/emails").expect("Failed to read email test directory") {
+            let path = entry.expect("Failed to read entry").path();
+            let raw_email = fs::read(&path).expect("Failed to read email file");
+            let config = config::Config::load("../config.toml").expect("Failed to parse config.toml");
+
+            let mut webhook = get_webhook_for_recipient("shane@textify.asgcom.net", &config.webhooks).expect("Failed to get webhook").clone();
+            webhook.url = url.clone();
+
+            // Assert that the webhook forward succeeds
+            let envelope = Envelope::new(String::new(), vec!["shane@textify.asgcom.net".to_string()]);
+            match webhook::client::forward_to_webhook("shane@textify.asgcom.net", &webhook, &raw_email, &envelope).await {
+                Ok(_) => println!("Forwarding succeeded for email at: {:?}", path),
+                Err(e) => {
+                    panic!("Forwarding failed for email at {:?}: {}", path, e);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_silent_webhook_times_out_as_a_network_error() {
+        let dir = test_dir("webhook-timeout");
+        let config = test_config(&spawn_silent_webhook().await, &dir, "", "");
+        let mut webhook = get_webhook_for_recipient("shane@example.com", &config.webhooks)
+            .expect("Failed to get webhook")
+            .clone();
+        webhook.timeout_secs = 1;
+
+        let envelope = Envelope::new(String::new(), vec!["shane@example.com".to_string()]);
+        let raw_email = b"Subject: Test\r\n\r\nTest\r\n";
+        let delivery = webhook::client::forward_to_webhook("shane@example.com", &webhook, raw_email, &envelope);
+        let result = tokio::time::timeout(Duration::from_secs(10), delivery)
+            .await
+            .expect("Delivery never gave up on the webhook");
+
+        // Retried like any other webhook that can't be reached
+        let error = result.unwrap_err();
+        assert!(matches!(error, DeliveryError::Network(_)), "{}", error);
+        assert!(error.is_retryable());
+    }
+}
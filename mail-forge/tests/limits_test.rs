@@ -0,0 +1,211 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{spawn_webhook_stub, start_test_server, test_config, test_dir, SmtpClient};
+    use mail_forge::config::LimitsConfig;
+    use mail_forge::smtp::limits::{Limiter, SessionRefused};
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_sessions_are_capped_per_network_and_in_total() {
+        let config: LimitsConfig =
+            toml::from_str("max_sessions = 3\nmax_sessions_per_ip = 2\nipv4_prefix = 24").unwrap();
+        let limiter = Arc::new(Limiter::new(config));
+
+        let first = limiter.open_session(ip("192.0.2.1")).unwrap();
+        let _second = limiter.open_session(ip("192.0.2.2")).unwrap();
+        assert_eq!(
+            limiter.open_session(ip("192.0.2.3")).err(),
+            Some(SessionRefused::TooManyFromNetwork)
+        );
+
+        let _third = limiter.open_session(ip("198.51.100.1")).unwrap();
+        assert_eq!(
+            limiter.open_session(ip("198.51.100.2")).err(),
+            Some(SessionRefused::TooManySessions)
+        );
+
+        // Closing a session frees its place
+        drop(first);
+        assert!(limiter.open_session(ip("192.0.2.3")).is_ok());
+    }
+
+    #[test]
+    fn test_connections_count_before_their_client_is_known() {
+        let config: LimitsConfig =
+            toml::from_str("max_sessions = 2\nmax_sessions_per_ip = 1").unwrap();
+        let limiter = Arc::new(Limiter::new(config));
+
+        // Neither client has said who it is yet
+        let mut waiting = limiter.open_connection().unwrap();
+        let other = limiter.open_connection().unwrap();
+        assert_eq!(
+            limiter.open_session(ip("198.51.100.1")).err(),
+            Some(SessionRefused::TooManySessions)
+        );
+
+        waiting.assign(ip("192.0.2.1")).unwrap();
+        drop(other);
+        let mut late = limiter.open_connection().unwrap();
+        assert_eq!(
+            late.assign(ip("192.0.2.1")).err(),
+            Some(SessionRefused::TooManyFromNetwork)
+        );
+    }
+
+    #[test]
+    fn test_addresses_are_grouped_by_prefix() {
+        let config = LimitsConfig::default();
+        assert_eq!(config.network(ip("192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(
+            config.network(ip("2001:db8:1:2:aaaa::1")),
+            ip("2001:db8:1:2::")
+        );
+
+        let config: LimitsConfig = toml::from_str("ipv4_prefix = 0").unwrap();
+        assert_eq!(config.network(ip("192.0.2.1")), ip("0.0.0.0"));
+    }
+
+    #[tokio::test]
+    async fn test_extra_session_from_the_same_address_gets_421() {
+        let dir = test_dir("limits-sessions");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "",
+            "[limits]\nmax_sessions_per_ip = 1",
+        );
+        let addr = start_test_server(config).await;
+
+        let (mut first, greeting) = SmtpClient::connect(addr).await;
+        assert_eq!(greeting.code, 220);
+
+        let (_, refused) = SmtpClient::connect(addr).await;
+        assert_eq!(refused.code, 421);
+        assert!(refused.text().starts_with("4.7.0"));
+
+        assert_eq!(first.command("QUIT").await.code, 221);
+        let mut greeting = SmtpClient::connect(addr).await.1;
+        for _ in 0..20 {
+            if greeting.code == 220 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            greeting = SmtpClient::connect(addr).await.1;
+        }
+        assert_eq!(greeting.code, 220);
+    }
+
+    #[tokio::test]
+    async fn test_message_and_recipient_rates_are_limited() {
+        let dir = test_dir("limits-rates");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "",
+            "[limits]\nmessages_per_minute = 1\nrecipients_per_minute = 2",
+        );
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        client.command("EHLO client.example.org").await;
+        assert_eq!(client.command("MAIL FROM:<a@example.org>").await.code, 250);
+        assert_eq!(client.command("RCPT TO:<one@example.com>").await.code, 250);
+        assert_eq!(client.command("RCPT TO:<two@example.com>").await.code, 250);
+
+        let reply = client.command("RCPT TO:<three@example.com>").await;
+        assert_eq!(reply.code, 451);
+        assert!(reply.text().starts_with("4.7.1"));
+
+        assert_eq!(client.command("RSET").await.code, 250);
+        let reply = client.command("MAIL FROM:<a@example.org>").await;
+        assert_eq!(reply.code, 451);
+        assert!(reply.text().starts_with("4.7.1"));
+    }
+
+    #[tokio::test]
+    async fn test_recipients_beyond_max_recipients_get_452() {
+        let dir = test_dir("limits-recipients");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "",
+            "[limits]\nmax_recipients = 2",
+        );
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        client.command("EHLO client.example.org").await;
+        assert_eq!(client.command("MAIL FROM:<a@example.org>").await.code, 250);
+        assert_eq!(client.command("RCPT TO:<one@example.com>").await.code, 250);
+        assert_eq!(client.command("RCPT TO:<two@example.com>").await.code, 250);
+
+        let reply = client.command("RCPT TO:<three@example.com>").await;
+        assert_eq!(reply.code, 452);
+        assert!(reply.text().starts_with("4.5.3"));
+
+        // The message still goes to the recipients that were accepted
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: Test\r\n\r\nTest\r\n.\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+    }
+
+    #[tokio::test]
+    async fn test_zero_max_recipients_is_unlimited() {
+        let dir = test_dir("limits-recipients-unlimited");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "",
+            "[limits]\nmax_recipients = 0",
+        );
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        client.command("EHLO client.example.org").await;
+        assert_eq!(client.command("MAIL FROM:<a@example.org>").await.code, 250);
+        for n in 0..150 {
+            let reply = client.command(&format!("RCPT TO:<r{}@example.com>", n)).await;
+            assert_eq!(reply.code, 250);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_is_closed_after_max_messages() {
+        let dir = test_dir("limits-messages");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "",
+            "[limits]\nmax_messages_per_session = 2",
+        );
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        client.command("EHLO client.example.org").await;
+        for _ in 0..2 {
+            assert_eq!(client.command("MAIL FROM:<a@example.org>").await.code, 250);
+            assert_eq!(client.command("RCPT TO:<shane@example.com>").await.code, 250);
+            assert_eq!(client.command("DATA").await.code, 354);
+            client.send(b"Subject: Test\r\n\r\nTest\r\n.\r\n").await;
+            assert_eq!(client.read_reply().await.code, 250);
+        }
+
+        let reply = client.command("MAIL FROM:<a@example.org>").await;
+        assert_eq!(reply.code, 421);
+        assert_eq!(client.read_reply().await.code, 0);
+
+        // A new session starts counting afresh
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        client.command("EHLO client.example.org").await;
+        assert_eq!(client.command("MAIL FROM:<a@example.org>").await.code, 250);
+    }
+}
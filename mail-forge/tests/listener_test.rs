@@ -0,0 +1,133 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{
+        spawn_webhook_stub, start_test_listener, start_test_listeners, test_cert_path, test_config,
+        test_dir, SmtpClient,
+    };
+    use mail_forge::config::{Config, ListenerConfig, ListenerMode};
+
+    #[tokio::test]
+    async fn test_implicit_tls_listener() {
+        let dir = test_dir("listener-smtps");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        let addr = start_test_listener(config, ListenerMode::Smtps).await;
+
+        let (mut client, greeting) = SmtpClient::connect_tls(addr, &test_cert_path(&dir)).await;
+        assert_eq!(greeting.code, 220);
+
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: Test\r\n\r\nTest\r\n.\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+    }
+
+    #[tokio::test]
+    async fn test_listeners_share_one_routing_table() {
+        let dir = test_dir("listener-many");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+
+        let mut smtp = ListenerConfig::new("127.0.0.1:0".to_string(), ListenerMode::Smtp);
+        smtp.banner = Some("Welcome to the test MX".to_string());
+        let lmtp = ListenerConfig::new("127.0.0.1:0".to_string(), ListenerMode::Lmtp);
+        let addrs = start_test_listeners(config, vec![smtp, lmtp]).await;
+
+        let (mut client, greeting) = SmtpClient::connect(addrs[0]).await;
+        assert_eq!(greeting.text(), "mx.example.com Welcome to the test MX");
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+
+        let (mut client, greeting) = SmtpClient::connect(addrs[1]).await;
+        assert!(greeting.text().contains("LMTP"));
+        assert_eq!(client.command("LHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.net>").await.code,
+            550
+        );
+    }
+
+    #[tokio::test]
+    async fn test_require_tls_refuses_plaintext_mail() {
+        let dir = test_dir("listener-require-tls");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+
+        let mut listener = ListenerConfig::new("127.0.0.1:0".to_string(), ListenerMode::Smtp);
+        listener.require_tls = true;
+        let addr = start_test_listeners(config, vec![listener]).await[0];
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        let reply = client.command("MAIL FROM:<sender@example.org>").await;
+        assert_eq!(reply.code, 530);
+        assert!(reply.text().starts_with("5.7.0"));
+    }
+
+    #[test]
+    fn test_listeners_fall_back_to_server_addresses() {
+        let parse = |listeners: &str| -> Config {
+            toml::from_str(&format!(
+                r#"
+                [server]
+                smtp_bind_address = "0.0.0.0:25"
+                lmtp_bind_address = "127.0.0.1:24"
+                hostname = "mx.example.com"
+                cert_path = "cert.pem"
+                key_path = "key.pem"
+                max_size = 1024
+
+                [webhooks]
+
+                {}
+                "#,
+                listeners
+            ))
+            .unwrap()
+        };
+
+        let listeners = parse("").effective_listeners();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].address, "0.0.0.0:25");
+        assert_eq!(listeners[0].mode, ListenerMode::Smtp);
+        assert_eq!(listeners[1].address, "127.0.0.1:24");
+        assert_eq!(listeners[1].mode, ListenerMode::Lmtp);
+
+        let listeners = parse(
+            r#"
+            [[listeners]]
+            address = "0.0.0.0:465"
+            mode = "smtps"
+            banner = "Submission"
+            "#,
+        )
+        .effective_listeners();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].mode, ListenerMode::Smtps);
+        assert_eq!(listeners[0].banner.as_deref(), Some("Submission"));
+        assert!(!listeners[0].require_tls);
+    }
+}
@@ -0,0 +1,91 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{
+        spawn_webhook_stub, start_test_listener, test_config, test_dir, SmtpClient,
+    };
+    use mail_forge::config::ListenerMode;
+
+    #[tokio::test]
+    async fn test_lmtp_replies_once_per_recipient() {
+        let dir = test_dir("lmtp-replies");
+        let routes = format!(
+            "\"*@rejected.example\" = {{ url = \"{}\", api_key = \"12345\" }}\n\
+             \"*@down.example\" = {{ url = \"{}\", api_key = \"12345\" }}",
+            spawn_webhook_stub(400).await,
+            spawn_webhook_stub(503).await
+        );
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", &routes);
+        let addr = start_test_listener(config, ListenerMode::Lmtp).await;
+
+        let (mut client, greeting) = SmtpClient::connect(addr).await;
+        assert_eq!(greeting.code, 220);
+        assert!(greeting.text().contains("LMTP"));
+
+        // LMTP has its own greeting command
+        assert_eq!(client.command("EHLO client.example.org").await.code, 500);
+        assert_eq!(client.command("LHLO client.example.org").await.code, 250);
+
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<info@rejected.example>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<info@down.example>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: Test\r\n\r\nTest\r\n.\r\n").await;
+
+        let delivered = client.read_reply().await;
+        assert_eq!(delivered.code, 250, "{}", delivered.text());
+        assert!(delivered.text().contains("<shane@example.com>"));
+        let rejected = client.read_reply().await;
+        assert_eq!(rejected.code, 550, "{}", rejected.text());
+        assert!(rejected.text().contains("<info@rejected.example>"));
+        let deferred = client.read_reply().await;
+        assert_eq!(deferred.code, 451, "{}", deferred.text());
+        assert!(deferred.text().contains("<info@down.example>"));
+
+        // The session is ready for the next transaction
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lmtp_oversized_message_fails_every_recipient() {
+        let dir = test_dir("lmtp-oversized");
+        let mut config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        config.server.max_size = 16;
+        let addr = start_test_listener(config, ListenerMode::Lmtp).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("LHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(client.command("RCPT TO:<a@example.com>").await.code, 250);
+        assert_eq!(client.command("RCPT TO:<b@example.com>").await.code, 250);
+        assert_eq!(client.command("DATA").await.code, 354);
+        client
+            .send(b"Subject: Too long for the limit\r\n\r\nTest\r\n.\r\n")
+            .await;
+
+        assert_eq!(client.read_reply().await.code, 552);
+        assert_eq!(client.read_reply().await.code, 552);
+        assert_eq!(client.command("NOOP").await.code, 250);
+    }
+}
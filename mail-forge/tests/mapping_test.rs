@@ -0,0 +1,89 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{test_config, test_dir};
+    use mail_forge::spool::Envelope;
+    use mail_forge::webhook::client::preview_payload;
+    use mail_forge::webhook::mapping::get_webhook_for_recipient;
+
+    #[test]
+    fn test_idn_domains_match_after_punycode_normalisation() {
+        let dir = test_dir("mapping-idn");
+        let config = test_config(
+            "http://127.0.0.1:9/default",
+            &dir,
+            "",
+            r#""*@bücher.example" = { url = "http://127.0.0.1:9/books", api_key = "12345" }
+            "jörg@xn--mnchen-3ya.example" = { url = "http://127.0.0.1:9/jorg", api_key = "12345" }"#,
+        );
+        let url = |recipient: &str| {
+            get_webhook_for_recipient(recipient, &config.webhooks)
+                .map(|webhook| webhook.url.clone())
+        };
+
+        assert_eq!(
+            url("anna@bücher.example").unwrap(),
+            "http://127.0.0.1:9/books"
+        );
+        assert_eq!(
+            url("anna@xn--bcher-kva.example").unwrap(),
+            "http://127.0.0.1:9/books"
+        );
+        assert_eq!(
+            url("anna@BÜCHER.example").unwrap(),
+            "http://127.0.0.1:9/books"
+        );
+        assert_eq!(
+            url("jörg@münchen.example").unwrap(),
+            "http://127.0.0.1:9/jorg"
+        );
+        assert!(url("anna@notbücher.example").is_none());
+    }
+
+    #[test]
+    fn test_wildcards_cover_subdomains() {
+        let dir = test_dir("mapping-subdomains");
+        let config = test_config(
+            "http://127.0.0.1:9/default",
+            &dir,
+            "",
+            r#""*@eu.example.com" = { url = "http://127.0.0.1:9/eu", api_key = "12345" }"#,
+        );
+        let url = |recipient: &str| {
+            get_webhook_for_recipient(recipient, &config.webhooks)
+                .map(|webhook| webhook.url.clone())
+        };
+
+        assert_eq!(url("shane@example.com").unwrap(), "http://127.0.0.1:9/default");
+        assert_eq!(
+            url("shane@mail.example.com").unwrap(),
+            "http://127.0.0.1:9/default"
+        );
+        // The most specific wildcard wins
+        assert_eq!(url("shane@eu.example.com").unwrap(), "http://127.0.0.1:9/eu");
+        assert_eq!(
+            url("shane@paris.eu.example.com").unwrap(),
+            "http://127.0.0.1:9/eu"
+        );
+        // Only whole labels count
+        assert!(url("shane@notexample.com").is_none());
+    }
+
+    #[test]
+    fn test_payload_carries_unicode_and_ascii_addresses() {
+        let raw_email =
+            "From: Jörg <jörg@xn--mnchen-3ya.example>\r\nSubject: Grüße\r\n\r\nHallo\r\n";
+
+        let payload = preview_payload(
+            "anna@xn--bcher-kva.example",
+            raw_email.as_bytes(),
+            &Envelope::new(String::new(), Vec::new()),
+        ).unwrap();
+        assert_eq!(payload["to"], "anna@bücher.example");
+        assert_eq!(payload["to-ascii"], "anna@xn--bcher-kva.example");
+        assert_eq!(payload["from"], "jörg@münchen.example");
+        assert_eq!(payload["from-ascii"], "jörg@xn--mnchen-3ya.example");
+    }
+}
@@ -0,0 +1,87 @@
+#[cfg(test)]
+mod tests {
+
+    use mail_forge::smtp::params::{
+        parse_mail_from, parse_rcpt_to, BodyType, Notify, ParamError, Ret,
+    };
+
+    #[test]
+    fn test_mail_parameters_are_parsed() {
+        let mail_from = parse_mail_from(
+            "FROM:<sender@example.org> SIZE=1024 BODY=8BITMIME RET=HDRS ENVID=QQ+2B314159 SMTPUTF8",
+        )
+        .unwrap();
+
+        assert_eq!(mail_from.address, "sender@example.org");
+        assert_eq!(mail_from.size, Some(1024));
+        assert_eq!(mail_from.body, Some(BodyType::EightBitMime));
+        assert_eq!(mail_from.ret, Some(Ret::Hdrs));
+        assert_eq!(mail_from.envid.as_deref(), Some("QQ+314159"));
+        assert!(mail_from.smtputf8);
+
+        let mail_from = parse_mail_from("FROM:<app@example.org> AUTH=e+3Dmc2@example.org").unwrap();
+        assert_eq!(mail_from.auth.as_deref(), Some("e=mc2@example.org"));
+        let mail_from = parse_mail_from("FROM:<app@example.org> AUTH=<>").unwrap();
+        assert_eq!(mail_from.auth.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_null_sender_and_quoted_local_part() {
+        assert_eq!(parse_mail_from("FROM:<>").unwrap().address, "");
+        assert_eq!(
+            parse_mail_from("from: <\"odd > name\"@example.org>")
+                .unwrap()
+                .address,
+            "\"odd > name\"@example.org"
+        );
+        assert_eq!(
+            parse_rcpt_to("TO:<@relay.example.net:user@example.com>")
+                .unwrap()
+                .address,
+            "user@example.com"
+        );
+    }
+
+    #[test]
+    fn test_paths_without_brackets_are_tolerated() {
+        let mail_from = parse_mail_from("FROM:sender@example.org SIZE=1024").unwrap();
+        assert_eq!(mail_from.address, "sender@example.org");
+        assert_eq!(mail_from.size, Some(1024));
+        assert_eq!(
+            parse_mail_from("FROM: sender@example.org").unwrap().address,
+            "sender@example.org"
+        );
+        assert_eq!(
+            parse_rcpt_to("TO:user@example.com").unwrap().address,
+            "user@example.com"
+        );
+    }
+
+    #[test]
+    fn test_rcpt_parameters_are_parsed() {
+        let rcpt_to = parse_rcpt_to(
+            "TO:<user@example.com> NOTIFY=FAILURE,DELAY ORCPT=rfc822;user+2Bx@example.com",
+        )
+        .unwrap();
+
+        assert_eq!(rcpt_to.notify, Some(vec![Notify::Failure, Notify::Delay]));
+        assert_eq!(rcpt_to.orcpt.as_deref(), Some("rfc822;user+x@example.com"));
+    }
+
+    #[test]
+    fn test_invalid_parameters_are_rejected() {
+        assert_eq!(
+            parse_mail_from("FROM:<a@example.org> XFOO=1").unwrap_err(),
+            ParamError::Unrecognized("XFOO".to_string())
+        );
+        assert!(parse_mail_from("FROM:<a@example.org> SIZE=big")
+            .unwrap_err()
+            .reply()
+            .starts_with("501 "));
+        assert!(parse_mail_from("FROM:").is_err());
+        assert!(parse_mail_from("FROM:<a@example.org").is_err());
+        assert!(parse_rcpt_to("TO:<>").is_err());
+        assert!(parse_rcpt_to("TO:<a@example.com> NOTIFY=NEVER,FAILURE").is_err());
+        assert!(parse_rcpt_to("TO:<a@example.com> ORCPT=rfc822;a+zz").is_err());
+    }
+}
@@ -0,0 +1,177 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{
+        deliver_and_load_spooled, spawn_webhook_recorder, spawn_webhook_stub, spooling_test_config,
+        start_test_listeners, test_config, test_dir, SmtpClient,
+    };
+    use mail_forge::config::{ListenerConfig, ListenerMode};
+    use mail_forge::smtp::proxy::read_proxy_header;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+    fn proxy_listener() -> ListenerConfig {
+        let mut listener = ListenerConfig::new("127.0.0.1:0".to_string(), ListenerMode::Smtp);
+        listener.proxy_protocol = true;
+        listener
+    }
+
+    #[tokio::test]
+    async fn test_v1_header_is_parsed() {
+        let mut input: &[u8] = b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 25\r\nEHLO";
+        let client = read_proxy_header(&mut input).await.unwrap();
+        assert_eq!(client, Some("203.0.113.7:51234".parse().unwrap()));
+        // The SMTP conversation that follows is left alone
+        assert_eq!(input, b"EHLO");
+
+        let mut input: &[u8] = b"PROXY TCP6 2001:db8::7 2001:db8::1 40000 25\r\n";
+        let client = read_proxy_header(&mut input).await.unwrap();
+        assert_eq!(client, Some("[2001:db8::7]:40000".parse().unwrap()));
+
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut input).await.unwrap(), None);
+
+        let mut input: &[u8] = b"PROXY TCP4 2001:db8::7 192.0.2.1 51234 25\r\n";
+        assert!(read_proxy_header(&mut input).await.is_err());
+
+        let mut input: &[u8] = b"EHLO client.example.org\r\n";
+        assert!(read_proxy_header(&mut input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2_header_is_parsed() {
+        // PROXY over TCP/IPv4, with a trailing TLV we don't care about
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x11, 0, 16]);
+        input.extend_from_slice(&[203, 0, 113, 7, 192, 0, 2, 1, 0xc8, 0x22, 0, 25]);
+        input.extend_from_slice(&[0x04, 0, 1, 0]);
+        input.extend_from_slice(b"EHLO");
+        let mut reader = input.as_slice();
+        let client = read_proxy_header(&mut reader).await.unwrap();
+        assert_eq!(client, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(reader, b"EHLO");
+
+        // PROXY over TCP/IPv6
+        let source: SocketAddr = "[2001:db8::7]:40000".parse().unwrap();
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        if let SocketAddr::V6(source) = source {
+            input.extend_from_slice(&source.ip().octets());
+        }
+        input.extend_from_slice(&[0; 16]);
+        input.extend_from_slice(&40000u16.to_be_bytes());
+        input.extend_from_slice(&25u16.to_be_bytes());
+        let client = read_proxy_header(&mut input.as_slice()).await.unwrap();
+        assert_eq!(client, Some(source));
+
+        // LOCAL, as sent by the load balancer's health checks
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_proxy_header(&mut input.as_slice()).await.unwrap(), None);
+
+        // An IPv4 family with too few address bytes
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x11, 0, 4, 203, 0, 113, 7]);
+        assert!(read_proxy_header(&mut input.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxied_client_reaches_the_payload() {
+        let dir = test_dir("proxy-payload");
+        let (url, mut requests) = spawn_webhook_recorder(200).await;
+        let config = test_config(&url, &dir, "", "");
+        let addr = start_test_listeners(config, vec![proxy_listener()]).await[0];
+
+        let (mut client, greeting) = SmtpClient::connect_via_proxy(
+            addr,
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 25\r\n",
+        )
+        .await;
+        assert_eq!(greeting.code, 220);
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(client.command("MAIL FROM:<a@example.org>").await.code, 250);
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: Proxied\r\n\r\nHello\r\n.\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+
+        let body = tokio::time::timeout(Duration::from_secs(10), requests.recv())
+            .await
+            .expect("Webhook was never called")
+            .unwrap();
+        assert!(body.contains("name=\"client-addr\""));
+        assert!(body.contains("203.0.113.7:51234"));
+    }
+
+    #[tokio::test]
+    async fn test_received_header_names_the_proxied_client() {
+        let dir = test_dir("proxy-received");
+        let config = spooling_test_config(&dir).await;
+        let addr = start_test_listeners(config, vec![proxy_listener()]).await[0];
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[198, 51, 100, 9, 192, 0, 2, 1, 0x9c, 0x40, 0, 25]);
+        let (mut client, greeting) = SmtpClient::connect_via_proxy(addr, &header).await;
+        assert_eq!(greeting.code, 220);
+        let (envelope, message) = deliver_and_load_spooled(
+            &mut client,
+            &dir,
+            "a@example.org",
+            b"Subject: Proxied\r\n\r\nHello\r\n",
+        )
+        .await;
+        assert_eq!(envelope.client_addr.as_deref(), Some("198.51.100.9:40000"));
+        assert!(message.starts_with(
+            "Received: from client.example.org ([198.51.100.9])\r\n\tby mx.example.com with ESMTP id "
+        ));
+        assert!(message.ends_with("Subject: Proxied\r\n\r\nHello\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_connection_without_proxy_header_is_dropped() {
+        let dir = test_dir("proxy-missing");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        let addr = start_test_listeners(config, vec![proxy_listener()]).await[0];
+
+        let (_, greeting) =
+            SmtpClient::connect_via_proxy(addr, b"EHLO client.example.org\r\n").await;
+        assert_eq!(greeting.code, 0);
+    }
+
+    #[tokio::test]
+    async fn test_connections_awaiting_the_header_count_as_sessions() {
+        use tokio::io::AsyncBufReadExt;
+
+        let dir = test_dir("proxy-sessions");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "",
+            "[limits]\nmax_sessions = 1",
+        );
+        let addr = start_test_listeners(config, vec![proxy_listener()]).await[0];
+
+        // Says nothing, holding its place until the greeting timeout
+        let _silent = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let second = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut reply = String::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            tokio::io::BufReader::new(second).read_line(&mut reply),
+        )
+        .await
+        .expect("The second connection was never refused")
+        .unwrap();
+        assert!(reply.starts_with("421 4.7.0"), "{}", reply);
+    }
+}
@@ -0,0 +1,130 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{spawn_webhook_stub, test_config, test_dir};
+    use mail_forge::replay::{self, ReplayArgs};
+    use mail_forge::spool::{Envelope, Spool};
+
+    #[tokio::test]
+    async fn test_replay_dead_letter_removes_it_once_delivered() {
+        let dir = test_dir("replay");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+
+        let (spool, _receiver) = Spool::open(&config.spool.dir).expect("Failed to open spool");
+        let envelope = Envelope::new(
+            "sender@example.com".to_string(),
+            vec!["shane@example.com".to_string()],
+        );
+        spool
+            .dead_letter(&envelope, b"Subject: Test\r\n\r\nTest\r\n")
+            .expect("Failed to dead-letter message");
+
+        let args = ReplayArgs {
+            files: Vec::new(),
+            dead_letter: Some(envelope.id.clone()),
+            recipients: Vec::new(),
+            dry_run: false,
+        };
+        replay::run(&config, args).await.expect("Replay failed");
+
+        assert!(spool.dead_letter_ids().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_replay_dead_letter_keeps_recipients_still_failing() {
+        let dir = test_dir("replay-partial");
+        let webhook = spawn_webhook_stub(200).await;
+        let config = test_config(&webhook, &dir, "", "");
+
+        let (spool, _receiver) = Spool::open(&config.spool.dir).expect("Failed to open spool");
+        let envelope = Envelope::new(
+            "sender@example.com".to_string(),
+            vec![
+                "shane@example.com".to_string(),
+                "someone@elsewhere.org".to_string(),
+            ],
+        );
+        spool
+            .dead_letter(&envelope, b"Subject: Test\r\n\r\nTest\r\n")
+            .expect("Failed to dead-letter message");
+        let args = || ReplayArgs {
+            files: Vec::new(),
+            dead_letter: Some(envelope.id.clone()),
+            recipients: Vec::new(),
+            dry_run: false,
+        };
+
+        // Only the recipient without a webhook is left for next time
+        assert!(replay::run(&config, args()).await.is_err());
+        let (dead, _) = spool.load_dead_letter(&envelope.id).unwrap();
+        let recipients: Vec<&str> = dead
+            .recipients
+            .iter()
+            .map(|recipient| recipient.address.as_str())
+            .collect();
+        assert_eq!(recipients, vec!["someone@elsewhere.org"]);
+        assert_eq!(
+            dead.recipients[0].last_error.as_deref(),
+            Some("No webhook mapping found for recipient")
+        );
+
+        let route = format!(
+            "\"*@elsewhere.org\" = {{ url = \"{}\", api_key = \"12345\" }}",
+            webhook
+        );
+        let config = test_config(&webhook, &dir, "", &route);
+        replay::run(&config, args()).await.expect("Replay failed");
+        assert!(spool.dead_letter_ids().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_replay_of_some_recipients_keeps_the_others() {
+        let dir = test_dir("replay-some");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+
+        let (spool, _receiver) = Spool::open(&config.spool.dir).expect("Failed to open spool");
+        let envelope = Envelope::new(
+            "sender@example.com".to_string(),
+            vec!["shane@example.com".to_string(), "info@example.com".to_string()],
+        );
+        spool
+            .dead_letter(&envelope, b"Subject: Test\r\n\r\nTest\r\n")
+            .expect("Failed to dead-letter message");
+
+        let args = ReplayArgs {
+            files: Vec::new(),
+            dead_letter: Some(envelope.id.clone()),
+            recipients: vec!["shane@example.com".to_string()],
+            dry_run: false,
+        };
+        replay::run(&config, args).await.expect("Replay failed");
+
+        // The recipient that wasn't replayed is still waiting
+        let (dead, _) = spool.load_dead_letter(&envelope.id).unwrap();
+        let recipients: Vec<&str> = dead
+            .recipients
+            .iter()
+            .map(|recipient| recipient.address.as_str())
+            .collect();
+        assert_eq!(recipients, vec!["info@example.com"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_replay_reports_unroutable_recipients() {
+        let dir = test_dir("replay-rcpt");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+
+        let args = ReplayArgs {
+            files: vec!["tests/emails/email2.eml".into()],
+            dead_letter: None,
+            recipients: vec!["someone@elsewhere.org".to_string()],
+            dry_run: true,
+        };
+        assert!(replay::run(&config, args).await.is_err());
+    }
+}
@@ -0,0 +1,66 @@
+
+#[cfg(test)]
+mod tests {
+
+    use chrono::{TimeDelta, Utc};
+    use mail_forge::config::RetryPolicy;
+    use mail_forge::spool::{retry, Recipient};
+    use mail_forge::webhook::client::DeliveryError;
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay_secs: 10,
+            backoff_factor: 2.0,
+            max_age_secs: 3600,
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_exponentially_up_to_max_age() {
+        let policy = policy();
+        assert_eq!(retry::backoff_delay(&policy, 1), Duration::from_secs(10));
+        assert_eq!(retry::backoff_delay(&policy, 2), Duration::from_secs(20));
+        assert_eq!(retry::backoff_delay(&policy, 4), Duration::from_secs(80));
+        assert_eq!(retry::backoff_delay(&policy, 40), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_client_errors_are_not_retried() {
+        let now = Utc::now();
+        let mut recipient = Recipient::new("a@example.com".to_string(), now);
+        let error = DeliveryError::Rejected(StatusCode::UNPROCESSABLE_ENTITY);
+
+        assert!(!retry::record_failure(&policy(), &mut recipient, now, &error, now));
+        assert_eq!(recipient.attempts, 1);
+        assert!(recipient.last_error.unwrap().contains("422"));
+    }
+
+    #[test]
+    fn test_server_errors_exhaust_attempts() {
+        let now = Utc::now();
+        let mut recipient = Recipient::new("a@example.com".to_string(), now);
+        let error = DeliveryError::ServerError(StatusCode::BAD_GATEWAY);
+
+        assert!(retry::record_failure(&policy(), &mut recipient, now, &error, now));
+        assert_eq!(recipient.next_attempt_at, now + TimeDelta::seconds(10));
+        assert!(retry::record_failure(&policy(), &mut recipient, now, &error, now));
+        assert!(!retry::record_failure(&policy(), &mut recipient, now, &error, now));
+    }
+
+    #[test]
+    fn test_network_errors_are_bounded_by_max_age() {
+        let received_at = Utc::now();
+        let mut recipient = Recipient::new("a@example.com".to_string(), received_at);
+        let error = DeliveryError::Network("connection refused".to_string());
+
+        // Well past max_attempts, but still young enough
+        recipient.attempts = 5;
+        assert!(retry::record_failure(&policy(), &mut recipient, received_at, &error, received_at));
+
+        let later = received_at + TimeDelta::minutes(59);
+        assert!(!retry::record_failure(&policy(), &mut recipient, received_at, &error, later));
+    }
+}
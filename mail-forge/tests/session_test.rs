@@ -0,0 +1,164 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{spawn_webhook_stub, start_test_server, test_config, test_dir, SmtpClient};
+
+    #[tokio::test]
+    async fn test_bdat_chunks_are_queued_like_data() {
+        let dir = test_dir("session-bdat");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        let addr = start_test_server(config).await;
+
+        let (mut client, greeting) = SmtpClient::connect(addr).await;
+        assert_eq!(greeting.code, 220);
+
+        let ehlo = client.command("EHLO client.example.org").await;
+        assert_eq!(ehlo.code, 250);
+        assert!(ehlo.lines.iter().any(|line| line == "CHUNKING"));
+        assert!(ehlo.lines.iter().any(|line| line == "BINARYMIME"));
+        assert!(ehlo.lines.iter().any(|line| line == "8BITMIME"));
+
+        assert_eq!(client.command("MAIL FROM:<sender@example.org>").await.code, 250);
+        assert_eq!(client.command("RCPT TO:<shane@example.com>").await.code, 250);
+
+        client.send(b"BDAT 17\r\nSubject: Test\r\n\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+        client.send(b"BDAT 7 LAST\r\n.Test\r\n").await;
+        let reply = client.read_reply().await;
+        assert_eq!(reply.code, 250, "{}", reply.text());
+        assert!(reply.text().contains("queued as"));
+
+        // The transaction is over, so another chunk is out of sequence
+        client.send(b"BDAT 2 LAST\r\nhi").await;
+        assert_eq!(client.read_reply().await.code, 503);
+        assert_eq!(client.command("NOOP").await.code, 250);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_bdat_chunk_is_rejected() {
+        let dir = test_dir("session-bdat-size");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        client.command("EHLO client.example.org").await;
+        client.command("MAIL FROM:<sender@example.org>").await;
+        client.command("RCPT TO:<shane@example.com>").await;
+
+        // One byte over the 1 MiB limit of the test config
+        let chunk = vec![b'a'; 1048577];
+        client.send(format!("BDAT {} LAST\r\n", chunk.len()).as_bytes()).await;
+        client.send(&chunk).await;
+        assert_eq!(client.read_reply().await.code, 552);
+        assert_eq!(client.command("NOOP").await.code, 250);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_commands_get_replies_in_order() {
+        let dir = test_dir("session-pipelining");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        let ehlo = client.command("EHLO client.example.org").await;
+        assert!(ehlo.lines.iter().any(|line| line == "PIPELINING"));
+
+        client
+            .send(
+                b"MAIL FROM:<sender@example.org>\r\n\
+                  RCPT TO:<a@example.com>\r\n\
+                  RCPT TO:<nobody@elsewhere.org>\r\n\
+                  RCPT TO:<b@example.com>\r\n\
+                  DATA\r\n",
+            )
+            .await;
+
+        let codes = [
+            client.read_reply().await.code,
+            client.read_reply().await.code,
+            client.read_reply().await.code,
+            client.read_reply().await.code,
+            client.read_reply().await.code,
+        ];
+        assert_eq!(codes, [250, 250, 550, 250, 354]);
+
+        client.send(b"Subject: Test\r\n\r\nTest\r\n.\r\nQUIT\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+        assert_eq!(client.read_reply().await.code, 221);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_data_without_recipients_keeps_the_session() {
+        let dir = test_dir("session-pipelining-refused");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        client.command("EHLO client.example.org").await;
+        assert_eq!(client.command("DATA").await.code, 503);
+
+        client
+            .send(
+                b"MAIL FROM:<sender@example.org>\r\n\
+                  RCPT TO:<nobody@elsewhere.org>\r\n\
+                  RCPT TO:<somebody@elsewhere.org>\r\n\
+                  DATA\r\n\
+                  RSET\r\n",
+            )
+            .await;
+        let codes = [
+            client.read_reply().await.code,
+            client.read_reply().await.code,
+            client.read_reply().await.code,
+            client.read_reply().await.code,
+            client.read_reply().await.code,
+        ];
+        assert_eq!(codes, [250, 550, 550, 554, 250]);
+
+        // The next transaction goes through on the same connection
+        assert_eq!(client.command("MAIL FROM:<sender@example.org>").await.code, 250);
+        assert_eq!(client.command("RCPT TO:<shane@example.com>").await.code, 250);
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: Test\r\n\r\nTest\r\n.\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+    }
+
+    #[tokio::test]
+    async fn test_declared_size_and_unknown_parameters_are_refused_up_front() {
+        let dir = test_dir("session-params");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        client.command("EHLO client.example.org").await;
+
+        assert_eq!(client.command("MAIL FROM:<a@example.org> SIZE=999999999").await.code, 552);
+        assert_eq!(client.command("MAIL FROM:<a@example.org> XFOO=1").await.code, 555);
+        assert_eq!(client.command("RCPT TO:<shane@example.com>").await.code, 503);
+
+        assert_eq!(client.command("MAIL FROM:<> BODY=BINARYMIME").await.code, 250);
+        assert_eq!(client.command("RCPT TO:<shane@example.com> NOTIFY=NEVER").await.code, 250);
+        assert_eq!(client.command("DATA").await.code, 503);
+    }
+
+    #[tokio::test]
+    async fn test_utf8_addresses_require_smtputf8() {
+        let dir = test_dir("session-smtputf8");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        let ehlo = client.command("EHLO client.example.org").await;
+        assert!(ehlo.lines.iter().any(|line| line == "SMTPUTF8"));
+
+        assert_eq!(client.command("MAIL FROM:<jörg@example.org>").await.code, 553);
+        assert_eq!(client.command("MAIL FROM:<a@example.org>").await.code, 250);
+        assert_eq!(client.command("RCPT TO:<josé@example.com>").await.code, 553);
+        assert_eq!(client.command("RSET").await.code, 250);
+
+        assert_eq!(client.command("MAIL FROM:<jörg@münchen.example> SMTPUTF8").await.code, 250);
+        assert_eq!(client.command("RCPT TO:<josé@EXAMPLE.com>").await.code, 250);
+    }
+}
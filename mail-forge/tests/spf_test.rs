@@ -0,0 +1,291 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{
+        spawn_webhook_recorder, spawn_webhook_stub, start_test_server_with_resolver, test_config,
+        test_dir, SmtpClient, StubResolver,
+    };
+    use mail_forge::config::SpfFailAction;
+    use mail_forge::spf::{check_host, verify, SpfResult};
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    async fn check(resolver: &StubResolver, ip: &str, domain: &str) -> SpfResult {
+        let ip: IpAddr = ip.parse().unwrap();
+        let sender = format!("alice@{}", domain);
+        check_host(resolver, ip, domain, &sender, "client.example.org").await
+    }
+
+    #[tokio::test]
+    async fn test_qualifiers_decide_the_result() {
+        let resolver = StubResolver::default()
+            .txt(
+                "example.org",
+                "v=spf1 ip4:192.0.2.0/24 ?ip4:198.51.100.1 -all",
+            )
+            .txt("example.net", "v=spf1 ip6:2001:db8::/32 ~all")
+            .txt("example.com", "v=spf1 a/24 mx")
+            .a("example.com", "203.0.113.7")
+            .mx("example.com", "mail.example.com")
+            .aaaa("mail.example.com", "2001:db8:1::25");
+
+        assert_eq!(
+            check(&resolver, "192.0.2.10", "example.org").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check(&resolver, "198.51.100.1", "example.org").await,
+            SpfResult::Neutral
+        );
+        assert_eq!(
+            check(&resolver, "203.0.113.1", "example.org").await,
+            SpfResult::Fail
+        );
+        assert_eq!(
+            check(&resolver, "2001:db8::1", "example.net").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.10", "example.net").await,
+            SpfResult::SoftFail
+        );
+        assert_eq!(
+            check(&resolver, "203.0.113.200", "example.com").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check(&resolver, "2001:db8:1::25", "example.com").await,
+            SpfResult::Pass
+        );
+        // No record matched and there is no "all": neutral
+        assert_eq!(
+            check(&resolver, "2001:db8:2::1", "example.com").await,
+            SpfResult::Neutral
+        );
+        // IPv4-mapped addresses count as the IPv4 address
+        assert_eq!(
+            check(&resolver, "::ffff:192.0.2.10", "example.org").await,
+            SpfResult::Pass
+        );
+    }
+
+    #[tokio::test]
+    async fn test_include_redirect_and_macros() {
+        let resolver = StubResolver::default()
+            .txt("example.org", "v=spf1 include:_spf.example.net -all")
+            .txt("_spf.example.net", "v=spf1 ip4:192.0.2.0/24 -all")
+            .txt("example.com", "v=spf1 redirect=example.org")
+            .txt("example.edu", "v=spf1 exists:%{ir}.%{l1r+-}._spf.%{d} -all")
+            .a("10.2.0.192.alice._spf.example.edu", "127.0.0.2");
+
+        assert_eq!(
+            check(&resolver, "192.0.2.10", "example.org").await,
+            SpfResult::Pass
+        );
+        // A failing include doesn't match, so the rest of the record decides
+        assert_eq!(
+            check(&resolver, "198.51.100.1", "example.org").await,
+            SpfResult::Fail
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.10", "example.com").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check(&resolver, "198.51.100.1", "example.com").await,
+            SpfResult::Fail
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.10", "example.edu").await,
+            SpfResult::Pass
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.11", "example.edu").await,
+            SpfResult::Fail
+        );
+    }
+
+    #[tokio::test]
+    async fn test_broken_records_and_dns_failures() {
+        let mut resolver = StubResolver::default()
+            .txt("two.example", "v=spf1 -all")
+            .txt("two.example", "v=spf1 +all")
+            .txt("typo.example", "v=spf1 ip4:192.0.2.300 -all")
+            .txt("missing.example", "v=spf1 include:nowhere.example -all")
+            .txt("loop.example", "v=spf1 include:loop.example -all")
+            .txt(
+                "void.example",
+                "v=spf1 a:a.void.example a:b.void.example a:c.void.example -all",
+            )
+            .txt("other.example", "google-site-verification=abc")
+            .failing("down.example");
+        for i in 0..11 {
+            resolver = resolver.txt(
+                &format!("n{}.example", i),
+                &format!("v=spf1 include:n{}.example", i + 1),
+            );
+        }
+
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "two.example").await,
+            SpfResult::PermError
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "typo.example").await,
+            SpfResult::PermError
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "missing.example").await,
+            SpfResult::PermError
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "loop.example").await,
+            SpfResult::PermError
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "n0.example").await,
+            SpfResult::PermError
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "void.example").await,
+            SpfResult::PermError
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "other.example").await,
+            SpfResult::None
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "nothing.example").await,
+            SpfResult::None
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "down.example").await,
+            SpfResult::TempError
+        );
+        assert_eq!(
+            check(&resolver, "192.0.2.1", "localhost").await,
+            SpfResult::None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bounces_are_checked_against_the_helo_name() {
+        let resolver = StubResolver::default()
+            .txt("mta.example.org", "v=spf1 a -all")
+            .a("mta.example.org", "192.0.2.25")
+            .txt("example.net", "v=spf1 -all");
+        let ip: IpAddr = "192.0.2.25".parse().unwrap();
+
+        let verdict = verify(&resolver, ip, "mta.example.org", "").await;
+        assert_eq!(verdict.result, SpfResult::Pass);
+        assert_eq!(verdict.domain, "mta.example.org");
+
+        let verdict = verify(&resolver, ip, "mta.example.org", "bob@example.net").await;
+        assert_eq!(verdict.result, SpfResult::Fail);
+        assert_eq!(verdict.domain, "example.net");
+        assert_eq!(verdict.helo, Some(SpfResult::Pass));
+
+        let verdict = verify(&resolver, ip, "[192.0.2.25]", "bob@example.net").await;
+        assert_eq!(verdict.helo, None);
+    }
+
+    #[tokio::test]
+    async fn test_result_is_passed_to_the_webhook() {
+        let dir = test_dir("spf-payload");
+        let (url, mut requests) = spawn_webhook_recorder(200).await;
+        let config = test_config(&url, &dir, "", "");
+        let resolver = StubResolver::default().txt("example.org", "v=spf1 ip4:127.0.0.0/8 -all");
+        let addr = start_test_server_with_resolver(config, resolver).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: SPF\r\n\r\nHello\r\n.\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+
+        let body = tokio::time::timeout(Duration::from_secs(10), requests.recv())
+            .await
+            .expect("Webhook was never called")
+            .unwrap();
+        assert!(body.contains("name=\"spf\""));
+        assert!(body.contains(r#"{"domain":"example.org","helo":"none","result":"pass"}"#));
+    }
+
+    #[tokio::test]
+    async fn test_spf_fail_is_rejected_at_rcpt() {
+        let dir = test_dir("spf-reject-rcpt");
+        let mut config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        config
+            .webhooks
+            .get_mut("*@example.com")
+            .unwrap()
+            .on_spf_fail = SpfFailAction::RejectRcpt;
+        let resolver = StubResolver::default()
+            .txt("example.org", "v=spf1 ip4:192.0.2.0/24 -all")
+            .txt("example.net", "v=spf1 ~all");
+        let addr = start_test_server_with_resolver(config, resolver).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        let reply = client.command("RCPT TO:<shane@example.com>").await;
+        assert_eq!(reply.code, 550);
+        assert!(reply.text().starts_with("5.7.23"));
+
+        // A softfail is not a fail
+        assert_eq!(client.command("RSET").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.net>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spf_fail_is_rejected_after_data() {
+        let dir = test_dir("spf-reject-data");
+        let mut config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        config
+            .webhooks
+            .get_mut("*@example.com")
+            .unwrap()
+            .on_spf_fail = SpfFailAction::RejectData;
+        let resolver = StubResolver::default().txt("example.org", "v=spf1 -all");
+        let addr = start_test_server_with_resolver(config, resolver).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: SPF\r\n\r\nHello\r\n.\r\n").await;
+        let reply = client.read_reply().await;
+        assert_eq!(reply.code, 550);
+        assert_eq!(reply.text(), "5.7.23 SPF check failed for example.org");
+
+        // The session carries on
+        assert_eq!(client.command("NOOP").await.code, 250);
+    }
+}
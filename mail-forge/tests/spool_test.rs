@@ -0,0 +1,106 @@
+
+#[cfg(test)]
+mod tests {
+
+    use mail_forge::spool::{Envelope, Spool};
+    use std::env::temp_dir;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_spooled_message_survives_reopen() {
+        let dir = temp_dir().join(format!("mail-forge-spool-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let envelope = Envelope::new(
+            "sender@example.com".to_string(),
+            vec!["shane@textify.asgcom.net".to_string()],
+        );
+        let message = b"Subject: Test\r\n\r\nTest\r\n";
+
+        {
+            let (spool, mut receiver) = Spool::open(&dir).expect("Failed to open spool");
+            spool.enqueue(&envelope, message).expect("Failed to enqueue message");
+            assert_eq!(receiver.recv().await.as_deref(), Some(envelope.id.as_str()));
+        }
+
+        // A fresh spool over the same directory sees the message again
+        let (spool, _receiver) = Spool::open(&dir).expect("Failed to reopen spool");
+        assert_eq!(spool.pending_ids().unwrap(), vec![envelope.id.clone()]);
+
+        let (loaded, loaded_message) = spool.load(&envelope.id).expect("Failed to load message");
+        assert_eq!(loaded.mail_from, "sender@example.com");
+        assert_eq!(loaded.recipients.len(), 1);
+        assert_eq!(loaded.recipients[0].address, "shane@textify.asgcom.net");
+        assert_eq!(loaded_message, message);
+
+        spool.remove(&envelope.id).expect("Failed to remove message");
+        assert!(spool.pending_ids().unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters_collect_failed_recipients() {
+        let dir = temp_dir().join(format!("mail-forge-dead-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (spool, _receiver) = Spool::open(&dir).expect("Failed to open spool");
+
+        let mut envelope = Envelope::new(
+            "sender@example.com".to_string(),
+            vec!["a@example.com".to_string(), "b@example.com".to_string()],
+        );
+        let message = b"Subject: Test\r\n\r\nTest\r\n";
+        let recipients = std::mem::take(&mut envelope.recipients);
+
+        // The two recipients give up at different times
+        for mut recipient in recipients {
+            recipient.last_error = Some(format!("Failure for {}", recipient.address));
+            let mut dead = envelope.clone();
+            dead.recipients = vec![recipient];
+            spool.dead_letter(&dead, message).expect("Failed to dead-letter message");
+        }
+
+        assert_eq!(spool.dead_letter_ids().unwrap(), vec![envelope.id.clone()]);
+        let (dead, dead_message) = spool.load_dead_letter(&envelope.id).unwrap();
+        assert_eq!(dead_message, message);
+        let errors: Vec<_> = dead
+            .recipients
+            .iter()
+            .map(|recipient| recipient.last_error.clone().unwrap())
+            .collect();
+        assert_eq!(errors, vec!["Failure for a@example.com", "Failure for b@example.com"]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_work_runs_off_the_runtime() {
+        let dir = temp_dir().join(format!("mail-forge-blocking-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (spool, mut receiver) = Spool::open(&dir).expect("Failed to open spool");
+        let spool = Arc::new(spool);
+
+        let envelope = Envelope::new(
+            "sender@example.com".to_string(),
+            vec!["shane@textify.asgcom.net".to_string()],
+        );
+        let id = envelope.id.clone();
+        spool
+            .blocking(move |spool| spool.enqueue(&envelope, b"Subject: Test\r\n\r\nTest\r\n"))
+            .await
+            .expect("Failed to enqueue message");
+        assert_eq!(receiver.recv().await, Some(id.clone()));
+
+        let (loaded, _) = {
+            let id = id.clone();
+            spool.blocking(move |spool| spool.load(&id)).await.unwrap()
+        };
+        assert_eq!(loaded.mail_from, "sender@example.com");
+
+        // Errors come back as they are
+        let missing = spool.blocking(|spool| spool.load("missing")).await;
+        assert_eq!(missing.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,77 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{
+        spawn_webhook_stub, start_test_server, test_cert_path, test_config, test_dir, SmtpClient,
+    };
+
+    #[tokio::test]
+    async fn test_require_tls_until_starttls() {
+        let dir = test_dir("starttls-required");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "require_tls = true",
+            "",
+        );
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        let ehlo = client.command("EHLO client.example.org").await;
+        assert!(ehlo.lines.iter().any(|line| line == "STARTTLS"));
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            530
+        );
+
+        assert_eq!(client.command("STARTTLS").await.code, 220);
+        let mut client = client.start_tls(&test_cert_path(&dir)).await;
+
+        let ehlo = client.command("EHLO client.example.org").await;
+        assert_eq!(ehlo.code, 250);
+        assert!(!ehlo.lines.iter().any(|line| line == "STARTTLS"));
+        assert_eq!(client.command("STARTTLS").await.code, 503);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+    }
+
+    #[tokio::test]
+    async fn test_starttls_discards_plaintext_state() {
+        let dir = test_dir("starttls-reset");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+
+        // A command smuggled in behind STARTTLS must not run under TLS
+        client
+            .send(b"STARTTLS\r\nRCPT TO:<shane@example.com>\r\n")
+            .await;
+        assert_eq!(client.read_reply().await.code, 220);
+        let mut client = client.start_tls(&test_cert_path(&dir)).await;
+
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        // The plaintext MAIL is forgotten, so neither RCPT nor DATA can follow
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            503
+        );
+        assert_eq!(
+            client.command("MAIL FROM:<sender@example.org>").await.code,
+            250
+        );
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+    }
+}
@@ -0,0 +1,100 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{spawn_webhook_stub, start_test_server, test_config, test_dir, SmtpClient};
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    #[tokio::test]
+    async fn test_client_hanging_up_without_quit_ends_the_session() {
+        let dir = test_dir("timeout-eof");
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", "");
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+        client.shutdown().await;
+
+        // The server notices and closes its side too
+        let reply = timeout(Duration::from_secs(5), client.read_reply())
+            .await
+            .expect("Session kept running after the client hung up");
+        assert_eq!(reply.code, 0);
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_is_told_421() {
+        let dir = test_dir("timeout-command");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "command_timeout_secs = 1",
+            "",
+        );
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        assert_eq!(client.command("EHLO client.example.org").await.code, 250);
+
+        let reply = timeout(Duration::from_secs(5), client.read_reply())
+            .await
+            .expect("Idle session was never closed");
+        assert_eq!(reply.code, 421);
+        assert!(reply.text().starts_with("4.4.2 mx.example.com"));
+        assert_eq!(client.read_reply().await.code, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_data_is_told_421() {
+        let dir = test_dir("timeout-data");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "data_block_timeout_secs = 1",
+            "",
+        );
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        client.command("EHLO client.example.org").await;
+        client.command("MAIL FROM:<sender@example.org>").await;
+        client.command("RCPT TO:<shane@example.com>").await;
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: Slow\r\n\r\nHal").await;
+
+        let reply = timeout(Duration::from_secs(5), client.read_reply())
+            .await
+            .expect("Stalled DATA was never timed out");
+        assert_eq!(reply.code, 421);
+    }
+
+    #[tokio::test]
+    async fn test_session_length_is_capped() {
+        let dir = test_dir("timeout-session");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "max_session_secs = 1",
+            "",
+        );
+        let addr = start_test_server(config).await;
+
+        let (mut client, _) = SmtpClient::connect(addr).await;
+        let busy = async {
+            // Staying active doesn't buy more time
+            loop {
+                let reply = client.command("NOOP").await;
+                if reply.code != 250 {
+                    return reply;
+                }
+                sleep(Duration::from_millis(200)).await;
+            }
+        };
+        let reply = timeout(Duration::from_secs(5), busy)
+            .await
+            .expect("Session outlived max_session_secs");
+        assert_eq!(reply.code, 421);
+    }
+}
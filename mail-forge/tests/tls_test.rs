@@ -0,0 +1,231 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+
+    use crate::common::{
+        spawn_webhook_recorder, spawn_webhook_stub, start_test_listener, test_cert_path,
+        test_config, test_dir, tls_handshake_succeeds, write_test_cert, SmtpClient,
+    };
+    use mail_forge::config::{self, ListenerMode};
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair};
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{
+        version, CipherSuite, ClientConfig, RootCertStore, SupportedProtocolVersion,
+    };
+    use tokio_rustls::TlsConnector;
+
+    /// Handshakes with the given client settings and returns the negotiated
+    /// cipher suite and ALPN protocol.
+    async fn negotiate(
+        addr: SocketAddr,
+        cert_path: &Path,
+        versions: &[&'static SupportedProtocolVersion],
+        alpn: &[&str],
+    ) -> Option<(CipherSuite, Option<Vec<u8>>)> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(cert_path).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let mut client_config = ClientConfig::builder_with_protocol_versions(versions)
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("mx.example.com").unwrap();
+        let stream = TlsConnector::from(Arc::new(client_config))
+            .connect(server_name, stream)
+            .await
+            .ok()?;
+        let (_, connection) = stream.get_ref();
+        Some((
+            connection.negotiated_cipher_suite()?.suite(),
+            connection.alpn_protocol().map(<[u8]>::to_vec),
+        ))
+    }
+
+    /// Writes a CA and a client certificate for `common_name` signed by it,
+    /// returning the paths of the CA certificate, client certificate and key.
+    fn write_client_cert(dir: &Path, common_name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Test Client CA");
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+        let mut params = CertificateParams::new(vec![common_name.to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &ca).unwrap();
+
+        let paths = (
+            dir.join("client-ca.crt"),
+            dir.join("client.crt"),
+            dir.join("client.key"),
+        );
+        std::fs::write(&paths.0, ca.pem()).unwrap();
+        std::fs::write(&paths.1, cert.pem()).unwrap();
+        std::fs::write(&paths.2, key.serialize_pem()).unwrap();
+        paths
+    }
+
+    #[tokio::test]
+    async fn test_certificate_is_chosen_by_server_name() {
+        let dir = test_dir("tls-sni");
+        let (customer_cert, customer_key) = write_test_cert(&dir, "mx.customer.test");
+        let (wildcard_cert, wildcard_key) = write_test_cert(&dir, "*.hosted.test");
+        let certificates = format!(
+            r#"
+            [[tls.certificates]]
+            server_name = "MX.Customer.test"
+            cert_path = "{}"
+            key_path = "{}"
+
+            [[tls.certificates]]
+            server_name = "*.hosted.test"
+            cert_path = "{}"
+            key_path = "{}"
+            "#,
+            customer_cert.display(),
+            customer_key.display(),
+            wildcard_cert.display(),
+            wildcard_key.display()
+        );
+        let config = test_config(&spawn_webhook_stub(200).await, &dir, "", &certificates);
+        let addr = start_test_listener(config, ListenerMode::Smtps).await;
+
+        // Each handshake only succeeds if the server presented the expected certificate
+        let (_, greeting) =
+            SmtpClient::connect_tls_as(addr, &customer_cert, "mx.customer.test").await;
+        assert_eq!(greeting.code, 220);
+
+        let (_, greeting) =
+            SmtpClient::connect_tls_as(addr, &wildcard_cert, "mx.hosted.test").await;
+        assert_eq!(greeting.code, 220);
+
+        // Unknown names get the default certificate
+        let (_, greeting) = SmtpClient::connect_tls(addr, &test_cert_path(&dir)).await;
+        assert_eq!(greeting.code, 220);
+    }
+
+    #[tokio::test]
+    async fn test_renewed_certificate_is_picked_up() {
+        let dir = test_dir("tls-reload");
+        let config = test_config(
+            &spawn_webhook_stub(200).await,
+            &dir,
+            "",
+            "[tls]\nreload_interval_secs = 1",
+        );
+        let addr = start_test_listener(config, ListenerMode::Smtps).await;
+
+        let old_cert = dir.join("old.crt");
+        std::fs::copy(test_cert_path(&dir), &old_cert).unwrap();
+        assert!(tls_handshake_succeeds(addr, &old_cert, "mx.example.com").await);
+
+        // Renew in place, as certbot does, with a fresh key pair
+        write_test_cert(&dir, "mx.example.com");
+
+        let mut reloaded = false;
+        for _ in 0..30 {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            if tls_handshake_succeeds(addr, &test_cert_path(&dir), "mx.example.com").await {
+                reloaded = true;
+                break;
+            }
+        }
+        assert!(reloaded, "The renewed certificate was never served");
+        assert!(!tls_handshake_succeeds(addr, &old_cert, "mx.example.com").await);
+    }
+
+    #[tokio::test]
+    async fn test_tls_version_cipher_suites_and_alpn_are_enforced() {
+        let dir = test_dir("tls-params");
+        let url = spawn_webhook_stub(200).await;
+        let config = test_config(
+            &url,
+            &dir,
+            "",
+            r#"
+            [tls]
+            min_version = "1.3"
+            cipher_suites = ["TLS13_CHACHA20_POLY1305_SHA256"]
+            alpn = ["smtp"]
+            "#,
+        );
+        let addr = start_test_listener(config, ListenerMode::Smtps).await;
+        let cert = test_cert_path(&dir);
+
+        assert!(negotiate(addr, &cert, &[&version::TLS12], &[])
+            .await
+            .is_none());
+
+        let (suite, alpn) = negotiate(addr, &cert, &[&version::TLS13], &["smtp"])
+            .await
+            .expect("TLS 1.3 handshake failed");
+        assert_eq!(suite, CipherSuite::TLS13_CHACHA20_POLY1305_SHA256);
+        assert_eq!(alpn.as_deref(), Some(&b"smtp"[..]));
+
+        // Typos are caught when the configuration is loaded, not at handshake time
+        let config = test_config(
+            &url,
+            &dir,
+            "",
+            "[tls]\ncipher_suites = [\"TLS13_AES_512_GCM_SHA384\"]",
+        );
+        assert!(config::load_certs(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_required_client_certificate_reaches_the_payload() {
+        let dir = test_dir("tls-client-auth");
+        let (ca_cert, client_cert, client_key) = write_client_cert(&dir, "relay.example.net");
+        let (url, mut requests) = spawn_webhook_recorder(200).await;
+        let config = test_config(
+            &url,
+            &dir,
+            "",
+            &format!(
+                "[tls]\nclient_auth = \"required\"\nclient_ca_path = \"{}\"",
+                ca_cert.display()
+            ),
+        );
+        let addr = start_test_listener(config, ListenerMode::Smtps).await;
+        let cert = test_cert_path(&dir);
+
+        // The server rejects the handshake once it sees no client certificate
+        let (_, greeting) = SmtpClient::connect_tls(addr, &cert).await;
+        assert_eq!(greeting.code, 0);
+
+        let (mut client, greeting) =
+            SmtpClient::connect_tls_with_client_cert(addr, &cert, (&client_cert, &client_key))
+                .await;
+        assert_eq!(greeting.code, 220);
+        assert_eq!(client.command("EHLO relay.example.net").await.code, 250);
+        assert_eq!(client.command("MAIL FROM:<a@example.org>").await.code, 250);
+        assert_eq!(
+            client.command("RCPT TO:<shane@example.com>").await.code,
+            250
+        );
+        assert_eq!(client.command("DATA").await.code, 354);
+        client.send(b"Subject: mTLS\r\n\r\nHello\r\n.\r\n").await;
+        assert_eq!(client.read_reply().await.code, 250);
+
+        let body = tokio::time::timeout(Duration::from_secs(10), requests.recv())
+            .await
+            .expect("Webhook was never called")
+            .unwrap();
+        assert!(body.contains("name=\"tls-client-subject\""));
+        assert!(body.contains("CN=relay.example.net"));
+    }
+}